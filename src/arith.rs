@@ -0,0 +1,554 @@
+// Integer arithmetic for `$(( ))`, `(( ))` and `let`. Expressions use the C
+// operators with C precedence, on 64-bit integers that wrap on overflow.
+// Variables are named without a `$` and read and assigned in place.
+
+use std::collections::HashMap;
+
+// How deeply a variable may hold an expression naming another variable.
+const MAX_DEPTH: usize = 64;
+
+// Longest first, so `<<=` isn't read as `<<` and `=`.
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*=", "/=",
+    "%=", "+=", "-=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "=", "!", "~", "&",
+    "^", "|", "?", ":", ",", "(", ")",
+];
+
+const ASSIGNMENTS: &[&str] = &["=", "*=", "/=", "%=", "+=", "-=", "<<=", ">>=", "&=", "^=", "|="];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Operator(&'static str),
+}
+
+#[derive(Debug)]
+enum Expr {
+    Number(i64),
+    Variable(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    // The operator is `=` or a compound one like `+=`.
+    Assign(String, &'static str, Box<Expr>),
+    // `++x`/`--x` (prefix) or `x++`/`x--`, as the amount added.
+    Increment(String, i64, bool),
+}
+
+/// Evaluates `expression`, reading and assigning `variables`. An empty
+/// expression is 0. Errors come back as the message to print.
+pub fn evaluate(expression: &str, variables: &mut HashMap<String, String>) -> Result<i64, String> {
+    evaluate_at_depth(expression, variables, 0)
+        .map_err(|message| format!("{}: {}", expression.trim(), message))
+}
+
+fn evaluate_at_depth(
+    expression: &str,
+    variables: &mut HashMap<String, String>,
+    depth: usize,
+) -> Result<i64, String> {
+    if depth > MAX_DEPTH {
+        return Err("expression recursion level exceeded".to_string());
+    }
+    match parse(expression)? {
+        Some(expr) => Evaluator { variables, depth }.eval(&expr),
+        None => Ok(0),
+    }
+}
+
+/// Whether `expression` parses, without evaluating it. It may still hold
+/// `$` expansions and backquotes, which are taken to stand for a number.
+pub fn is_expression(expression: &str) -> bool {
+    parse(&without_expansions(expression)).is_ok()
+}
+
+/// Parses a whole expression; `None` if it is empty.
+fn parse(expression: &str) -> Result<Option<Expr>, String> {
+    let tokens = tokenize(expression)?;
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+
+    let mut parser = Parser {
+        expression,
+        tokens,
+        position: 0,
+    };
+    let expr = parser.parse_comma()?;
+    if parser.position < parser.tokens.len() {
+        return Err(parser.syntax_error());
+    }
+    Ok(Some(expr))
+}
+
+/// Replaces each expansion in unexpanded text with `0`, and drops double
+/// quotes, leaving only what the expression itself is made of.
+fn without_expansions(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '$' => {
+                match chars.peek() {
+                    Some(&open @ ('(' | '{')) => {
+                        let close = if open == '(' { ')' } else { '}' };
+                        let mut depth = 0;
+                        for c in chars.by_ref() {
+                            if c == open {
+                                depth += 1;
+                            } else if c == close {
+                                depth -= 1;
+                                if depth == 0 {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+                        while chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_').is_some() {}
+                    }
+                    Some(_) => {
+                        chars.next();
+                    }
+                    None => {}
+                }
+                result.push_str(" 0 ");
+            }
+            '`' => {
+                chars.by_ref().find(|&c| c == '`');
+                result.push_str(" 0 ");
+            }
+            '"' => {}
+            '\\' => result.extend(chars.next()),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+/// Splits an expression into tokens, each with the byte offset it starts at.
+fn tokenize(expression: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = expression;
+    while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+        rest = &rest[start..];
+        let offset = expression.len() - rest.len();
+
+        let c = rest.chars().next().unwrap_or_default();
+        let len = if c.is_ascii_alphanumeric() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '#' || c == '@'))
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            if c.is_ascii_digit() {
+                tokens.push((Token::Number(parse_number(word)?), offset));
+            } else {
+                // A name can't contain `#` or `@`; leave those for the
+                // parser to reject.
+                let len = word.find(['#', '@']).unwrap_or(len);
+                tokens.push((Token::Name(word[..len].to_string()), offset));
+                rest = &rest[len..];
+                continue;
+            }
+            len
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+                Some(op) => {
+                    tokens.push((Token::Operator(op), offset));
+                    op.len()
+                }
+                None => return Err(format!("syntax error: invalid arithmetic operator (error token is \"{}\")", rest)),
+            }
+        };
+        rest = &rest[len..];
+    }
+    Ok(tokens)
+}
+
+/// Parses an integer constant: decimal, `0x` hex, `0` octal, or `base#digits`
+/// for bases 2 to 64, which use `0-9`, `a-z`, `A-Z`, `@` and `_` as digits.
+fn parse_number(text: &str) -> Result<i64, String> {
+    let invalid = || format!("value too great for base (error token is \"{}\")", text);
+
+    let (base, digits) = if let Some((base, digits)) = text.split_once('#') {
+        match base.parse::<u32>() {
+            Ok(base @ 2..=64) => (base, digits),
+            _ => return Err(format!("invalid arithmetic base (error token is \"{}\")", text)),
+        }
+    } else if let Some(digits) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        (16, digits)
+    } else if text.len() > 1 && text.starts_with('0') {
+        (8, &text[1..])
+    } else {
+        (10, text)
+    };
+    if digits.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut value: i64 = 0;
+    for c in digits.chars() {
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            // Up to base 36, letters are digits in either case.
+            'A'..='Z' if base <= 36 => c as u32 - 'A' as u32 + 10,
+            'A'..='Z' => c as u32 - 'A' as u32 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => return Err(invalid()),
+        };
+        if digit >= base {
+            return Err(invalid());
+        }
+        value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Ok(value)
+}
+
+fn binary_precedence(op: &str) -> Option<u8> {
+    Some(match op {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" => 6,
+        "<" | ">" | "<=" | ">=" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        "*" | "/" | "%" => 10,
+        "**" => 11,
+        _ => return None,
+    })
+}
+
+struct Parser<'a> {
+    expression: &'a str,
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn peek_operator(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Operator(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_operator() == Some(op) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.syntax_error())
+        }
+    }
+
+    fn syntax_error(&self) -> String {
+        match self.tokens.get(self.position) {
+            Some((_, offset)) => format!(
+                "syntax error in expression (error token is \"{}\")",
+                &self.expression[*offset..]
+            ),
+            None => "syntax error: operand expected".to_string(),
+        }
+    }
+
+    fn parse_comma(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_assignment()?;
+        while self.peek_operator() == Some(",") {
+            self.position += 1;
+            let next = self.parse_assignment()?;
+            expr = Expr::Binary(",", Box::new(expr), Box::new(next));
+        }
+        Ok(expr)
+    }
+
+    fn parse_assignment(&mut self) -> Result<Expr, String> {
+        let start = self.position;
+        if let Some(Token::Name(name)) = self.peek().cloned() {
+            self.position += 1;
+            if let Some(op) = self.peek_operator().filter(|op| ASSIGNMENTS.contains(op)) {
+                self.position += 1;
+                let value = self.parse_assignment()?;
+                return Ok(Expr::Assign(name, op, Box::new(value)));
+            }
+            self.position = start;
+        }
+        self.parse_conditional()
+    }
+
+    fn parse_conditional(&mut self) -> Result<Expr, String> {
+        let condition = self.parse_binary(1)?;
+        if self.peek_operator() != Some("?") {
+            return Ok(condition);
+        }
+        self.position += 1;
+        let then = self.parse_comma()?;
+        self.expect(":")?;
+        let otherwise = self.parse_assignment()?;
+        Ok(Expr::Conditional(Box::new(condition), Box::new(then), Box::new(otherwise)))
+    }
+
+    /// Precedence climbing over the binary operators. `**` is the only one
+    /// that groups to the right.
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.parse_unary()?;
+        while let Some(op) = self.peek_operator() {
+            let Some(precedence) = binary_precedence(op).filter(|&p| p >= min_precedence) else {
+                break;
+            };
+            self.position += 1;
+            let next = if op == "**" { precedence } else { precedence + 1 };
+            let rhs = self.parse_binary(next)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        match self.peek_operator() {
+            Some(op @ ("++" | "--")) => {
+                self.position += 1;
+                match self.peek().cloned() {
+                    Some(Token::Name(name)) => {
+                        self.position += 1;
+                        Ok(Expr::Increment(name, if op == "++" { 1 } else { -1 }, true))
+                    }
+                    // Not before a name, `--5` is just two minus signs.
+                    _ => {
+                        let operand = self.parse_unary()?;
+                        let sign = &op[..1];
+                        Ok(Expr::Unary(sign, Box::new(Expr::Unary(sign, Box::new(operand)))))
+                    }
+                }
+            }
+            Some(op @ ("-" | "+" | "!" | "~")) => {
+                self.position += 1;
+                Ok(Expr::Unary(op, Box::new(self.parse_unary()?)))
+            }
+            _ => self.parse_postfix(),
+        }
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr, String> {
+        match self.peek().cloned() {
+            Some(Token::Number(n)) => {
+                self.position += 1;
+                Ok(Expr::Number(n))
+            }
+            Some(Token::Name(name)) => {
+                self.position += 1;
+                match self.peek_operator() {
+                    Some(op @ ("++" | "--")) => {
+                        self.position += 1;
+                        Ok(Expr::Increment(name, if op == "++" { 1 } else { -1 }, false))
+                    }
+                    _ => Ok(Expr::Variable(name)),
+                }
+            }
+            Some(Token::Operator("(")) => {
+                self.position += 1;
+                let expr = self.parse_comma()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            _ => Err(self.syntax_error()),
+        }
+    }
+}
+
+struct Evaluator<'a> {
+    variables: &'a mut HashMap<String, String>,
+    depth: usize,
+}
+
+impl Evaluator<'_> {
+    fn eval(&mut self, expr: &Expr) -> Result<i64, String> {
+        match expr {
+            Expr::Number(n) => Ok(*n),
+            Expr::Variable(name) => self.get(name),
+            Expr::Unary(op, operand) => {
+                let value = self.eval(operand)?;
+                Ok(match *op {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    _ => value,
+                })
+            }
+            // Only one side of `&&`, `||` and `?:` is evaluated, so an
+            // assignment on the other side doesn't happen.
+            Expr::Binary("&&", lhs, rhs) => {
+                Ok((self.eval(lhs)? != 0 && self.eval(rhs)? != 0) as i64)
+            }
+            Expr::Binary("||", lhs, rhs) => {
+                Ok((self.eval(lhs)? != 0 || self.eval(rhs)? != 0) as i64)
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                let rhs = self.eval(rhs)?;
+                apply(op, lhs, rhs)
+            }
+            Expr::Conditional(condition, then, otherwise) => {
+                if self.eval(condition)? != 0 {
+                    self.eval(then)
+                } else {
+                    self.eval(otherwise)
+                }
+            }
+            Expr::Assign(name, op, value) => {
+                let value = self.eval(value)?;
+                let value = match op.strip_suffix('=').filter(|op| !op.is_empty()) {
+                    Some(op) => apply(op, self.get(name)?, value)?,
+                    None => value,
+                };
+                self.variables.insert(name.clone(), value.to_string());
+                Ok(value)
+            }
+            Expr::Increment(name, amount, prefix) => {
+                let old = self.get(name)?;
+                let new = old.wrapping_add(*amount);
+                self.variables.insert(name.clone(), new.to_string());
+                Ok(if *prefix { new } else { old })
+            }
+        }
+    }
+
+    /// The value of a variable. Unset or empty is 0, and a value that isn't
+    /// a number is evaluated as an expression in turn.
+    fn get(&mut self, name: &str) -> Result<i64, String> {
+        let value = self.variables.get(name).cloned().unwrap_or_default();
+        let value = value.trim();
+        if value.is_empty() {
+            return Ok(0);
+        }
+        match value.parse() {
+            Ok(n) => Ok(n),
+            Err(_) => evaluate_at_depth(value, self.variables, self.depth + 1),
+        }
+    }
+}
+
+fn apply(op: &str, lhs: i64, rhs: i64) -> Result<i64, String> {
+    Ok(match op {
+        "," => rhs,
+        "|" => lhs | rhs,
+        "^" => lhs ^ rhs,
+        "&" => lhs & rhs,
+        "==" => (lhs == rhs) as i64,
+        "!=" => (lhs != rhs) as i64,
+        "<" => (lhs < rhs) as i64,
+        ">" => (lhs > rhs) as i64,
+        "<=" => (lhs <= rhs) as i64,
+        ">=" => (lhs >= rhs) as i64,
+        "<<" => lhs.wrapping_shl(rhs as u32),
+        ">>" => lhs.wrapping_shr(rhs as u32),
+        "+" => lhs.wrapping_add(rhs),
+        "-" => lhs.wrapping_sub(rhs),
+        "*" => lhs.wrapping_mul(rhs),
+        "/" | "%" if rhs == 0 => return Err("division by 0".to_string()),
+        "/" => lhs.wrapping_div(rhs),
+        "%" => lhs.wrapping_rem(rhs),
+        "**" if rhs < 0 => return Err("exponent less than 0".to_string()),
+        "**" => lhs.wrapping_pow(rhs.min(u32::MAX as i64) as u32),
+        _ => unreachable!("not a binary operator: {}", op),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> Result<i64, String> {
+        evaluate(expression, &mut HashMap::new())
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3));
+        assert_eq!(eval("2 ** 3 ** 2"), Ok(512));
+        assert_eq!(eval("-2 ** 2"), Ok(4));
+        assert_eq!(eval("1 << 2 + 1"), Ok(8));
+        assert_eq!(eval("1 | 2 ^ 3 & 4"), Ok(3));
+        assert_eq!(eval("1 < 2 == 1"), Ok(1));
+        assert_eq!(eval("0 || 2 && 3"), Ok(1));
+        assert_eq!(eval("1 ? 0 ? 5 : 6 : 7"), Ok(6));
+        assert_eq!(eval("!0 + ~0"), Ok(0));
+        assert_eq!(eval("1, 2, 3"), Ok(3));
+        assert_eq!(eval(""), Ok(0));
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(eval("0x1f + 010 + 2#101 + 36#z"), Ok(31 + 8 + 5 + 35));
+        assert!(eval("09").is_err());
+    }
+
+    #[test]
+    fn assignments() {
+        let mut variables = HashMap::new();
+        variables.insert("x".to_string(), "5".to_string());
+        assert_eq!(evaluate("x += 3", &mut variables), Ok(8));
+        assert_eq!(evaluate("x *= 2, x -= 1", &mut variables), Ok(15));
+        assert_eq!(evaluate("x /= 4", &mut variables), Ok(3));
+        assert_eq!(evaluate("x %= 2", &mut variables), Ok(1));
+        assert_eq!(evaluate("x <<= 3", &mut variables), Ok(8));
+        assert_eq!(evaluate("x |= 3", &mut variables), Ok(11));
+        assert_eq!(evaluate("y = x++ + ++x", &mut variables), Ok(24));
+        assert_eq!(variables["x"], "13");
+        assert_eq!(variables["y"], "24");
+        assert_eq!(evaluate("a = b = 2", &mut variables), Ok(2));
+        assert_eq!(variables["a"], "2");
+    }
+
+    #[test]
+    fn variables_hold_expressions() {
+        let mut variables = HashMap::new();
+        variables.insert("x".to_string(), "y + 1".to_string());
+        variables.insert("y".to_string(), "2".to_string());
+        assert_eq!(evaluate("x * 2", &mut variables), Ok(6));
+        assert_eq!(evaluate("unset + 1", &mut variables), Ok(1));
+        variables.insert("loop".to_string(), "loop".to_string());
+        assert!(evaluate("loop", &mut variables).is_err());
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        assert_eq!(eval("1 / 0"), Err("1 / 0: division by 0".to_string()));
+        assert_eq!(eval("5 % 0"), Err("5 % 0: division by 0".to_string()));
+        let mut variables = HashMap::new();
+        variables.insert("x".to_string(), "4".to_string());
+        assert!(evaluate("x /= 0", &mut variables).is_err());
+        assert_eq!(variables["x"], "4");
+        assert_eq!(eval("-9223372036854775808 / -1"), Ok(i64::MIN));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert!(eval("1 +").is_err());
+        assert!(eval("(1").is_err());
+        assert!(eval("2 3").is_err());
+        assert!(eval("1 = 2").is_err());
+        assert!(eval("2 ** -1").is_err());
+    }
+
+    #[test]
+    fn expressions_are_checked_before_expansion() {
+        assert!(is_expression("x + 1"));
+        assert!(is_expression(""));
+        assert!(is_expression("$x * ${y:-2} + $(echo 3) - `echo 4` + $#"));
+        assert!(is_expression("\"$x\" > 1"));
+        assert!(!is_expression("cd a; make"));
+        assert!(!is_expression("echo $x"));
+    }
+}
@@ -0,0 +1,278 @@
+// Brace expansion: `pre{a,b}post` becomes `prea` and `preb`, and
+// `{1..10..2}` or `{a..e}` becomes a sequence. Only unquoted braces count.
+// It runs before any other expansion, so the results still go through `$`
+// expansion, field splitting and globbing like the word they came from.
+
+use crate::WordPart;
+
+// A word taken apart for brace expansion: its unquoted text one character
+// at a time, and its quoted parts whole, since only unquoted braces and
+// commas take part.
+#[derive(Debug, Clone)]
+enum Piece {
+    Char(char),
+    Quoted(WordPart),
+}
+
+/// Expands the braces in one word. A word without a valid brace expression
+/// comes back unchanged, as the only element.
+pub fn expand(word: &[WordPart]) -> Vec<Vec<WordPart>> {
+    let mut pieces = Vec::new();
+    for part in word {
+        match part {
+            WordPart::Unquoted(text) => pieces.extend(text.chars().map(Piece::Char)),
+            _ => pieces.push(Piece::Quoted(part.clone())),
+        }
+    }
+    expand_pieces(&pieces).iter().map(|pieces| join(pieces)).collect()
+}
+
+fn expand_pieces(pieces: &[Piece]) -> Vec<Vec<Piece>> {
+    let Some((open, close, commas)) = find_brace(pieces) else {
+        return vec![pieces.to_vec()];
+    };
+
+    let prefix = &pieces[..open];
+    let alternatives: Vec<Vec<Piece>> = if commas.is_empty() {
+        sequence(&pieces[open + 1..close])
+            .unwrap_or_default()
+            .iter()
+            .map(|item| item.chars().map(Piece::Char).collect())
+            .collect()
+    } else {
+        let mut bounds = vec![open];
+        bounds.extend(&commas);
+        bounds.push(close);
+        bounds
+            .windows(2)
+            .flat_map(|pair| expand_pieces(&pieces[pair[0] + 1..pair[1]]))
+            .collect()
+    };
+
+    let suffixes = expand_pieces(&pieces[close + 1..]);
+    let mut words = Vec::new();
+    for alternative in &alternatives {
+        for suffix in &suffixes {
+            words.push([prefix, alternative, suffix].concat());
+        }
+    }
+    words
+}
+
+/// Puts pieces back together into word parts.
+fn join(pieces: &[Piece]) -> Vec<WordPart> {
+    let mut parts = Vec::new();
+    let mut unquoted = String::new();
+    for piece in pieces {
+        match piece {
+            Piece::Char(c) => unquoted.push(*c),
+            Piece::Quoted(part) => {
+                if !unquoted.is_empty() {
+                    parts.push(WordPart::Unquoted(std::mem::take(&mut unquoted)));
+                }
+                parts.push(part.clone());
+            }
+        }
+    }
+    if !unquoted.is_empty() {
+        parts.push(WordPart::Unquoted(unquoted));
+    }
+    parts
+}
+
+fn char_at(pieces: &[Piece], i: usize) -> Option<char> {
+    match pieces.get(i) {
+        Some(Piece::Char(c)) => Some(*c),
+        _ => None,
+    }
+}
+
+/// Finds the first brace expression in `pieces`: the positions of its `{`
+/// and `}` and of the commas at its top level. Braces holding neither a
+/// comma nor a valid sequence are literal and skipped over.
+fn find_brace(pieces: &[Piece]) -> Option<(usize, usize, Vec<usize>)> {
+    let mut i = 0;
+    while i < pieces.len() {
+        if char_at(pieces, i) != Some('{') {
+            i = skip(pieces, i);
+            continue;
+        }
+
+        let mut depth = 0;
+        let mut commas = Vec::new();
+        let mut j = i + 1;
+        while j < pieces.len() {
+            match char_at(pieces, j) {
+                Some('{') => depth += 1,
+                Some('}') if depth == 0 => break,
+                Some('}') => depth -= 1,
+                Some(',') if depth == 0 => commas.push(j),
+                _ => {}
+            }
+            j = skip(pieces, j);
+        }
+
+        if j < pieces.len() && (!commas.is_empty() || sequence(&pieces[i + 1..j]).is_some()) {
+            return Some((i, j, commas));
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Returns the position after the piece at `i`, stepping over whole
+/// `${...}`, `$(...)` and backquoted substitutions so braces and commas
+/// inside them are never treated as part of a brace expression.
+fn skip(pieces: &[Piece], i: usize) -> usize {
+    match (char_at(pieces, i), char_at(pieces, i + 1)) {
+        (Some('$'), Some(open @ ('{' | '('))) => {
+            let close = if open == '{' { '}' } else { ')' };
+            let mut depth = 0;
+            let mut j = i + 1;
+            while j < pieces.len() {
+                match char_at(pieces, j) {
+                    Some(c) if c == open => depth += 1,
+                    Some(c) if c == close => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return j + 1;
+                        }
+                    }
+                    Some('\\') => j += 1,
+                    _ => {}
+                }
+                j += 1;
+            }
+            pieces.len()
+        }
+        (Some('`'), _) => {
+            let mut j = i + 1;
+            while j < pieces.len() && char_at(pieces, j) != Some('`') {
+                j += if char_at(pieces, j) == Some('\\') { 2 } else { 1 };
+            }
+            (j + 1).min(pieces.len())
+        }
+        _ => i + 1,
+    }
+}
+
+/// Expands the body of a `{x..y}` or `{x..y..step}` sequence, or returns
+/// `None` if it isn't one. The ends are either both integers or both single
+/// letters. An integer end written with a leading zero pads every number
+/// to the width of the wider end.
+fn sequence(body: &[Piece]) -> Option<Vec<String>> {
+    let body: String = (0..body.len()).map(|i| char_at(body, i)).collect::<Option<_>>()?;
+    let parts: Vec<&str> = body.split("..").collect();
+    let (start, end, step) = match parts.as_slice() {
+        [start, end] => (*start, *end, 1),
+        [start, end, step] => (*start, *end, step.parse::<i64>().ok()?),
+        _ => return None,
+    };
+    let step = step.unsigned_abs().max(1) as usize;
+
+    if let (Ok(first), Ok(last)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        let width = if is_padded(start) || is_padded(end) {
+            start.len().max(end.len())
+        } else {
+            0
+        };
+        return Some(
+            range(first, last, step)
+                .map(|n| {
+                    if n < 0 {
+                        format!("-{:0width$}", -n, width = width.saturating_sub(1))
+                    } else {
+                        format!("{:0width$}", n, width = width)
+                    }
+                })
+                .collect(),
+        );
+    }
+
+    let mut start_chars = start.chars();
+    let mut end_chars = end.chars();
+    match (start_chars.next(), start_chars.next(), end_chars.next(), end_chars.next()) {
+        (Some(first), None, Some(last), None) if first.is_ascii_alphabetic() && last.is_ascii_alphabetic() => Some(
+            range(first as i64, last as i64, step)
+                .map(|n| char::from(n as u8).to_string())
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+fn is_padded(number: &str) -> bool {
+    let digits = number.strip_prefix('-').unwrap_or(number);
+    digits.len() > 1 && digits.starts_with('0')
+}
+
+/// Counts from `first` to `last` inclusive in steps of `step`, downwards
+/// if `last` is the smaller.
+fn range(first: i64, last: i64, step: usize) -> Box<dyn Iterator<Item = i64>> {
+    if first <= last {
+        Box::new((first..=last).step_by(step))
+    } else {
+        Box::new((last..=first).rev().step_by(step))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The unquoted text of each word `text` expands to.
+    fn expanded(text: &str) -> Vec<String> {
+        expand(&[WordPart::Unquoted(text.to_string())])
+            .iter()
+            .map(|word| match word.as_slice() {
+                [WordPart::Unquoted(text)] => text.clone(),
+                [] => String::new(),
+                parts => panic!("unexpected parts {:?}", parts),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(expanded("a{b,c}d"), ["abd", "acd"]);
+        assert_eq!(expanded("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        assert_eq!(expanded("x{,y}"), ["x", "xy"]);
+    }
+
+    #[test]
+    fn nested() {
+        assert_eq!(expanded("a{b,c{d,e}}f"), ["abf", "acdf", "acef"]);
+        assert_eq!(expanded("{a,{1..3}}"), ["a", "1", "2", "3"]);
+        assert_eq!(expanded("{{a,b},c}"), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn sequences() {
+        assert_eq!(expanded("{1..4}"), ["1", "2", "3", "4"]);
+        assert_eq!(expanded("{3..1}"), ["3", "2", "1"]);
+        assert_eq!(expanded("{-1..1}"), ["-1", "0", "1"]);
+        assert_eq!(expanded("{1..10..4}"), ["1", "5", "9"]);
+        assert_eq!(expanded("{10..1..4}"), ["10", "6", "2"]);
+        assert_eq!(expanded("{08..10}"), ["08", "09", "10"]);
+        assert_eq!(expanded("{-05..-03}"), ["-05", "-04", "-03"]);
+        assert_eq!(expanded("{a..e..2}"), ["a", "c", "e"]);
+        assert_eq!(expanded("{C..A}"), ["C", "B", "A"]);
+    }
+
+    #[test]
+    fn invalid_expressions_stay_literal() {
+        for word in ["{a}", "{}", "{a..5}", "{5..a}", "{a..%}", "{ab..cd}", "{1..2..x}", "{1...3}", "a{b,c", "a}b,c{", "{1..}"] {
+            assert_eq!(expanded(word), [word]);
+        }
+    }
+
+    #[test]
+    fn quoted_braces_and_commas_do_not_count() {
+        let word = vec![
+            WordPart::Unquoted("{a".to_string()),
+            WordPart::SingleQuoted(",".to_string()),
+            WordPart::Unquoted("b}".to_string()),
+        ];
+        assert_eq!(expand(&word), vec![word]);
+    }
+}
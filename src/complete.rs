@@ -0,0 +1,726 @@
+// Tab completion: what the word before the cursor could become. A command
+// with a completion spec, set with `complete`, has its arguments completed
+// the way the spec says. Otherwise command names come from builtins,
+// functions and the `PATH`, and other words are completed as files, or as
+// variables, users or jobs by how they start.
+
+use std::env;
+use std::fs;
+
+use crate::{editor, glob, is_executable, parser, pattern, PipelineCommand, Shell, BUILTINS};
+
+// Characters that need a backslash to stand for themselves in a word.
+const SPECIAL: &str = " \t\n'\"\\$`&|;<>()*?[]{}!#";
+
+// Words after which a command name comes next.
+const COMMAND_STARTERS: &[&str] = &["!", "{", "do", "elif", "else", "if", "then", "until", "while"];
+
+// The lists a spec can draw candidates from, with the options that stand
+// for them besides `-A name`.
+const ACTIONS: &[(Option<char>, &str)] = &[
+    (Some('b'), "builtin"),
+    (Some('c'), "command"),
+    (Some('d'), "directory"),
+    (Some('f'), "file"),
+    (None, "function"),
+    (Some('j'), "job"),
+    (Some('u'), "user"),
+    (Some('v'), "variable"),
+];
+
+// What `-o` can turn on: `default` and `dirnames` fall back to file or
+// directory completion when the spec finds nothing, `filenames` treats the
+// candidates as files, quoting them and marking directories, and `nospace`
+// leaves out the space after a finished word.
+const OPTIONS: &[&str] = &["default", "dirnames", "filenames", "nospace"];
+
+/// One way to complete a word.
+pub struct Candidate {
+    /// The whole word once completed, quoted as it needs to be.
+    pub text: String,
+    /// How the candidate is shown in a list of them.
+    pub display: String,
+    /// Whether the word is finished, so a space can follow it. A directory
+    /// isn't, since a name inside it may come next.
+    pub finished: bool,
+}
+
+pub struct Completion {
+    /// Where the word being completed starts, as a byte offset.
+    pub start: usize,
+    /// The candidates, sorted.
+    pub candidates: Vec<Candidate>,
+}
+
+/// What's being completed, as a completion function sees it.
+#[derive(Default)]
+pub struct Request {
+    /// The command's words before the one being completed, from its name.
+    pub words: Vec<String>,
+    /// The word being completed, without its quotes.
+    pub word: String,
+    /// The whole line, and where in it the cursor is.
+    pub line: String,
+    pub point: usize,
+}
+
+/// How to complete the arguments of a command, as `complete` sets it.
+#[derive(Debug, Clone, Default)]
+pub struct Spec {
+    actions: Vec<&'static str>,
+    // `-G`: a pattern naming files.
+    glob: Option<String>,
+    // `-W`: words separated by whitespace.
+    words: Option<String>,
+    // `-F`: a function that leaves its candidates in `COMPREPLY`.
+    function: Option<String>,
+    // `-X`: a pattern for candidates to drop, or with a leading `!` to keep.
+    filter: Option<String>,
+    // `-P` and `-S`: text added before and after each candidate.
+    prefix: String,
+    suffix: String,
+    options: Vec<&'static str>,
+}
+
+impl Spec {
+    /// Reads the options `complete` and `compgen` share, returning the spec
+    /// and the arguments after the options.
+    pub fn parse(args: &[String]) -> Result<(Spec, &[String]), String> {
+        let mut spec = Spec::default();
+        let mut rest = args;
+        while let [arg, tail @ ..] = rest {
+            if arg == "--" {
+                rest = tail;
+                break;
+            }
+            let Some(letters) = arg.strip_prefix('-').filter(|letters| !letters.is_empty()) else {
+                break;
+            };
+            rest = tail;
+            for (i, letter) in letters.char_indices() {
+                if let Some((_, name)) = ACTIONS.iter().find(|(short, _)| *short == Some(letter)) {
+                    spec.actions.push(*name);
+                    continue;
+                }
+                if !"AGWFXPSo".contains(letter) {
+                    return Err(format!("-{}: invalid option", letter));
+                }
+                // The option's value is the rest of this argument, or the
+                // next one.
+                let value = match &letters[i + 1..] {
+                    "" => {
+                        let [value, tail @ ..] = rest else {
+                            return Err(format!("-{}: option requires an argument", letter));
+                        };
+                        rest = tail;
+                        value.clone()
+                    }
+                    attached => attached.to_string(),
+                };
+                match letter {
+                    'A' => match ACTIONS.iter().find(|(_, name)| *name == value) {
+                        Some((_, name)) => spec.actions.push(*name),
+                        None => return Err(format!("{}: invalid action name", value)),
+                    },
+                    'o' => match OPTIONS.iter().find(|option| **option == value) {
+                        Some(option) => spec.options.push(*option),
+                        None => return Err(format!("{}: invalid option name", value)),
+                    },
+                    'G' => spec.glob = Some(value),
+                    'W' => spec.words = Some(value),
+                    'F' => spec.function = Some(value),
+                    'X' => spec.filter = Some(value),
+                    'P' => spec.prefix = value,
+                    _ => spec.suffix = value,
+                }
+                break;
+            }
+        }
+        Ok((spec, rest))
+    }
+
+    /// The `complete` command that sets this spec for `name`.
+    pub fn describe(&self, name: &str) -> String {
+        let mut command = "complete".to_string();
+        for option in &self.options {
+            command.push_str(&format!(" -o {}", option));
+        }
+        for action in &self.actions {
+            match ACTIONS.iter().find(|(_, other)| other == action) {
+                Some((Some(short), _)) => command.push_str(&format!(" -{}", short)),
+                _ => command.push_str(&format!(" -A {}", action)),
+            }
+        }
+        let values = [
+            ('G', self.glob.as_deref()),
+            ('W', self.words.as_deref()),
+            ('F', self.function.as_deref()),
+            ('X', self.filter.as_deref()),
+            ('P', Some(self.prefix.as_str()).filter(|prefix| !prefix.is_empty())),
+            ('S', Some(self.suffix.as_str()).filter(|suffix| !suffix.is_empty())),
+        ];
+        for (letter, value) in values {
+            if let Some(value) = value {
+                command.push_str(&format!(" -{} {}", letter, single_quote(value)));
+            }
+        }
+        format!("{} {}", command, name)
+    }
+
+    /// The candidates for the word in `request`, unquoted. Those from the
+    /// spec's actions, pattern and words must start with the word; what a
+    /// function gives is taken as it is.
+    pub fn generate(&self, shell: &mut Shell, request: &Request) -> Vec<String> {
+        let word = request.word.as_str();
+        let mut found = Vec::new();
+        for action in &self.actions {
+            found.extend(generate_action(shell, action, word));
+        }
+        if let Some(pattern) = &self.glob {
+            let paths = glob::glob(pattern, &shell.glob_options);
+            found.extend(paths.into_iter().filter(|path| path.starts_with(word)));
+        }
+        if let Some(words) = &self.words {
+            let words = words.split_whitespace().filter(|candidate| candidate.starts_with(word));
+            found.extend(words.map(String::from));
+        }
+        if let Some(function) = &self.function {
+            found.extend(call_function(shell, function, request));
+        }
+        if let Some(filter) = &self.filter {
+            let (keep, pattern) = match filter.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, filter.as_str()),
+            };
+            found.retain(|candidate| pattern::matches(pattern, candidate) == keep);
+        }
+        found
+            .into_iter()
+            .map(|candidate| format!("{}{}{}", self.prefix, candidate, self.suffix))
+            .collect()
+    }
+
+    fn has_option(&self, option: &str) -> bool {
+        self.options.contains(&option)
+    }
+
+    /// Whether the candidates are file names, to quote and check for
+    /// directories.
+    fn names_files(&self) -> bool {
+        self.has_option("filenames") || self.actions.iter().any(|action| matches!(*action, "file" | "directory"))
+    }
+}
+
+/// Completes the word before `point` in `line`.
+pub fn complete(shell: &mut Shell, line: &str, point: usize) -> Completion {
+    let context = command_context(&line[..point]);
+    let word = &line[context.start..point];
+    let raw = unquote(word);
+
+    let spec = match context.words.first() {
+        Some(name) if !context.command_position => shell.completions.get(&unquote(name)).cloned(),
+        _ => None,
+    };
+    let mut candidates = match spec {
+        Some(spec) => {
+            let request = Request {
+                words: context.words.iter().map(|word| unquote(word)).collect(),
+                word: raw.clone(),
+                line: line.to_string(),
+                point,
+            };
+            let found = spec.generate(shell, &request);
+            let finished = !spec.has_option("nospace");
+            if !found.is_empty() {
+                let candidates = found.into_iter().map(|candidate| match spec.names_files() {
+                    true => file_candidate(candidate, finished),
+                    false => Candidate {
+                        text: candidate.clone(),
+                        display: candidate,
+                        finished,
+                    },
+                });
+                candidates.collect()
+            } else if spec.has_option("dirnames") {
+                let directories = files(&raw).into_iter().filter(|path| is_directory(path));
+                directories.map(|path| file_candidate(path, finished)).collect()
+            } else if spec.has_option("default") {
+                files(&raw).into_iter().map(|path| file_candidate(path, finished)).collect()
+            } else {
+                Vec::new()
+            }
+        }
+        None => complete_default(shell, word, &raw, context.command_position),
+    };
+    candidates.sort_by(|a, b| a.text.cmp(&b.text));
+    candidates.dedup_by(|a, b| a.text == b.text);
+    Completion {
+        start: context.start,
+        candidates,
+    }
+}
+
+/// The candidates `spec` gives for `word` outside of any command line,
+/// sorted, as `compgen` prints them.
+pub fn compgen(shell: &mut Shell, spec: &Spec, word: String) -> Vec<String> {
+    let request = Request {
+        word,
+        ..Default::default()
+    };
+    let mut candidates = spec.generate(shell, &request);
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+/// Completion for commands without a spec, going by where the word is and
+/// how it starts.
+fn complete_default(shell: &Shell, word: &str, raw: &str, command_position: bool) -> Vec<Candidate> {
+    if let Some(candidates) = complete_variable(shell, word) {
+        return candidates;
+    }
+    let simple = |text: String| Candidate {
+        text: quote(&text),
+        display: text,
+        finished: true,
+    };
+    if raw.starts_with('%') {
+        return jobs(shell, raw).into_iter().map(simple).collect();
+    }
+    if let Some(prefix) = raw.strip_prefix('~').filter(|prefix| !prefix.contains('/')) {
+        let names = user_names(prefix).into_iter();
+        let candidates = names.map(|name| Candidate {
+            text: format!("~{}/", quote(&name)),
+            display: format!("~{}", name),
+            finished: false,
+        });
+        return candidates.collect();
+    }
+    if command_position && !raw.contains('/') {
+        return commands(shell, raw).into_iter().map(simple).collect();
+    }
+    files(raw).into_iter().map(|path| file_candidate(path, true)).collect()
+}
+
+// Where the word being completed starts, and the command it belongs to.
+struct Context<'a> {
+    start: usize,
+    // The command's words before the one being completed, as typed, from
+    // its name on.
+    words: Vec<&'a str>,
+    // Whether the word being completed is where a command name goes: first
+    // in the line, after an operator or keyword, or after nothing but
+    // assignments.
+    command_position: bool,
+}
+
+/// Splits the command the end of `line` is in into words, closely enough
+/// for completion.
+fn command_context(line: &str) -> Context<'_> {
+    let mut context = Context {
+        start: 0,
+        words: Vec::new(),
+        command_position: true,
+    };
+    // Whether the word is the file of a redirection, which isn't one of the
+    // command's words.
+    let mut redirect_target = false;
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => escaped = true,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, ' ' | '\t' | '\n' | ';' | '&' | '|' | '(' | ')' | '<' | '>') => {
+                let word = &line[context.start..i];
+                if !word.is_empty() {
+                    if redirect_target {
+                        redirect_target = false;
+                    } else if context.command_position && COMMAND_STARTERS.contains(&word) {
+                        context.words.clear();
+                    } else if context.command_position && is_assignment(word) {
+                        // Still waiting for the command name.
+                    } else {
+                        context.words.push(word);
+                        context.command_position = false;
+                    }
+                }
+                match c {
+                    ';' | '&' | '|' | '(' | ')' | '\n' => {
+                        context.words.clear();
+                        context.command_position = true;
+                    }
+                    '<' | '>' => redirect_target = true,
+                    _ => {}
+                }
+                context.start = i + c.len_utf8();
+            }
+            (None, _) => {}
+        }
+    }
+    if redirect_target {
+        context.command_position = false;
+    }
+    context
+}
+
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| parser::is_name(name))
+}
+
+/// The text a word stands for once its quotes and backslashes are gone.
+fn unquote(word: &str) -> String {
+    let mut text = String::new();
+    let mut quote = None;
+    let mut chars = word.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (None, '\'' | '"') => quote = Some(c),
+            (Some('\''), _) => text.push(c),
+            (_, '\\') => text.extend(chars.next()),
+            _ => text.push(c),
+        }
+    }
+    text
+}
+
+/// Puts a backslash before each character of `text` that would otherwise
+/// be special.
+fn quote(text: &str) -> String {
+    let mut quoted = String::new();
+    for c in text.chars() {
+        if SPECIAL.contains(c) {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted
+}
+
+/// `text` in single quotes, as `complete -p` shows option values.
+fn single_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// A file as a candidate, quoted, with a `/` after it if it's a directory.
+fn file_candidate(path: String, finished: bool) -> Candidate {
+    let name = path.rsplit('/').next().unwrap_or(&path);
+    if is_directory(&path) {
+        Candidate {
+            text: format!("{}/", quote(&path)),
+            display: format!("{}/", name),
+            finished: false,
+        }
+    } else {
+        Candidate {
+            text: quote(&path),
+            display: name.to_string(),
+            finished,
+        }
+    }
+}
+
+/// Variable names, for a word ending in `$name` or `${name`.
+fn complete_variable(shell: &Shell, word: &str) -> Option<Vec<Candidate>> {
+    let dollar = word.rfind('$')?;
+    let (before, after) = (&word[..dollar], &word[dollar + 1..]);
+    let (open, close, prefix) = match after.strip_prefix('{') {
+        Some(prefix) => ("${", "}", prefix),
+        None => ("$", "", after),
+    };
+    if !prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    let candidates = variables(shell, prefix).into_iter().map(|name| Candidate {
+        text: format!("{}{}{}{}", before, open, name, close),
+        display: name,
+        finished: true,
+    });
+    Some(candidates.collect())
+}
+
+/// The names an action stands for, starting with `prefix`.
+fn generate_action(shell: &Shell, action: &str, prefix: &str) -> Vec<String> {
+    match action {
+        "builtin" => BUILTINS.iter().filter(|name| name.starts_with(prefix)).map(|name| name.to_string()).collect(),
+        "command" => commands(shell, prefix),
+        "directory" => files(prefix).into_iter().filter(|path| is_directory(path)).collect(),
+        "file" => files(prefix),
+        "function" => shell.functions.keys().filter(|name| name.starts_with(prefix)).cloned().collect(),
+        "job" => jobs(shell, prefix),
+        "user" => user_names(prefix),
+        _ => variables(shell, prefix),
+    }
+}
+
+/// Runs a completion function and returns the lines it leaves in
+/// `COMPREPLY`. As in bash, its arguments are the command's name, the word
+/// being completed and the word before it, and `COMP_LINE`, `COMP_POINT`,
+/// `COMP_WORDS` and `COMP_CWORD` describe the line for the length of the
+/// call. With no arrays, `COMP_WORDS` is the words joined by spaces, so a
+/// word with a space in it can't be told apart there; the function should
+/// take the word from `$2` and the rest from `COMP_LINE` up to `COMP_POINT`.
+fn call_function(shell: &mut Shell, name: &str, request: &Request) -> Vec<String> {
+    let Some(body) = shell.functions.get(name).cloned() else {
+        return Vec::new();
+    };
+    let mut words = request.words.clone();
+    words.push(request.word.clone());
+    let cmd = PipelineCommand {
+        command: name.to_string(),
+        args: vec![
+            request.words.first().cloned().unwrap_or_default(),
+            request.word.clone(),
+            request.words.last().cloned().unwrap_or_default(),
+        ],
+        redirections: Vec::new(),
+        assignments: vec![
+            ("COMP_LINE".to_string(), request.line.clone()),
+            ("COMP_POINT".to_string(), request.point.to_string()),
+            ("COMP_WORDS".to_string(), words.join(" ")),
+            ("COMP_CWORD".to_string(), (words.len() - 1).to_string()),
+        ],
+    };
+    let saved = shell.env_vars.remove("COMPREPLY");
+    editor::outside_raw_mode(|| shell.call_function(&body, &cmd));
+    let reply = shell.env_vars.remove("COMPREPLY").unwrap_or_default();
+    if let Some(saved) = saved {
+        shell.env_vars.insert("COMPREPLY".to_string(), saved);
+    }
+    reply.lines().filter(|line| !line.is_empty()).map(String::from).collect()
+}
+
+/// Builtins, functions, and programs in the `PATH` directories.
+fn commands(shell: &Shell, prefix: &str) -> Vec<String> {
+    let mut names: Vec<String> = BUILTINS.iter().map(|name| name.to_string()).collect();
+    names.extend(shell.functions.keys().cloned());
+    for dir in shell.search_path().split(':').filter(|dir| !dir.is_empty()) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(prefix) && is_executable(&entry.path()) {
+                names.push(name);
+            }
+        }
+    }
+    names.retain(|name| name.starts_with(prefix));
+    names
+}
+
+/// Paths to files and directories starting with `path`, relative to the
+/// current directory unless it says otherwise. Hidden files only match if
+/// their name is begun with a `.`.
+fn files(path: &str) -> Vec<String> {
+    let (dir, prefix) = match path.rfind('/') {
+        Some(slash) => path.split_at(slash + 1),
+        None => ("", path),
+    };
+    let search_dir = match dir {
+        "" => ".".to_string(),
+        _ => home_relative(dir),
+    };
+    let Ok(entries) = fs::read_dir(search_dir) else {
+        return Vec::new();
+    };
+    let names = entries.flatten().map(|entry| entry.file_name().to_string_lossy().into_owned());
+    names
+        .filter(|name| name.starts_with(prefix) && (!name.starts_with('.') || prefix.starts_with('.')))
+        .map(|name| format!("{}{}", dir, name))
+        .collect()
+}
+
+/// Variable names starting with `prefix`.
+fn variables(shell: &Shell, prefix: &str) -> Vec<String> {
+    shell.env_vars.keys().filter(|name| name.starts_with(prefix)).cloned().collect()
+}
+
+/// Job specs by number, like `%1`, starting with `prefix`.
+fn jobs(shell: &Shell, prefix: &str) -> Vec<String> {
+    let specs = shell.jobs.ids().into_iter().map(|id| format!("%{}", id));
+    specs.filter(|spec| spec.starts_with(prefix)).collect()
+}
+
+/// User names starting with `prefix`.
+fn user_names(prefix: &str) -> Vec<String> {
+    users().into_iter().map(|(name, _)| name).filter(|name| name.starts_with(prefix)).collect()
+}
+
+/// A path starting `~/` or `~user/` with the home directory in place of
+/// the `~` part.
+fn home_relative(path: &str) -> String {
+    let Some(rest) = path.strip_prefix('~') else {
+        return path.to_string();
+    };
+    let (user, rest) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let home = match user {
+        "" => env::var("HOME").ok(),
+        _ => users().into_iter().find(|(name, _)| name == user).map(|(_, home)| home),
+    };
+    match home {
+        Some(home) => format!("{}{}", home, rest),
+        None => path.to_string(),
+    }
+}
+
+/// Every user in the password file, with their home directory.
+fn users() -> Vec<(String, String)> {
+    let passwd = fs::read_to_string("/etc/passwd").unwrap_or_default();
+    passwd
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            Some((fields.first()?.to_string(), fields.get(5)?.to_string()))
+        })
+        .collect()
+}
+
+fn is_directory(path: &str) -> bool {
+    fs::metadata(home_relative(path)).is_ok_and(|metadata| metadata.is_dir())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glob::tests::TempDir;
+
+    fn args(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    // The candidates `spec_args` give for `word`, as `compgen` finds them.
+    fn generate(spec_args: &[&str], word: &str) -> Vec<String> {
+        let mut shell = Shell::new();
+        let args = args(spec_args);
+        let (spec, _) = Spec::parse(&args).unwrap();
+        let request = Request {
+            word: word.to_string(),
+            ..Default::default()
+        };
+        spec.generate(&mut shell, &request)
+    }
+
+    // The candidates Tab offers at the end of `line` after running `setup`.
+    fn complete_line(setup: &str, line: &str) -> Vec<(String, bool)> {
+        let mut shell = Shell::new();
+        shell.run_source(setup);
+        let completion = complete(&mut shell, line, line.len());
+        completion.candidates.into_iter().map(|candidate| (candidate.text, candidate.finished)).collect()
+    }
+
+    #[test]
+    fn spec_options_are_parsed_up_to_the_names() {
+        let args = args(&["-o", "nospace", "-d", "-A", "function", "-W", "a b", "-P", "<", "cmd", "other"]);
+        let (spec, names) = Spec::parse(&args).unwrap();
+        assert_eq!(spec.options, ["nospace"]);
+        assert_eq!(spec.actions, ["directory", "function"]);
+        assert_eq!(spec.words.as_deref(), Some("a b"));
+        assert_eq!(spec.prefix, "<");
+        assert_eq!(names, ["cmd", "other"]);
+
+        let args = self::args(&["-fW", "x y", "--", "-name"]);
+        let (spec, names) = Spec::parse(&args).unwrap();
+        assert_eq!(spec.actions, ["file"]);
+        assert_eq!(spec.words.as_deref(), Some("x y"));
+        assert_eq!(names, ["-name"]);
+    }
+
+    #[test]
+    fn bad_spec_options_are_errors() {
+        assert!(Spec::parse(&args(&["-A", "nothing", "cmd"])).is_err());
+        assert!(Spec::parse(&args(&["-o", "nothing", "cmd"])).is_err());
+        assert!(Spec::parse(&args(&["-q", "cmd"])).is_err());
+        assert!(Spec::parse(&args(&["-W"])).is_err());
+    }
+
+    #[test]
+    fn describe_gives_back_the_complete_command() {
+        let args = args(&["-o", "nospace", "-A", "function", "-b", "-W", "it's", "-S", "/", "cmd"]);
+        let (spec, _) = Spec::parse(&args).unwrap();
+        assert_eq!(spec.describe("cmd"), "complete -o nospace -A function -b -W 'it'\\''s' -S '/' cmd");
+        assert_eq!(Spec::default().describe("cmd"), "complete cmd");
+    }
+
+    #[test]
+    fn words_must_start_with_the_word_and_get_the_prefix_and_suffix() {
+        assert_eq!(generate(&["-W", "start stop status restart"], "st"), ["start", "stop", "status"]);
+        assert_eq!(generate(&["-W", "start stop", "-P", "[", "-S", "]"], "sto"), ["[stop]"]);
+        assert_eq!(generate(&["-W", "start stop status", "-X", "s*p"], ""), ["start", "status"]);
+        assert_eq!(generate(&["-W", "start stop status", "-X", "!s*p"], ""), ["stop"]);
+        assert!(generate(&["-W", "start stop"], "x").is_empty());
+    }
+
+    #[test]
+    fn completion_options_change_what_tab_offers() {
+        let setup = "complete -W 'start stop' svc";
+        assert_eq!(complete_line(setup, "svc sta"), [("start".to_string(), true)]);
+        let setup = "complete -o nospace -W 'start stop' svc";
+        assert_eq!(complete_line(setup, "svc sta"), [("start".to_string(), false)]);
+        let setup = "complete -o filenames -W 'x&y' svc";
+        assert_eq!(complete_line(setup, "svc x"), [("x\\&y".to_string(), true)]);
+
+        let dir = TempDir::new("complete-options", &["file", "sub/"]);
+        let files = complete_line("complete -o default -W 'start' svc", &format!("svc {}/", dir.0));
+        let expected = [(format!("{}/file", dir.0), true), (format!("{}/sub/", dir.0), false)];
+        assert_eq!(files, expected);
+        let dirs = complete_line("complete -o dirnames -W 'start' svc", &format!("svc {}/", dir.0));
+        assert_eq!(dirs, [(format!("{}/sub/", dir.0), false)]);
+        assert!(complete_line("complete -W 'start' svc", &format!("svc {}/", dir.0)).is_empty());
+    }
+
+    #[test]
+    fn completion_functions_get_the_word_as_their_second_argument() {
+        let setup = "_svc() { COMPREPLY=\"$1:$2:$3:$COMP_CWORD:$COMP_POINT\"; }; complete -F _svc svc";
+        assert_eq!(complete_line(setup, "svc -v 'a b"), [("svc:a b:-v:2:11".to_string(), true)]);
+    }
+
+    #[test]
+    fn compgen_sorts_the_candidates_and_drops_repeats() {
+        let mut shell = Shell::new();
+        let args = args(&["-W", "stop start stop", "-A", "function"]);
+        let (spec, _) = Spec::parse(&args).unwrap();
+        shell.run_source("stage() { :; }");
+        assert_eq!(compgen(&mut shell, &spec, "st".to_string()), ["stage", "start", "stop"]);
+        assert!(compgen(&mut shell, &spec, "x".to_string()).is_empty());
+    }
+
+    // Whether the word at the end of `line` goes where a command name does,
+    // the words of its command before it, and the word itself.
+    fn context(line: &str) -> (bool, Vec<&str>, &str) {
+        let context = command_context(line);
+        (context.command_position, context.words, &line[context.start..])
+    }
+
+    #[test]
+    fn command_position_follows_operators_and_substitutions() {
+        for line in ["ec", "ls | ec", "ls; ec", "ls && ec", "ls || ec", "echo $(ec", "if ec", "A=1 ec", "(ec"] {
+            assert_eq!(context(line), (true, vec![], "ec"), "{}", line);
+        }
+        assert_eq!(context("ls | grep -v fo"), (false, vec!["grep", "-v"], "fo"));
+        assert_eq!(context("echo $(ls -l "), (false, vec!["ls", "-l"], ""));
+        assert_eq!(context("ls > fi"), (false, vec!["ls"], "fi"));
+        assert_eq!(context("cat 'a; b' c"), (false, vec!["cat", "'a; b'"], "c"));
+        assert_eq!(context("cat a\\ b"), (false, vec!["cat"], "a\\ b"));
+    }
+
+    #[test]
+    fn quoting_round_trips_awkward_file_names() {
+        for name in ["plain", "with space", "it's", "say \"hi\"", "back\\slash", "a$b*c", "tab\there"] {
+            assert_eq!(unquote(&quote(name)), name);
+        }
+        assert_eq!(quote("it's a file"), "it\\'s\\ a\\ file");
+        assert_eq!(unquote("'it'\\''s' \"a \\\"b\\\"\""), "it's a \"b\"");
+        assert_eq!(unquote("\"don't\""), "don't");
+    }
+}
@@ -0,0 +1,598 @@
+// The line editor for interactive input. While a line is being read the
+// terminal is in raw mode, so every key arrives as typed and the editor does
+// its own echoing and cursor movement. The terminal's own settings are back
+// in place before any command runs.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::sync::{Mutex, PoisonError};
+
+use crate::complete::{self, Candidate};
+use crate::history::History;
+use crate::{sys, Shell};
+
+// How many completions can be listed without asking first.
+const LIST_WITHOUT_ASKING: usize = 100;
+
+// The terminal's own settings while raw mode is on, for completion
+// functions to run with.
+static ORIGINAL_MODE: Mutex<Option<sys::Termios>> = Mutex::new(None);
+
+/// Raw mode for as long as it's held. Dropping it, including while a panic
+/// unwinds, puts the terminal back the way it was.
+struct RawMode {
+    original: sys::Termios,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        let original = sys::terminal_mode(0)?;
+        sys::set_terminal_mode(0, &sys::raw_mode(&original))?;
+        *ORIGINAL_MODE.lock().unwrap_or_else(PoisonError::into_inner) = Some(original);
+        Ok(RawMode { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        ORIGINAL_MODE.lock().unwrap_or_else(PoisonError::into_inner).take();
+        sys::set_terminal_mode(0, &self.original).ok();
+    }
+}
+
+/// Runs a completion function with the terminal's own settings back in
+/// place, so the commands it runs see the terminal as any other command
+/// does and Ctrl-C and Ctrl-Z raise their signals again. Raw mode is back on
+/// once it returns, and a Ctrl-C that interrupted it doesn't also discard
+/// the line being edited.
+pub fn outside_raw_mode<T>(f: impl FnOnce() -> T) -> T {
+    let original = *ORIGINAL_MODE.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(original) = original else {
+        return f();
+    };
+    let raw = sys::terminal_mode(0);
+    sys::set_terminal_mode(0, &original).ok();
+    let result = f();
+    if let Ok(raw) = raw {
+        sys::set_terminal_mode(0, &raw).ok();
+    }
+    sys::set_interrupted(false);
+    result
+}
+
+// A key, decoded from the bytes the terminal sends for it.
+enum Key {
+    Char(char),
+    // A letter typed with Ctrl held, as the lowercase letter.
+    Control(u8),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Escape,
+    Unknown,
+}
+
+/// Reads one line of input, after printing `prompt` if the input is a
+/// terminal. Returns `None` once input is exhausted. Ctrl-C discards the
+/// line, returning it empty with the interrupted flag set. `shell` is
+/// what Tab completes commands, variables and jobs from, and runs
+/// completion functions in.
+pub fn read_line(prompt: &str, history: &mut History, shell: &mut Shell) -> Option<String> {
+    if !sys::is_terminal(0) {
+        return read_plain_line();
+    }
+    let Ok(raw) = RawMode::enable() else {
+        print!("{}", prompt);
+        io::stdout().flush().ok();
+        return read_plain_line();
+    };
+    let mut editor = Editor {
+        prompt,
+        buffer: Vec::new(),
+        cursor: 0,
+        cursor_row: 0,
+        search: None,
+        after_tab: false,
+    };
+    editor.refresh();
+    let line = editor.edit(history, shell);
+    drop(raw);
+    line
+}
+
+/// Reads up to the next newline with no editing, from a file or pipe or
+/// from a terminal doing the line editing itself.
+fn read_plain_line() -> Option<String> {
+    let mut line = Vec::new();
+    loop {
+        match sys::read_byte(0) {
+            Ok(Some(b'\n')) => break,
+            Ok(Some(byte)) => line.push(byte),
+            Ok(None) if line.is_empty() => return None,
+            Ok(None) => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                if sys::interrupted() {
+                    println!();
+                    return Some(String::new());
+                }
+            }
+            Err(_) => return None,
+        }
+    }
+    Some(String::from_utf8_lossy(&line).into_owned())
+}
+
+struct Editor<'a> {
+    prompt: &'a str,
+    buffer: Vec<char>,
+    // Where in `buffer` the next character goes.
+    cursor: usize,
+    // The row the terminal's cursor was left on by the last redraw,
+    // counting the prompt's row as 0.
+    cursor_row: usize,
+    search: Option<Search>,
+    // Whether the last key was Tab, so another lists the completions.
+    after_tab: bool,
+}
+
+// An incremental history search, started by Ctrl-R (or Ctrl-S to search
+// forward). While it runs, the line shows the entry found, with the cursor
+// at the match.
+struct Search {
+    term: String,
+    backward: bool,
+    // The history entry shown, once one has matched.
+    found: Option<usize>,
+    // Whether the term as typed has no match, so the line shows the last
+    // entry that did.
+    failed: bool,
+    // The line from before the search, for Ctrl-G to put back.
+    original: Vec<char>,
+}
+
+impl Editor<'_> {
+    fn edit(&mut self, history: &mut History, shell: &mut Shell) -> Option<String> {
+        loop {
+            let key = match self.read_key() {
+                Ok(Some(key)) => key,
+                Ok(None) if self.buffer.is_empty() => {
+                    self.finish("");
+                    return None;
+                }
+                Ok(None) => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted && !sys::interrupted() => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => Key::Control(b'c'),
+                Err(_) => return None,
+            };
+            let key = match self.search {
+                Some(_) => match self.search_key(key, history) {
+                    Some(key) => key,
+                    None => {
+                        self.refresh();
+                        continue;
+                    }
+                },
+                None => key,
+            };
+            let second_tab = std::mem::replace(&mut self.after_tab, matches!(key, Key::Control(b'i')));
+
+            match key {
+                Key::Enter => break,
+                Key::Control(b'c') => {
+                    self.finish("^C");
+                    sys::set_interrupted(true);
+                    return Some(String::new());
+                }
+                Key::Control(b'd') if self.buffer.is_empty() => {
+                    self.finish("");
+                    return None;
+                }
+                Key::Control(b'd') | Key::Delete => {
+                    if self.cursor < self.buffer.len() {
+                        self.buffer.remove(self.cursor);
+                    }
+                }
+                Key::Backspace => {
+                    if self.cursor > 0 {
+                        self.cursor -= 1;
+                        self.buffer.remove(self.cursor);
+                    }
+                }
+                Key::Left | Key::Control(b'b') => self.cursor = self.cursor.saturating_sub(1),
+                Key::Right | Key::Control(b'f') => self.cursor = (self.cursor + 1).min(self.buffer.len()),
+                Key::Home | Key::Control(b'a') => self.cursor = 0,
+                Key::End | Key::Control(b'e') => self.cursor = self.buffer.len(),
+                Key::Control(b'k') => self.buffer.truncate(self.cursor),
+                Key::Control(b'u') => {
+                    self.buffer.drain(..self.cursor);
+                    self.cursor = 0;
+                }
+                Key::Control(b'l') => {
+                    print!("\x1b[H\x1b[2J");
+                    self.cursor_row = 0;
+                }
+                Key::Up => {
+                    if let Some(previous) = history.get_previous() {
+                        self.set_text(&previous.clone());
+                    }
+                }
+                Key::Down => match history.get_next() {
+                    Some(next) => self.set_text(&next.clone()),
+                    None => self.set_text(""),
+                },
+                Key::Char(c) => {
+                    self.buffer.insert(self.cursor, c);
+                    self.cursor += 1;
+                }
+                Key::Control(b'i') => self.complete(shell, second_tab),
+                Key::Control(b'r') | Key::Control(b's') => {
+                    self.search = Some(Search {
+                        term: String::new(),
+                        backward: matches!(key, Key::Control(b'r')),
+                        found: None,
+                        failed: false,
+                        original: self.buffer.clone(),
+                    });
+                }
+                Key::Control(_) | Key::Escape | Key::Unknown => continue,
+            }
+            self.refresh();
+        }
+        self.finish("");
+        Some(self.buffer.iter().collect())
+    }
+
+    fn set_text(&mut self, text: &str) {
+        self.buffer = text.chars().collect();
+        self.cursor = self.buffer.len();
+    }
+
+    /// Completes the word before the cursor: all the way if only one thing
+    /// fits, otherwise as far as every candidate agrees. When that gets no
+    /// further, a second Tab lists the candidates.
+    fn complete(&mut self, shell: &mut Shell, second_tab: bool) {
+        let line: String = self.buffer.iter().collect();
+        let point = self.buffer[..self.cursor].iter().map(|c| c.len_utf8()).sum();
+        let completion = complete::complete(shell, &line, point);
+        let start = line[..completion.start].chars().count();
+        let replacement = match completion.candidates.as_slice() {
+            [] => {
+                print!("\x07");
+                return;
+            }
+            [only] if only.finished => format!("{} ", only.text),
+            [only] => only.text.clone(),
+            candidates => {
+                let common = common_prefix(candidates);
+                if common.chars().count() <= self.cursor - start {
+                    if second_tab {
+                        self.list(candidates);
+                    } else {
+                        print!("\x07");
+                    }
+                    return;
+                }
+                common
+            }
+        };
+        self.buffer.splice(start..self.cursor, replacement.chars());
+        self.cursor = start + replacement.chars().count();
+    }
+
+    /// Shows the candidates in columns below the line, asking first if
+    /// there are a lot of them. The line is redrawn underneath.
+    fn list(&mut self, candidates: &[Candidate]) {
+        let cursor = self.cursor;
+        self.finish("");
+        self.cursor = cursor;
+        self.cursor_row = 0;
+
+        if candidates.len() > LIST_WITHOUT_ASKING {
+            print!("Display all {} possibilities? (y or n)", candidates.len());
+            io::stdout().flush().ok();
+            let answer = sys::read_byte(0).ok().flatten();
+            print!("\r\n");
+            if !matches!(answer, Some(b'y' | b'Y' | b' ')) {
+                return;
+            }
+        }
+
+        let column_width = candidates.iter().map(|candidate| candidate.display.chars().count()).max().unwrap_or(0) + 2;
+        let columns = (terminal_width() / column_width).max(1);
+        let rows = candidates.len().div_ceil(columns);
+        for row in 0..rows {
+            let mut line = String::new();
+            // Down each column, then across, as `ls` does.
+            for candidate in candidates.iter().skip(row).step_by(rows) {
+                write!(line, "{:<width$}", candidate.display, width = column_width).ok();
+            }
+            print!("{}\r\n", line.trim_end());
+        }
+    }
+
+    /// Handles a key typed during a search. Keys that end the search and
+    /// then do what they usually do, like Enter or the arrows, come back.
+    fn search_key(&mut self, key: Key, history: &History) -> Option<Key> {
+        let search = self.search.as_mut()?;
+        match key {
+            Key::Char(c) => search.term.push(c),
+            Key::Backspace => {
+                search.term.pop();
+            }
+            Key::Control(b'r') => {
+                self.search_again(history, true);
+                return None;
+            }
+            Key::Control(b's') => {
+                self.search_again(history, false);
+                return None;
+            }
+            // Ctrl-G gives up, putting the line back as it was.
+            Key::Control(b'g') => {
+                self.buffer = search.original.clone();
+                self.cursor = self.buffer.len();
+                self.search = None;
+                return None;
+            }
+            // Escape keeps the entry found, to edit it.
+            Key::Escape => {
+                self.search = None;
+                return None;
+            }
+            key => {
+                self.search = None;
+                return Some(key);
+            }
+        }
+        // The term changed; the entry shown may still match it.
+        let from = search.found.or(history.len().checked_sub(1));
+        self.search_from(history, from, false);
+        None
+    }
+
+    /// Steps to the next match, older if `backward` and newer if not.
+    fn search_again(&mut self, history: &History, backward: bool) {
+        let Some(search) = &mut self.search else {
+            return;
+        };
+        let from = match search.found {
+            Some(found) if backward => found.checked_sub(1),
+            Some(found) => Some(found + 1),
+            None if backward => history.len().checked_sub(1),
+            None => None,
+        };
+        search.backward = backward;
+        self.search_from(history, from, true);
+    }
+
+    /// Shows the nearest entry matching the search, from entry `from` on in
+    /// the search's direction. With `skip_shown`, entries the same as the
+    /// line shown are passed over, so that stepping always changes it.
+    fn search_from(&mut self, history: &History, mut from: Option<usize>, skip_shown: bool) {
+        let Some(search) = &mut self.search else {
+            return;
+        };
+        let shown: String = self.buffer.iter().collect();
+        while let Some(start) = from {
+            let Some((index, entry)) = history.search(&search.term, start, search.backward) else {
+                break;
+            };
+            if skip_shown && entry == shown {
+                from = if search.backward { index.checked_sub(1) } else { Some(index + 1) };
+                continue;
+            }
+            let at = entry.find(&search.term).unwrap_or(0);
+            self.cursor = entry[..at].chars().count();
+            self.buffer = entry.chars().collect();
+            search.found = Some(index);
+            search.failed = false;
+            return;
+        }
+        search.failed = true;
+    }
+
+    /// The prompt as shown, which during a search is the search term.
+    fn shown_prompt(&self) -> String {
+        let Some(search) = &self.search else {
+            return self.prompt.to_string();
+        };
+        format!(
+            "({}{}i-search)`{}': ",
+            if search.failed { "failed " } else { "" },
+            if search.backward { "reverse-" } else { "" },
+            search.term
+        )
+    }
+
+    /// The part of the line to highlight: the match a search found.
+    fn highlight(&self) -> Option<(usize, usize)> {
+        let search = self.search.as_ref()?;
+        if search.failed || search.found.is_none() {
+            return None;
+        }
+        let end = (self.cursor + search.term.chars().count()).min(self.buffer.len());
+        Some((self.cursor, end))
+    }
+
+    /// Leaves the cursor after the end of the line, followed by `marker`,
+    /// and moves on to a fresh row for whatever comes next.
+    fn finish(&mut self, marker: &str) {
+        self.search = None;
+        self.cursor = self.buffer.len();
+        self.refresh();
+        // A line ending exactly at the edge already left us on a fresh row.
+        let end = self.prompt.chars().count() + width_of(&self.buffer);
+        if !marker.is_empty() || !end.is_multiple_of(terminal_width()) {
+            print!("{}\r\n", marker);
+        }
+        io::stdout().flush().ok();
+    }
+
+    /// Redraws the prompt and line, which may wrap over several rows of
+    /// the terminal, and puts the cursor where it belongs.
+    fn refresh(&mut self) {
+        let width = terminal_width();
+        let prompt = self.shown_prompt();
+        let prompt_width = prompt.chars().count();
+        let end = prompt_width + width_of(&self.buffer);
+        let at = prompt_width + width_of(&self.buffer[..self.cursor]);
+
+        // Back up to the prompt's row and clear everything from there down.
+        let mut output = String::new();
+        if self.cursor_row > 0 {
+            write!(output, "\x1b[{}A", self.cursor_row).ok();
+        }
+        output.push_str("\r\x1b[J");
+        output.push_str(&prompt);
+        match self.highlight() {
+            Some((start, end)) => {
+                output.push_str(&shown(&self.buffer[..start]));
+                output.push_str("\x1b[7m");
+                output.push_str(&shown(&self.buffer[start..end]));
+                output.push_str("\x1b[27m");
+                output.push_str(&shown(&self.buffer[end..]));
+            }
+            None => output.push_str(&shown(&self.buffer)),
+        }
+        // A line that exactly fills its last row leaves the cursor hanging
+        // at the edge; move it onto the next row so the arithmetic holds.
+        if end > 0 && end.is_multiple_of(width) {
+            output.push_str("\r\n");
+        }
+
+        let end_row = end / width;
+        let cursor_row = at / width;
+        if end_row > cursor_row {
+            write!(output, "\x1b[{}A", end_row - cursor_row).ok();
+        }
+        output.push('\r');
+        if !at.is_multiple_of(width) {
+            write!(output, "\x1b[{}C", at % width).ok();
+        }
+        self.cursor_row = cursor_row;
+
+        print!("{}", output);
+        io::stdout().flush().ok();
+    }
+
+    /// Reads the bytes of one key. `None` at end of input.
+    fn read_key(&mut self) -> io::Result<Option<Key>> {
+        let Some(byte) = sys::read_byte(0)? else {
+            return Ok(None);
+        };
+        let key = match byte {
+            b'\r' | b'\n' => Key::Enter,
+            127 | 8 => Key::Backspace,
+            // A lone escape, rather than the start of a key's sequence.
+            27 if !sys::input_ready(0, 50) => Key::Escape,
+            27 => self.read_escape()?,
+            1..=26 => Key::Control(b'a' + byte - 1),
+            0x80.. => self.read_utf8(byte)?,
+            _ => Key::Char(byte as char),
+        };
+        Ok(Some(key))
+    }
+
+    /// Decodes the rest of an escape sequence, as sent for the arrow,
+    /// Home, End and Delete keys.
+    fn read_escape(&mut self) -> io::Result<Key> {
+        let key = match sys::read_byte(0)? {
+            Some(b'[') => match sys::read_byte(0)? {
+                Some(b'A') => Key::Up,
+                Some(b'B') => Key::Down,
+                Some(b'C') => Key::Right,
+                Some(b'D') => Key::Left,
+                Some(b'H') => Key::Home,
+                Some(b'F') => Key::End,
+                Some(digit @ b'0'..=b'9') => {
+                    let mut code = vec![digit];
+                    while let Some(byte) = sys::read_byte(0)? {
+                        if byte == b'~' {
+                            break;
+                        }
+                        code.push(byte);
+                    }
+                    match code.as_slice() {
+                        b"1" | b"7" => Key::Home,
+                        b"4" | b"8" => Key::End,
+                        b"3" => Key::Delete,
+                        _ => Key::Unknown,
+                    }
+                }
+                _ => Key::Unknown,
+            },
+            Some(b'O') => match sys::read_byte(0)? {
+                Some(b'H') => Key::Home,
+                Some(b'F') => Key::End,
+                _ => Key::Unknown,
+            },
+            _ => Key::Unknown,
+        };
+        Ok(key)
+    }
+
+    /// Reads the continuation bytes of a character starting with `first`.
+    fn read_utf8(&mut self, first: u8) -> io::Result<Key> {
+        let length = match first {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return Ok(Key::Unknown),
+        };
+        let mut bytes = vec![first];
+        while bytes.len() < length {
+            match sys::read_byte(0)? {
+                Some(byte) => bytes.push(byte),
+                None => return Ok(Key::Unknown),
+            }
+        }
+        Ok(match std::str::from_utf8(&bytes).ok().and_then(|text| text.chars().next()) {
+            Some(c) => Key::Char(c),
+            None => Key::Unknown,
+        })
+    }
+}
+
+/// How `chars` are drawn on the line: control characters, like the
+/// newlines in an entry from a multi-line command, in caret notation as
+/// `^J`, so each stays on the row and the column counts hold.
+fn shown(chars: &[char]) -> String {
+    let mut text = String::new();
+    for &c in chars {
+        match c {
+            '\x7f' => text.push_str("^?"),
+            c if c < ' ' => {
+                text.push('^');
+                text.push((c as u8 + b'@') as char);
+            }
+            c => text.push(c),
+        }
+    }
+    text
+}
+
+/// The columns `chars` take up on the line.
+fn width_of(chars: &[char]) -> usize {
+    shown(chars).chars().count()
+}
+
+fn terminal_width() -> usize {
+    sys::terminal_width(0).unwrap_or(80)
+}
+
+/// The longest start all the candidates' text has in common.
+fn common_prefix(candidates: &[Candidate]) -> String {
+    let first = &candidates[0].text;
+    let mut length = first.len();
+    for candidate in &candidates[1..] {
+        let same = first.chars().zip(candidate.text.chars()).take_while(|(a, b)| a == b);
+        length = length.min(same.map(|(c, _)| c.len_utf8()).sum());
+    }
+    first[..length].to_string()
+}
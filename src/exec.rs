@@ -0,0 +1,848 @@
+// The executor: walks the syntax tree from `parser.rs`. Each command's words
+// are expanded right before it runs, so they see what earlier commands did.
+
+use std::fs::File;
+use std::io::{self, PipeReader, PipeWriter, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::rc::Rc;
+
+use crate::parser::{
+    AndOr, CaseItem, CaseTerminator, Command, Compound, Connector, List, Pipeline, SimpleCommand, Word,
+};
+use crate::jobs::{self, Jobs};
+use crate::{
+    exec_program, exit_code, fork_shell, pattern, run_builtin, spawn_stage, sys, PipelineCommand, Redirector, Shell, BUILTINS,
+    COMMAND_NOT_FOUND,
+};
+
+/// A `break` or `continue` on its way out to the loop it applies to, with
+/// how many enclosing loops it still has to pass through, or a `return` on
+/// its way out of the current function.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Flow {
+    Break(usize),
+    Continue(usize),
+    Return,
+}
+
+// What a loop does after part of it has run.
+enum LoopAction {
+    Proceed,
+    NextIteration,
+    Exit,
+}
+
+// A pipeline stage once it has been started.
+enum Running {
+    // A spawned program, or a forked copy of the shell running a builtin
+    // or compound command.
+    Process(i32),
+    // A stage that failed before it could start, with its status.
+    Finished(i32),
+}
+
+/// The process group a new process joins under job control: `pgid`, or a
+/// new group led by the process itself if that's 0. A foreground group
+/// gets the terminal.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct JobGroup {
+    pub pgid: i32,
+    pub foreground: bool,
+}
+
+impl Shell {
+    pub(crate) fn execute_list(&mut self, list: &List) -> i32 {
+        for and_or in &list.0 {
+            // Ctrl-C abandons the rest of the list.
+            if sys::interrupted() {
+                break;
+            }
+            if and_or.background {
+                self.execute_background(and_or);
+                continue;
+            }
+            self.execute_and_or(and_or);
+            if self.flow.is_some() {
+                break;
+            }
+        }
+        self.last_status
+    }
+
+    /// Starts an and-or list as a background job: a forked copy of the
+    /// shell, in a process group of its own, that runs the list and exits,
+    /// or becomes the program when the list is a single one.
+    fn execute_background(&mut self, and_or: &AndOr) {
+        // Without a terminal to stop it with, a job reading stdin would eat
+        // the input the shell reads its own commands from.
+        let detach_stdin = !sys::is_terminal(0);
+        let group = JobGroup {
+            pgid: 0,
+            foreground: false,
+        };
+        let started = self.fork_stage(None, None, None, Some(group), |shell| {
+            if detach_stdin {
+                if let Ok(null) = File::open("/dev/null") {
+                    sys::dup2(null.as_raw_fd(), 0).ok();
+                }
+            }
+            shell.jobs = Jobs::default();
+            match lone_command(and_or) {
+                Some(simple) => shell.exec_simple(simple),
+                None => shell.execute_and_or(and_or),
+            }
+        });
+        match started {
+            Ok(pid) => {
+                let id = self.jobs.add(pid, vec![(pid, None)], and_or.text.clone(), false);
+                if self.flags.contains('i') {
+                    eprintln!("[{}] {}", id, pid);
+                }
+                self.last_background_pid = Some(pid as u32);
+                self.last_status = 0;
+            }
+            Err(e) => {
+                eprintln!("Error executing command: {}", e);
+                self.last_status = 1;
+            }
+        }
+    }
+
+    /// Runs a simple command as the last thing a forked copy of the shell
+    /// does. A program replaces the copy rather than running under it, so
+    /// the pid the parent knows, as a job and as `$!`, is the program's own.
+    fn exec_simple(&mut self, simple: &SimpleCommand) -> i32 {
+        match self.expand_simple(simple) {
+            Ok(cmd) if self.runs_in_shell(&cmd) => self.run_simple(&cmd),
+            Ok(cmd) => match Redirector::permanent().apply(&cmd.redirections) {
+                Ok(()) => match exec_program(&cmd.command, &cmd.args, &cmd.assignments, self.search_path()) {
+                    Ok(status) => status,
+                    Err(e) => {
+                        eprintln!("Error executing command: {}", e);
+                        1
+                    }
+                },
+                Err(e) => {
+                    eprintln!("{}", e);
+                    1
+                }
+            },
+            Err(message) => {
+                eprintln!("{}", message);
+                1
+            }
+        }
+    }
+
+    fn execute_and_or(&mut self, and_or: &AndOr) -> i32 {
+        let mut status = self.execute_pipeline(&and_or.first);
+        for (connector, pipeline) in &and_or.rest {
+            if self.flow.is_some() || sys::interrupted() {
+                break;
+            }
+            let run = match connector {
+                Connector::And => status == 0,
+                Connector::Or => status != 0,
+            };
+            if run {
+                status = self.execute_pipeline(pipeline);
+            }
+        }
+        status
+    }
+
+    /// Runs a pipeline and records its status in `last_status`.
+    fn execute_pipeline(&mut self, pipeline: &Pipeline) -> i32 {
+        self.pipeline_text.clone_from(&pipeline.text);
+        let status = match pipeline.commands.as_slice() {
+            [command] => self.execute_command(command),
+            commands => self.execute_stages(commands),
+        };
+        let status = if pipeline.negated { (status == 0) as i32 } else { status };
+        self.last_status = status;
+        status
+    }
+
+    /// Runs one command in the shell itself, waiting for it to finish.
+    fn execute_command(&mut self, command: &Command) -> i32 {
+        match command {
+            Command::Simple(simple) => match self.expand_simple(simple) {
+                Ok(cmd) => self.run_simple(&cmd),
+                Err(message) => {
+                    eprintln!("{}", message);
+                    1
+                }
+            },
+            Command::Compound(compound, redirects) => {
+                let redirections = match self.expand_redirects(redirects) {
+                    Ok(redirections) => redirections,
+                    Err(message) => {
+                        eprintln!("{}", message);
+                        return 1;
+                    }
+                };
+                let mut redirector = Redirector::default();
+                let status = match redirector.apply(&redirections) {
+                    Ok(()) => self.execute_compound(compound),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        1
+                    }
+                };
+                redirector.restore();
+                status
+            }
+            Command::Function(name, body) => {
+                self.functions.insert(name.clone(), Rc::clone(body));
+                0
+            }
+        }
+    }
+
+    fn execute_compound(&mut self, compound: &Compound) -> i32 {
+        match compound {
+            Compound::BraceGroup(list) => self.execute_list(list),
+            Compound::Subshell(list) => {
+                let group = self.job_group(None);
+                let waited = self
+                    .fork_stage(None, None, None, group, |shell| shell.execute_list(list))
+                    .and_then(|pid| self.wait_foreground(vec![Running::Process(pid)]));
+                match waited {
+                    Ok(status) => status,
+                    Err(e) => {
+                        eprintln!("Error executing command: {}", e);
+                        1
+                    }
+                }
+            }
+            Compound::Arithmetic(expression) => match self.arithmetic(expression) {
+                Ok(value) => (value == 0) as i32,
+                Err(message) => {
+                    eprintln!("{}", message);
+                    1
+                }
+            },
+            Compound::If(branches, otherwise) => {
+                for (condition, body) in branches {
+                    let status = self.execute_list(condition);
+                    if self.flow.is_some() {
+                        return status;
+                    }
+                    if status == 0 {
+                        return self.execute_list(body);
+                    }
+                }
+                match otherwise {
+                    Some(body) => self.execute_list(body),
+                    None => 0,
+                }
+            }
+            Compound::Loop {
+                until,
+                condition,
+                body,
+            } => {
+                self.loop_depth += 1;
+                let mut status = 0;
+                loop {
+                    let test = self.execute_list(condition);
+                    match self.loop_action() {
+                        LoopAction::Proceed => {}
+                        LoopAction::NextIteration => continue,
+                        LoopAction::Exit => break,
+                    }
+                    if (test == 0) == *until {
+                        break;
+                    }
+                    status = self.execute_list(body);
+                    if let LoopAction::Exit = self.loop_action() {
+                        break;
+                    }
+                }
+                self.loop_depth -= 1;
+                status
+            }
+            Compound::For { name, words, body } => {
+                let items = match words {
+                    Some(words) => match self.expand_words(words) {
+                        Ok(items) => items,
+                        Err(message) => {
+                            eprintln!("{}", message);
+                            return 1;
+                        }
+                    },
+                    None => self.positional.clone(),
+                };
+
+                self.loop_depth += 1;
+                let mut status = 0;
+                for item in items {
+                    self.env_vars.insert(name.clone(), item);
+                    status = self.execute_list(body);
+                    if let LoopAction::Exit = self.loop_action() {
+                        break;
+                    }
+                }
+                self.loop_depth -= 1;
+                status
+            }
+            Compound::Case(word, items) => self.execute_case(word, items),
+        }
+    }
+
+    /// Runs the first item with a pattern matching the word, then follows
+    /// its terminator: stop, fall through into the next body, or keep
+    /// testing the items after it.
+    fn execute_case(&mut self, word: &Word, items: &[CaseItem]) -> i32 {
+        let subject = match self.expand_single(word) {
+            Ok(subject) => subject,
+            Err(message) => {
+                eprintln!("{}", message);
+                return 1;
+            }
+        };
+
+        let mut status = 0;
+        let mut fall_through = false;
+        for item in items {
+            if !fall_through {
+                let mut matched = false;
+                for word in &item.patterns {
+                    match self.expand_pattern(word) {
+                        Ok(pattern) if pattern::matches(&pattern, &subject) => {
+                            matched = true;
+                            break;
+                        }
+                        Ok(_) => {}
+                        Err(message) => {
+                            eprintln!("{}", message);
+                            return 1;
+                        }
+                    }
+                }
+                if !matched {
+                    continue;
+                }
+            }
+
+            status = self.execute_list(&item.body);
+            if self.flow.is_some() {
+                break;
+            }
+            match item.terminator {
+                CaseTerminator::Break => break,
+                CaseTerminator::FallThrough => fall_through = true,
+                CaseTerminator::Continue => fall_through = false,
+            }
+        }
+        status
+    }
+
+    /// Consumes a `break` or `continue` aimed at the innermost loop, and
+    /// passes one aimed further out on to the next loop.
+    fn loop_action(&mut self) -> LoopAction {
+        match self.flow.take() {
+            None if sys::interrupted() => LoopAction::Exit,
+            None => LoopAction::Proceed,
+            Some(Flow::Continue(1)) => LoopAction::NextIteration,
+            Some(Flow::Break(1)) => LoopAction::Exit,
+            Some(Flow::Break(n)) => {
+                self.flow = Some(Flow::Break(n - 1));
+                LoopAction::Exit
+            }
+            Some(Flow::Continue(n)) => {
+                self.flow = Some(Flow::Continue(n - 1));
+                LoopAction::Exit
+            }
+            Some(Flow::Return) => {
+                self.flow = Some(Flow::Return);
+                LoopAction::Exit
+            }
+        }
+    }
+
+    /// Calls a function with the command's arguments as its positional
+    /// parameters. Prefix assignments become locals of the call, and the
+    /// call's redirections apply around the whole body.
+    pub(crate) fn call_function(&mut self, body: &Command, cmd: &PipelineCommand) -> i32 {
+        let positional = std::mem::replace(&mut self.positional, cmd.args.clone());
+        let loop_depth = std::mem::take(&mut self.loop_depth);
+        self.local_frames.push(Vec::new());
+        for (name, value) in &cmd.assignments {
+            self.set_local(name, Some(value.clone()));
+        }
+
+        let mut redirector = Redirector::default();
+        let status = match redirector.apply(&cmd.redirections) {
+            Ok(()) => self.execute_command(body),
+            Err(e) => {
+                eprintln!("{}", e);
+                1
+            }
+        };
+        redirector.restore();
+        if let Some(Flow::Return) = self.flow {
+            self.flow = None;
+        }
+
+        if let Some(frame) = self.local_frames.pop() {
+            for (name, value) in frame.into_iter().rev() {
+                match value {
+                    Some(value) => self.env_vars.insert(name, value),
+                    None => self.env_vars.remove(&name),
+                };
+            }
+        }
+        self.loop_depth = loop_depth;
+        self.positional = positional;
+        status
+    }
+
+    /// Sets a variable local to the innermost function call, or unsets it
+    /// with `None`. The first time a call touches a name, its outer value is
+    /// saved so it can be put back when the call returns; until then,
+    /// functions it calls see the local value.
+    pub(crate) fn set_local(&mut self, name: &str, value: Option<String>) {
+        if let Some(frame) = self.local_frames.last_mut() {
+            if !frame.iter().any(|(saved, _)| saved == name) {
+                frame.push((name.to_string(), self.env_vars.get(name).cloned()));
+            }
+        }
+        match value {
+            Some(value) => self.env_vars.insert(name.to_string(), value),
+            None => self.env_vars.remove(name),
+        };
+    }
+
+    /// Whether a command runs inside the shell rather than as a program.
+    fn runs_in_shell(&self, cmd: &PipelineCommand) -> bool {
+        cmd.command.is_empty()
+            || self.functions.contains_key(&cmd.command)
+            || BUILTINS.contains(&cmd.command.as_str())
+    }
+
+    /// Runs an expanded simple command. Without a command name, its
+    /// assignments set shell variables and its redirections are performed
+    /// and then undone, and its status is that of the last command
+    /// substitution in it, if any.
+    fn run_simple(&mut self, cmd: &PipelineCommand) -> i32 {
+        if cmd.command.is_empty() {
+            for (name, value) in &cmd.assignments {
+                self.env_vars.insert(name.clone(), value.clone());
+            }
+            let mut redirector = Redirector::default();
+            let status = match redirector.apply(&cmd.redirections) {
+                Ok(()) => self.substitution_status.unwrap_or(0),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    1
+                }
+            };
+            redirector.restore();
+            return status;
+        }
+
+        if let Some(body) = self.functions.get(&cmd.command) {
+            return self.call_function(&Rc::clone(body), cmd);
+        }
+
+        let result = if BUILTINS.contains(&cmd.command.as_str()) {
+            run_builtin(self, cmd)
+        } else {
+            let group = self.job_group(None);
+            spawn_stage(cmd, self.search_path(), None, None, group).and_then(|child| match child {
+                Some(child) => self.wait_foreground(vec![Running::Process(child.id() as i32)]),
+                None => Ok(COMMAND_NOT_FOUND),
+            })
+        };
+        match result {
+            Ok(status) => status,
+            Err(e) => {
+                eprintln!("Error executing command: {}", e);
+                1
+            }
+        }
+    }
+
+    /// Starts every stage of a pipeline up front, connecting each one's
+    /// stdout to the next one's stdin, then reaps them all. External
+    /// commands are spawned directly; anything the shell runs itself gets a
+    /// forked copy of the shell. The last stage decides the status.
+    fn execute_stages(&mut self, commands: &[Command]) -> i32 {
+        let mut running = Vec::new();
+        let mut previous_output = None;
+        let mut result = Ok(());
+        // The process group every stage joins: the first one's.
+        let mut pgid = None;
+
+        for (i, command) in commands.iter().enumerate() {
+            let (reader, writer) = if i + 1 < commands.len() {
+                match io::pipe() {
+                    Ok((reader, writer)) => (Some(reader), Some(writer)),
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            } else {
+                (None, None)
+            };
+
+            let stdin = previous_output.take();
+            // The read end of this stage's own output, which a forked copy
+            // of the shell must not keep open, or it would never see its
+            // reader go away.
+            let output_end = reader.as_ref().map(|reader| reader.as_raw_fd());
+            let group = self.job_group(pgid);
+            let started = match command {
+                Command::Simple(simple) => match self.expand_simple(simple) {
+                    Ok(cmd) if self.runs_in_shell(&cmd) => {
+                        self.fork_stage(stdin, writer, output_end, group, |shell| shell.run_simple(&cmd))
+                            .map(Running::Process)
+                    }
+                    Ok(cmd) => spawn_stage(&cmd, self.search_path(), stdin, writer, group).map(|child| match child {
+                        Some(child) => Running::Process(child.id() as i32),
+                        None => Running::Finished(COMMAND_NOT_FOUND),
+                    }),
+                    // The stage fails on its own; the rest of the pipeline
+                    // still runs, reading nothing from it.
+                    Err(message) => {
+                        eprintln!("{}", message);
+                        Ok(Running::Finished(1))
+                    }
+                },
+                Command::Compound(..) | Command::Function(..) => self
+                    .fork_stage(stdin, writer, output_end, group, |shell| shell.execute_command(command))
+                    .map(Running::Process),
+            };
+            match started {
+                Ok(stage) => {
+                    if let (None, Running::Process(pid)) = (pgid, &stage) {
+                        pgid = Some(*pid);
+                    }
+                    running.push(stage);
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+            previous_output = reader;
+        }
+
+        // Drop our end of any unconsumed pipe before waiting, so an upstream
+        // writer sees EPIPE instead of blocking forever.
+        drop(previous_output);
+        match (result, self.wait_foreground(running)) {
+            (Ok(()), Ok(status)) => status,
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("Error executing command: {}", e);
+                1
+            }
+        }
+    }
+
+    /// The group a new foreground process joins: `pgid`, or a new one if
+    /// it's the first process of its job. `None` without job control.
+    fn job_group(&self, pgid: Option<i32>) -> Option<JobGroup> {
+        self.job_control.then_some(JobGroup {
+            pgid: pgid.unwrap_or(0),
+            foreground: true,
+        })
+    }
+
+    /// Waits for the stages of a foreground job, with the terminal handed to
+    /// its process group until they finish. Returns the last stage's status.
+    /// If the job stops instead, as with Ctrl-Z, it goes into the job table
+    /// and the status is 128 plus the stop signal.
+    fn wait_foreground(&mut self, running: Vec<Running>) -> io::Result<i32> {
+        let pgid = running.iter().find_map(|stage| match stage {
+            Running::Process(pid) => Some(*pid),
+            Running::Finished(_) => None,
+        });
+        let controlled = self.job_control && pgid.is_some();
+        if let (true, Some(pgid)) = (controlled, pgid) {
+            sys::set_terminal_group(0, pgid).ok();
+        }
+
+        let options = if controlled { sys::WUNTRACED } else { 0 };
+        let mut processes = Vec::new();
+        let mut status = 0;
+        let mut stop_signal = None;
+        let mut killed_by = None;
+        let mut result = Ok(());
+        for stage in running {
+            let pid = match stage {
+                Running::Process(pid) => pid,
+                Running::Finished(finished) => {
+                    status = finished;
+                    continue;
+                }
+            };
+            match sys::wait_pid(pid, options) {
+                Ok(Some(reaped)) => match reaped.stopped_signal() {
+                    Some(signal) => {
+                        stop_signal = Some(signal);
+                        processes.push((pid, None));
+                    }
+                    None => {
+                        status = exit_code(reaped);
+                        killed_by = reaped.signal().map(|signal| (signal, reaped.core_dumped()));
+                        processes.push((pid, Some(reaped)));
+                    }
+                },
+                Ok(None) => {}
+                Err(e) => {
+                    status = 1;
+                    result = Err(e);
+                }
+            }
+        }
+
+        if controlled {
+            sys::set_terminal_group(0, sys::process_group()).ok();
+        }
+        result?;
+        match killed_by {
+            // What was running is abandoned, as if the shell had been
+            // interrupted itself.
+            Some((sys::SIGINT, _)) => sys::set_interrupted(true),
+            Some((sys::SIGPIPE, _)) | None => {}
+            Some((signal, core_dumped)) => {
+                let core = if core_dumped { " (core dumped)" } else { "" };
+                eprintln!("{}{}", jobs::signal_name(signal), core);
+            }
+        }
+        if let (Some(signal), Some(pgid)) = (stop_signal, pgid) {
+            let id = self.jobs.add(pgid, processes, self.pipeline_text.clone(), true);
+            self.report_stopped(id);
+            return Ok(128 + signal);
+        }
+        Ok(status)
+    }
+
+    /// Tells the user a job has stopped, on a line of its own after the
+    /// `^Z` the terminal echoed.
+    pub(crate) fn report_stopped(&mut self, id: usize) {
+        eprintln!();
+        eprintln!("{}", self.jobs.describe(id, false));
+        self.jobs.reported(id);
+    }
+
+    /// Forks the shell to run `body` as a pipeline stage, subshell or
+    /// background job, with the pipe ends, if any, in place of its stdin and
+    /// stdout, and in `group` under job control. Returns the child's pid.
+    fn fork_stage(
+        &mut self,
+        stdin: Option<PipeReader>,
+        stdout: Option<PipeWriter>,
+        close: Option<RawFd>,
+        group: Option<JobGroup>,
+        body: impl FnOnce(&mut Shell) -> i32,
+    ) -> io::Result<i32> {
+        io::stdout().flush()?;
+        let pid = fork_shell()?;
+        if pid != 0 {
+            // Set from both sides, so the group is in place whichever of
+            // the two runs first.
+            if let Some(group) = group {
+                let pgid = if group.pgid == 0 { pid } else { group.pgid };
+                sys::set_process_group(pid, pgid).ok();
+            }
+            return Ok(pid);
+        }
+
+        // The copy runs its own commands without job control, all in the
+        // group it was started in.
+        match group {
+            Some(group) => sys::enter_job(group.pgid, group.foreground),
+            None => sys::default_signals(),
+        }
+        self.job_control = false;
+
+        let mut connected = Ok(());
+        if let Some(stdin) = &stdin {
+            connected = connected.and_then(|_| sys::dup2(stdin.as_raw_fd(), 0));
+        }
+        if let Some(stdout) = &stdout {
+            connected = connected.and_then(|_| sys::dup2(stdout.as_raw_fd(), 1));
+        }
+        drop((stdin, stdout));
+        if let Some(fd) = close {
+            sys::close(fd).ok();
+        }
+        let status = match connected {
+            Ok(()) => body(self),
+            Err(e) => {
+                eprintln!("Error executing command: {}", e);
+                1
+            }
+        };
+        io::stdout().flush().ok();
+        sys::exit_child(status)
+    }
+}
+
+/// The one simple command an and-or list is made of, if that's all it is.
+fn lone_command(and_or: &AndOr) -> Option<&SimpleCommand> {
+    match (and_or.first.commands.as_slice(), and_or.first.negated) {
+        ([Command::Simple(simple)], false) if and_or.rest.is_empty() => Some(simple),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs `source` in a new shell, for the test to look at what it left.
+    fn run(source: &str) -> Shell {
+        let mut shell = Shell::new();
+        shell.run_source(source);
+        shell
+    }
+
+    fn var<'a>(shell: &'a Shell, name: &str) -> &'a str {
+        shell.env_vars.get(name).map_or("", String::as_str)
+    }
+
+    #[test]
+    fn case_falls_through_and_continues_matching_in_order() {
+        let shell = run("case b in
+                a) out=${out}a ;;
+                b) out=${out}b ;&
+                c) out=${out}c ;;&
+                *c*) out=${out}x ;;
+                x|*) out=${out}d ;;
+                *) out=${out}e ;;
+            esac");
+        assert_eq!(var(&shell, "out"), "bcd");
+    }
+
+    #[test]
+    fn case_patterns_alternate_and_quoted_ones_match_literally() {
+        let shell = run("for v in a b '*' z; do
+                case $v in
+                    a|b) out=${out}1 ;;
+                    \"*\") out=${out}2 ;;
+                    *) out=${out}3 ;;
+                esac
+            done");
+        assert_eq!(var(&shell, "out"), "1123");
+        let shell = run("p='?'; case x in \"$p\") out=quoted ;; $p) out=unquoted ;; esac");
+        assert_eq!(var(&shell, "out"), "unquoted");
+    }
+
+    #[test]
+    fn locals_shadow_outer_values_until_the_call_returns() {
+        let shell = run("x=top
+            show() { seen=\"$seen $x\"; }
+            f() { local x=inner y; show; x=changed; y=set; show; }
+            f; show");
+        assert_eq!(var(&shell, "seen"), " inner changed top");
+        assert_eq!(var(&shell, "x"), "top");
+        assert!(!shell.env_vars.contains_key("y"));
+        assert!(shell.local_frames.is_empty());
+
+        let shell = run("g() { local n=$1; (( n > 0 )) && g $(( n - 1 )); seen=\"$seen$n\"; }; g 2");
+        assert_eq!(var(&shell, "seen"), "012");
+        assert!(!shell.env_vars.contains_key("n"));
+    }
+
+    #[test]
+    fn return_sets_the_status_and_leaves_loops_in_the_function() {
+        let shell = run("f() { return 3; out=skipped; }; f; first=$?
+            g() {
+                for i in 1 2 3; do
+                    while true; do (( i == 2 )) && return 7; break; done
+                    out=$out$i
+                done
+                out=${out}never
+            }
+            g; second=$?
+            for i in a b; do out=$out$i; done");
+        assert_eq!(var(&shell, "first"), "3");
+        assert_eq!(var(&shell, "second"), "7");
+        assert_eq!(var(&shell, "out"), "1ab");
+        assert_eq!(shell.loop_depth, 0);
+
+        let shell = run("f() { false; return; }; f; s=$?; h() { return 257; }; h");
+        assert_eq!(var(&shell, "s"), "1");
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn function_keyword_defines_a_function_too() {
+        let shell = run("function greet { out=\"$out hi $1\"; }
+            function paren() { out=\"$out ($#)\"; }
+            greet you; paren a b");
+        assert_eq!(var(&shell, "out"), " hi you (2)");
+        assert_eq!(shell.positional, Shell::new().positional);
+    }
+
+    #[test]
+    fn subshells_leave_the_shell_unchanged() {
+        let _lock = crate::glob::tests::lock_current_dir();
+        let cwd = std::env::current_dir().unwrap();
+        let shell = run("x=outer; ( cd /; x=inner; y=1; f() { :; }; exit 3 ); status=$?");
+        assert_eq!(std::env::current_dir().unwrap(), cwd);
+        assert_eq!(var(&shell, "x"), "outer");
+        assert!(!shell.env_vars.contains_key("y"));
+        assert!(!shell.functions.contains_key("f"));
+        assert_eq!(var(&shell, "status"), "3");
+
+        let shell = run("x=outer; seen=$( (echo $x; x=inner; echo $x) ); after=$x");
+        assert_eq!(var(&shell, "seen"), "outer\ninner");
+        assert_eq!(var(&shell, "after"), "outer");
+    }
+
+    #[test]
+    fn brace_groups_run_in_the_shell_itself() {
+        let shell = run("{ x=1; f() { :; }; false; }; status=$?; { y=$x; }");
+        assert_eq!(var(&shell, "x"), "1");
+        assert_eq!(var(&shell, "y"), "1");
+        assert!(shell.functions.contains_key("f"));
+        assert_eq!(var(&shell, "status"), "1");
+    }
+
+    #[test]
+    fn break_and_continue_count_enclosing_loops() {
+        let shell = run("for i in 1 2 3; do
+                for j in 1 2 3; do
+                    (( j == 2 )) && continue 2
+                    (( i == 3 )) && break 2
+                    out=\"$out$i$j \"
+                done
+                out=\"${out}never \"
+            done");
+        assert_eq!(var(&shell, "out"), "11 21 ");
+
+        let shell = run("for i in 1 2; do while true; do out=$out$i; break 5; done; done; out=${out}end");
+        assert_eq!(var(&shell, "out"), "1end");
+        assert_eq!(shell.loop_depth, 0);
+    }
+
+    #[test]
+    fn until_runs_while_its_condition_fails() {
+        let shell = run("n=0; until (( n >= 3 )); do out=$out$n; (( n += 1 )); done");
+        assert_eq!(var(&shell, "out"), "012");
+        let shell = run("until true; do out=ran; done");
+        assert_eq!(var(&shell, "out"), "");
+        assert_eq!(shell.last_status, 0);
+    }
+
+    #[test]
+    fn elif_chains_take_the_first_true_branch() {
+        let shell = run("for x in 1 2 3 4; do
+                if (( x == 1 )); then out=${out}a
+                elif (( x == 2 )); then out=${out}b
+                elif (( x == 3 )); then out=${out}c
+                else out=${out}d
+                fi
+            done");
+        assert_eq!(var(&shell, "out"), "abcd");
+        let shell = run("if false; then :; elif false; then :; fi");
+        assert_eq!(shell.last_status, 0);
+    }
+}
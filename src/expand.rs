@@ -0,0 +1,1073 @@
+// Word expansion. The lexer leaves `$` and backquote expansions in words as
+// raw text; they are carried out here, on one command's words at a time,
+// right before that command runs. Errors come back as the message to print.
+
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+
+use crate::parser::{self, Redirect, SimpleCommand, Word};
+use crate::{
+    arith, brace, exit_code, fork_shell, glob, parse_redirection, pattern, sys, Lexer, PipelineCommand, RedirectType,
+    Redirection, Shell, WordPart,
+};
+
+// Characters a backslash quotes inside double quotes and here-doc bodies.
+const QUOTE_ESCAPES: &str = "$`\"\\\n";
+const HEREDOC_ESCAPES: &str = "$`\\\n";
+
+const SPECIAL_PARAMETERS: &str = "?$!#@*-";
+
+// What `$IFS` is taken to be when it isn't set.
+const DEFAULT_IFS: &str = " \t\n";
+
+/// One field of an expanded word, with its text also written as a glob
+/// pattern in which everything that came from quotes is escaped.
+#[derive(Default)]
+struct Field {
+    text: String,
+    pattern: String,
+    // Quoted text keeps a field even when it is empty, as with `""`.
+    kept: bool,
+}
+
+impl Field {
+    fn is_empty(&self) -> bool {
+        !self.kept && self.text.is_empty()
+    }
+}
+
+/// Collects the fields of a word as its parts are expanded.
+struct Fields {
+    done: Vec<Field>,
+    current: Field,
+    separators: String,
+    // Whether the current field was just ended by IFS whitespace, which a
+    // following non-whitespace separator then belongs with.
+    after_space: bool,
+}
+
+impl Fields {
+    fn new(separators: String) -> Self {
+        Fields {
+            done: Vec::new(),
+            current: Field::default(),
+            separators,
+            after_space: false,
+        }
+    }
+
+    fn push_quoted(&mut self, text: &str) {
+        self.current.text.push_str(text);
+        self.current.pattern.push_str(&pattern::escape(text));
+        self.current.kept = true;
+        self.after_space = false;
+    }
+
+    fn push_unquoted(&mut self, c: char) {
+        self.current.text.push(c);
+        self.current.pattern.push(c);
+        self.current.kept = true;
+        self.after_space = false;
+    }
+
+    /// Adds the result of an unquoted expansion, splitting it into fields.
+    /// Runs of IFS whitespace separate fields; every other IFS character
+    /// ends one, so `a::b` splits on `:` into `a`, an empty field, and `b`.
+    fn push_expansion(&mut self, value: &str) {
+        for c in value.chars() {
+            if !self.separators.contains(c) {
+                self.current.text.push(c);
+                self.current.pattern.push(c);
+                self.after_space = false;
+            } else if c.is_whitespace() {
+                if !self.current.is_empty() {
+                    self.end_field(false);
+                    self.after_space = true;
+                }
+            } else {
+                if !(self.after_space && self.current.is_empty()) {
+                    self.end_field(true);
+                }
+                self.after_space = false;
+            }
+        }
+    }
+
+    /// Ends the current field. An empty one is only kept if it was quoted,
+    /// or if `force` says a separator delimited it.
+    fn end_field(&mut self, force: bool) {
+        let field = std::mem::take(&mut self.current);
+        if force || !field.is_empty() {
+            self.done.push(field);
+        }
+    }
+
+    fn finish(mut self) -> Vec<Field> {
+        self.end_field(false);
+        self.done
+    }
+}
+
+impl Shell {
+    /// Expands a simple command's words, assignments and redirections,
+    /// ready to run. `command` is left empty when the words expand to
+    /// nothing at all.
+    pub(crate) fn expand_simple(&mut self, simple: &SimpleCommand) -> Result<PipelineCommand, String> {
+        // Like an assignment on its own, a `NAME=value` argument to `local`
+        // is expanded as a single field.
+        let declaration = matches!(
+            simple.words.first().map(Vec::as_slice),
+            Some([WordPart::Unquoted(name)]) if name == "local"
+        );
+        self.substitution_status = None;
+        let mut words = Vec::new();
+        for (i, word) in simple.words.iter().enumerate() {
+            match parser::assignment(word) {
+                Some(assignment) if declaration && i > 0 => {
+                    let value = self.expand_single(&assignment.value)?;
+                    words.push(format!("{}={}", assignment.name, value));
+                }
+                _ => words.extend(self.expand_words(std::slice::from_ref(word))?),
+            }
+        }
+
+        let mut assignments = Vec::new();
+        for assignment in &simple.assignments {
+            let value = match &assignment.elements {
+                Some(elements) => self.expand_words(elements)?.join("\n"),
+                None => self.expand_single(&assignment.value)?,
+            };
+            assignments.push((assignment.name.clone(), value));
+        }
+
+        let mut words = words.into_iter();
+        Ok(PipelineCommand {
+            command: words.next().unwrap_or_default(),
+            args: words.collect(),
+            redirections: self.expand_redirects(&simple.redirects)?,
+            assignments,
+        })
+    }
+
+    /// Expands words into arguments: brace expansion, then `$` expansions,
+    /// field splitting and globbing.
+    pub(crate) fn expand_words(&mut self, words: &[Word]) -> Result<Vec<String>, String> {
+        let mut expanded = Vec::new();
+        for word in words {
+            for word in brace::expand(word) {
+                for field in self.expand_word(&word, true)? {
+                    self.expand_pathname(field, &mut expanded)?;
+                }
+            }
+        }
+        Ok(expanded)
+    }
+
+    pub(crate) fn expand_redirects(&mut self, redirects: &[Redirect]) -> Result<Vec<Redirection>, String> {
+        let mut redirections = Vec::new();
+        for redirect in redirects {
+            match &redirect.kind {
+                RedirectType::HereDoc(body, false) => {
+                    let body = self.expand_text(body, HEREDOC_ESCAPES)?;
+                    let kind = RedirectType::HereDoc(body, true);
+                    parse_redirection(redirect.fd, &kind, "", &mut redirections);
+                }
+                kind => {
+                    let target = self.expand_single(&redirect.target)?;
+                    parse_redirection(redirect.fd, kind, &target, &mut redirections);
+                }
+            }
+        }
+        Ok(redirections)
+    }
+
+    /// Expands a word to be used as a pattern, as in `case`. Quoted parts
+    /// only match themselves.
+    pub(crate) fn expand_pattern(&mut self, word: &[WordPart]) -> Result<String, String> {
+        let fields: Vec<String> = self
+            .expand_word(word, false)?
+            .into_iter()
+            .map(|field| field.pattern)
+            .collect();
+        Ok(fields.join(" "))
+    }
+
+    /// Expands a word that has to stay one word, like an assignment's value
+    /// or a redirection target: no field splitting and no globbing.
+    pub(crate) fn expand_single(&mut self, word: &[WordPart]) -> Result<String, String> {
+        let fields: Vec<String> = self
+            .expand_word(word, false)?
+            .into_iter()
+            .map(|field| field.text)
+            .collect();
+        Ok(fields.join(" "))
+    }
+
+    /// Expands one word into the fields it stands for. With `split`, the
+    /// results of unquoted expansions are split on the characters of `$IFS`;
+    /// an unquoted expansion that comes out empty leaves no field behind.
+    fn expand_word(&mut self, parts: &[WordPart], split: bool) -> Result<Vec<Field>, String> {
+        let separators = if split {
+            self.lookup("IFS").unwrap_or_else(|| DEFAULT_IFS.to_string())
+        } else {
+            String::new()
+        };
+        let mut fields = Fields::new(separators);
+        for part in parts {
+            match part {
+                WordPart::SingleQuoted(text) => fields.push_quoted(text),
+                WordPart::DoubleQuoted(raw) => {
+                    for (i, text) in self.expand_quoted(raw)?.iter().enumerate() {
+                        if i > 0 {
+                            fields.end_field(true);
+                        }
+                        fields.push_quoted(text);
+                    }
+                }
+                WordPart::Unquoted(raw) => {
+                    let mut lexer = Lexer::new(raw);
+                    while let Some(c) = lexer.advance() {
+                        let mut value = String::new();
+                        match c {
+                            '$' => self.expand_dollar(&mut lexer, &mut value)?,
+                            '`' => {
+                                let body = unescape_backquoted(&lexer.lex_backquoted());
+                                value = self.command_substitution(&body);
+                            }
+                            _ => {
+                                fields.push_unquoted(c);
+                                continue;
+                            }
+                        }
+                        fields.push_expansion(&value);
+                    }
+                }
+            }
+        }
+        Ok(fields.finish())
+    }
+
+    fn expand_pathname(&self, field: Field, expanded: &mut Vec<String>) -> Result<(), String> {
+        if !pattern::has_wildcards(&field.pattern) {
+            expanded.push(field.text);
+            return Ok(());
+        }
+        let matches = glob::glob(&field.pattern, &self.glob_options);
+        if !matches.is_empty() {
+            expanded.extend(matches);
+        } else if self.glob_options.failglob {
+            return Err(format!("no match: {}", field.text));
+        } else if !self.glob_options.nullglob {
+            expanded.push(field.text);
+        }
+        Ok(())
+    }
+
+    /// Expands the inside of double quotes. `"$@"` is the one quoted
+    /// expansion that can make several fields: one per positional parameter,
+    /// with the text before it joined to the first and the text after it to
+    /// the last. With no parameters and nothing else in the quotes, there
+    /// are no fields at all.
+    fn expand_quoted(&mut self, raw: &str) -> Result<Vec<String>, String> {
+        let mut fields = Vec::new();
+        let mut current = String::new();
+        let mut has_parameters = false;
+        let mut has_text = false;
+        let mut lexer = Lexer::new(raw);
+        let mut segment_start = 0;
+        while let Some(c) = lexer.advance() {
+            let start = lexer.position - 1;
+            let parameters = match c {
+                '\\' => {
+                    lexer.advance();
+                    continue;
+                }
+                '`' => {
+                    lexer.lex_backquoted();
+                    continue;
+                }
+                '$' => match self.expand_positional(&mut lexer)? {
+                    Some(parameters) => parameters,
+                    None => continue,
+                },
+                _ => continue,
+            };
+
+            has_parameters = true;
+            if start > segment_start {
+                has_text = true;
+                let segment: String = lexer.input[segment_start..start].iter().collect();
+                current.push_str(&self.expand_text(&segment, QUOTE_ESCAPES)?);
+            }
+            for (i, parameter) in parameters.into_iter().enumerate() {
+                if i > 0 {
+                    fields.push(std::mem::take(&mut current));
+                }
+                current.push_str(&parameter);
+            }
+            segment_start = lexer.position;
+        }
+        if lexer.input.len() > segment_start {
+            has_text = true;
+            let segment: String = lexer.input[segment_start..].iter().collect();
+            current.push_str(&self.expand_text(&segment, QUOTE_ESCAPES)?);
+        }
+        if !has_parameters || has_text || !fields.is_empty() || !current.is_empty() {
+            fields.push(current);
+        }
+        Ok(fields)
+    }
+
+    /// Reads the expansion after a `$`. If it is `$@`, `${@}` or
+    /// `${@:offset:length}`, returns the positional parameters it stands for;
+    /// otherwise leaves the lexer past it, for the caller to expand later.
+    fn expand_positional(&mut self, lexer: &mut Lexer) -> Result<Option<Vec<String>>, String> {
+        match lexer.peek() {
+            Some('@') => {
+                lexer.advance();
+                Ok(Some(self.positional.clone()))
+            }
+            Some('{') => {
+                lexer.advance();
+                let body = lexer.lex_braced();
+                if body == "@" {
+                    return Ok(Some(self.positional.clone()));
+                }
+                match body.strip_prefix("@:").filter(|_| split_test(&body[1..]).is_none()) {
+                    Some(spec) => self.positional_slice(spec).map(Some),
+                    None => Ok(None),
+                }
+            }
+            Some('(') => {
+                lexer.advance();
+                lexer.lex_substitution();
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The positional parameters `${@:offset:length}` stands for. Offset 0
+    /// starts at `$0`, and a negative one counts back from the last.
+    fn positional_slice(&mut self, spec: &str) -> Result<Vec<String>, String> {
+        let (offset, length) = split_unescaped(spec, ':');
+        let offset = self.arithmetic(offset)?;
+        let length = length.map(|length| self.arithmetic(length)).transpose()?;
+        let mut parameters = vec![self.arg0.clone()];
+        parameters.extend(self.positional.iter().cloned());
+        let count = parameters.len() as i64;
+        let start = if offset < 0 { count + offset } else { offset };
+        if start < 0 || start >= count {
+            return Ok(Vec::new());
+        }
+        let end = match length {
+            None => count,
+            Some(length) if length < 0 => return Err(format!("{}: substring expression < 0", length)),
+            Some(length) => (start + length).min(count),
+        };
+        Ok(parameters[start as usize..end as usize].to_vec())
+    }
+
+    /// Joins the parameters the way `$*` does, with the first character of
+    /// `$IFS` between them; a space if it is unset, and nothing if it is empty.
+    fn join_parameters(&self, parameters: &[String]) -> String {
+        let separator = match self.lookup("IFS") {
+            Some(ifs) => ifs.chars().next().map(String::from).unwrap_or_default(),
+            None => " ".to_string(),
+        };
+        parameters.join(&separator)
+    }
+
+    /// Performs the `$` and backquote expansions in raw text. A backslash
+    /// in front of one of `escapes` quotes that character; in front of
+    /// anything else it stays, and still keeps the character from expanding.
+    fn expand_text(&mut self, raw: &str, escapes: &str) -> Result<String, String> {
+        let mut lexer = Lexer::new(raw);
+        let mut expanded = String::new();
+        while let Some(c) = lexer.advance() {
+            match c {
+                '\\' => match lexer.advance() {
+                    Some('\n') if escapes.contains('\n') => {}
+                    Some(next) if escapes.contains(next) => expanded.push(next),
+                    Some(next) => {
+                        expanded.push(c);
+                        expanded.push(next);
+                    }
+                    None => expanded.push(c),
+                },
+                '$' => self.expand_dollar(&mut lexer, &mut expanded)?,
+                '`' => {
+                    let body = unescape_backquoted(&lexer.lex_backquoted());
+                    expanded.push_str(&self.command_substitution(&body));
+                }
+                _ => expanded.push(c),
+            }
+        }
+        Ok(expanded)
+    }
+
+    fn expand_dollar(&mut self, lexer: &mut Lexer, expanded: &mut String) -> Result<(), String> {
+        match lexer.peek() {
+            Some('(') => {
+                lexer.advance();
+                let body = lexer.lex_substitution();
+                match arithmetic_body(&body) {
+                    Some(expression) => expanded.push_str(&self.arithmetic(expression)?.to_string()),
+                    None => expanded.push_str(&self.command_substitution(&body)),
+                }
+            }
+            Some('{') => {
+                lexer.advance();
+                let body = lexer.lex_braced();
+                expanded.push_str(&self.expand_parameter(&body)?);
+            }
+            // `$10` is `$1` followed by a 0; only `${10}` reaches past $9.
+            Some(c) if c.is_ascii_digit() || SPECIAL_PARAMETERS.contains(c) => {
+                lexer.advance();
+                if let Some(value) = self.lookup(&c.to_string()) {
+                    expanded.push_str(&value);
+                }
+            }
+            _ => {
+                let name = lexer.lex_name();
+                if name.is_empty() {
+                    expanded.push('$');
+                } else if let Some(value) = self.lookup(&name) {
+                    expanded.push_str(&value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Expands the `$` and backquote expansions in an arithmetic expression,
+    /// then evaluates it.
+    pub(crate) fn arithmetic(&mut self, raw: &str) -> Result<i64, String> {
+        let expression = self.expand_text(raw, QUOTE_ESCAPES)?;
+        arith::evaluate(&expression, &mut self.env_vars)
+    }
+
+    fn lookup(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_status.to_string()),
+            "$" => Some(self.shell_pid.to_string()),
+            "!" => self.last_background_pid.map(|pid| pid.to_string()),
+            "0" => Some(self.arg0.clone()),
+            "#" => Some(self.positional.len().to_string()),
+            "@" => Some(self.positional.join(" ")),
+            "*" => Some(self.join_parameters(&self.positional)),
+            "-" => Some(self.flags.clone()),
+            _ if name.chars().all(|c| c.is_ascii_digit()) => {
+                let index: usize = name.parse().ok()?;
+                self.positional.get(index.checked_sub(1)?).cloned()
+            }
+            _ => self.env_vars.get(name).cloned(),
+        }
+    }
+
+    /// Expands the inside of a `${...}`.
+    fn expand_parameter(&mut self, body: &str) -> Result<String, String> {
+        let bad_substitution = || format!("${{{}}}: bad substitution", body);
+
+        if let Some(name) = body.strip_prefix('#').filter(|name| is_parameter(name)) {
+            if name == "@" || name == "*" {
+                return Ok(self.positional.len().to_string());
+            }
+            let value = self.lookup(name).unwrap_or_default();
+            return Ok(value.chars().count().to_string());
+        }
+        if let Some(name) = body.strip_prefix('!').filter(|name| is_named(name)) {
+            let target = self.lookup(name).unwrap_or_default();
+            return Ok(self.lookup(&target).unwrap_or_default());
+        }
+
+        let name_len = match body.chars().next() {
+            Some(c) if c.is_ascii_digit() => {
+                body.find(|c: char| !c.is_ascii_digit()).unwrap_or(body.len())
+            }
+            Some(c) if SPECIAL_PARAMETERS.contains(c) => 1,
+            _ => body
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(body.len()),
+        };
+        let (name, operation) = body.split_at(name_len);
+        if name.is_empty() {
+            return Err(bad_substitution());
+        }
+        let value = self.lookup(name);
+
+        if let Some((check_null, test, word)) = split_test(operation) {
+            let is_set = value.as_ref().is_some_and(|v| !(check_null && v.is_empty()));
+            return match (test, is_set) {
+                ('-', false) => self.expand_operand(word, false),
+                ('=', false) => {
+                    let assigned = self.expand_operand(word, false)?;
+                    self.env_vars.insert(name.to_string(), assigned.clone());
+                    Ok(assigned)
+                }
+                ('?', false) => {
+                    let message = self.expand_operand(word, false)?;
+                    if message.is_empty() {
+                        Err(format!("{}: parameter null or not set", name))
+                    } else {
+                        Err(format!("{}: {}", name, message))
+                    }
+                }
+                ('+', true) => self.expand_operand(word, false),
+                ('+', false) => Ok(String::new()),
+                _ => Ok(value.unwrap_or_default()),
+            };
+        }
+
+        if let (Some(spec), "@" | "*") = (operation.strip_prefix(':'), name) {
+            let parameters = self.positional_slice(spec)?;
+            return Ok(match name {
+                "*" => self.join_parameters(&parameters),
+                _ => parameters.join(" "),
+            });
+        }
+
+        let value = value.unwrap_or_default();
+        if operation.is_empty() {
+            return Ok(value);
+        }
+        if let Some(pattern) = operation.strip_prefix("##") {
+            let pattern = self.expand_operand(pattern, true)?;
+            return Ok(remove_prefix(&value, &pattern, true));
+        }
+        if let Some(pattern) = operation.strip_prefix('#') {
+            let pattern = self.expand_operand(pattern, true)?;
+            return Ok(remove_prefix(&value, &pattern, false));
+        }
+        if let Some(pattern) = operation.strip_prefix("%%") {
+            let pattern = self.expand_operand(pattern, true)?;
+            return Ok(remove_suffix(&value, &pattern, true));
+        }
+        if let Some(pattern) = operation.strip_prefix('%') {
+            let pattern = self.expand_operand(pattern, true)?;
+            return Ok(remove_suffix(&value, &pattern, false));
+        }
+        if let Some(spec) = operation.strip_prefix('/') {
+            let (anchor, spec) = match spec.chars().next() {
+                Some(anchor @ ('/' | '#' | '%')) => (Some(anchor), &spec[1..]),
+                _ => (None, spec),
+            };
+            let (pattern, replacement) = split_unescaped(spec, '/');
+            let pattern = self.expand_operand(pattern, true)?;
+            let replacement = self.expand_operand(replacement.unwrap_or(""), false)?;
+            return Ok(replace_pattern(&value, &pattern, &replacement, anchor));
+        }
+        if let Some(spec) = operation.strip_prefix(':') {
+            let (offset, length) = split_unescaped(spec, ':');
+            let offset = self.arithmetic(offset)?;
+            let length = length.map(|length| self.arithmetic(length)).transpose()?;
+            return Ok(substring(&value, offset, length));
+        }
+        for (prefix, upper, all) in [("^^", true, true), ("^", true, false), (",,", false, true), (",", false, false)] {
+            if let Some(pattern) = operation.strip_prefix(prefix) {
+                let pattern = self.expand_operand(pattern, true)?;
+                return Ok(change_case(&value, &pattern, upper, all));
+            }
+        }
+        Err(bad_substitution())
+    }
+
+    /// Expands the word inside a `${...}` (a default value, a pattern or a
+    /// replacement) and removes its quotes. In a pattern, quoted text is
+    /// escaped so that it only matches itself.
+    fn expand_operand(&mut self, word: &str, as_pattern: bool) -> Result<String, String> {
+        let quoted = |text: &str| {
+            if as_pattern {
+                pattern::escape(text)
+            } else {
+                text.to_string()
+            }
+        };
+
+        let mut lexer = Lexer::new(word);
+        let mut expanded = String::new();
+        while let Some(c) = lexer.advance() {
+            match c {
+                '\'' => {
+                    let mut literal = String::new();
+                    while let Some(inner) = lexer.advance().filter(|&inner| inner != '\'') {
+                        literal.push(inner);
+                    }
+                    expanded.push_str(&quoted(&literal));
+                }
+                '"' => {
+                    let mut raw = String::new();
+                    while let Some(inner) = lexer.advance().filter(|&inner| inner != '"') {
+                        lexer.lex_raw_char(inner, &mut raw);
+                    }
+                    let text = self.expand_text(&raw, QUOTE_ESCAPES)?;
+                    expanded.push_str(&quoted(&text));
+                }
+                '\\' => {
+                    if let Some(next) = lexer.advance() {
+                        expanded.push_str(&quoted(&next.to_string()));
+                    }
+                }
+                '$' => self.expand_dollar(&mut lexer, &mut expanded)?,
+                '`' => {
+                    let body = unescape_backquoted(&lexer.lex_backquoted());
+                    expanded.push_str(&self.command_substitution(&body));
+                }
+                _ => expanded.push(c),
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Runs `source` in a forked copy of the shell and returns what it
+    /// printed, minus trailing newlines. Its status becomes `$?`.
+    fn command_substitution(&mut self, source: &str) -> String {
+        match self.capture_output(source) {
+            Ok((output, status)) => {
+                self.last_status = status;
+                self.substitution_status = Some(status);
+                output.trim_end_matches('\n').to_string()
+            }
+            Err(e) => {
+                eprintln!("Error executing command: {}", e);
+                String::new()
+            }
+        }
+    }
+
+    fn capture_output(&mut self, source: &str) -> io::Result<(String, i32)> {
+        let (mut reader, writer) = io::pipe()?;
+        io::stdout().flush()?;
+
+        let pid = fork_shell()?;
+        if pid == 0 {
+            // In the child: anything the commands print goes into the pipe,
+            // and nothing they change leaks back into the parent shell. It
+            // stays in the shell's own process group, where Ctrl-C reaches it.
+            drop(reader);
+            self.job_control = false;
+            sys::default_signals();
+            let status = match sys::dup2(writer.as_raw_fd(), 1) {
+                Ok(()) => {
+                    drop(writer);
+                    self.run_source(source)
+                }
+                Err(_) => 1,
+            };
+            io::stdout().flush().ok();
+            sys::exit_child(status);
+        }
+
+        drop(writer);
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output)?;
+        let status = sys::wait_for(pid)?;
+        Ok((String::from_utf8_lossy(&output).into_owned(), exit_code(status)))
+    }
+}
+
+/// A variable name or a positional parameter's number.
+fn is_named(text: &str) -> bool {
+    parser::is_name(text) || (!text.is_empty() && text.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn is_parameter(text: &str) -> bool {
+    is_named(text) || (text.len() == 1 && SPECIAL_PARAMETERS.contains(text))
+}
+
+/// Splits the `-`, `=`, `?` and `+` tests (optionally preceded by `:`, which
+/// makes an empty value count as unset) into their parts.
+fn split_test(operation: &str) -> Option<(bool, char, &str)> {
+    let (check_null, rest) = match operation.strip_prefix(':') {
+        Some(rest) => (true, rest),
+        None => (false, operation),
+    };
+    let test = rest.chars().next().filter(|c| "-=?+".contains(*c))?;
+    Some((check_null, test, &rest[1..]))
+}
+
+/// Splits `text` at the first `separator` that isn't backslash-escaped.
+fn split_unescaped(text: &str, separator: char) -> (&str, Option<&str>) {
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == separator {
+            return (&text[..i], Some(&text[i + c.len_utf8()..]));
+        }
+    }
+    (text, None)
+}
+
+fn remove_prefix(value: &str, pattern: &str, longest: bool) -> String {
+    let mut ends: Vec<usize> = value.char_indices().map(|(i, _)| i).chain([value.len()]).collect();
+    if longest {
+        ends.reverse();
+    }
+    ends.into_iter()
+        .find(|&end| pattern::matches(pattern, &value[..end]))
+        .map_or_else(|| value.to_string(), |end| value[end..].to_string())
+}
+
+fn remove_suffix(value: &str, pattern: &str, longest: bool) -> String {
+    let mut starts: Vec<usize> = value.char_indices().map(|(i, _)| i).chain([value.len()]).collect();
+    if !longest {
+        starts.reverse();
+    }
+    starts
+        .into_iter()
+        .find(|&start| pattern::matches(pattern, &value[start..]))
+        .map_or_else(|| value.to_string(), |start| value[..start].to_string())
+}
+
+/// Replaces the longest match of `pattern` in `value`: the first one, every
+/// one (anchor `/`), or only one at the start (`#`) or end (`%`).
+fn replace_pattern(value: &str, pattern: &str, replacement: &str, anchor: Option<char>) -> String {
+    if pattern.is_empty() {
+        return value.to_string();
+    }
+    let bounds: Vec<usize> = value.char_indices().map(|(i, _)| i).chain([value.len()]).collect();
+    let longest_match_at = |start: usize| {
+        bounds
+            .iter()
+            .rev()
+            .copied()
+            .filter(|&end| end >= start)
+            .find(|&end| pattern::matches(pattern, &value[start..end]))
+    };
+
+    match anchor {
+        Some('#') => match longest_match_at(0) {
+            Some(end) => format!("{}{}", replacement, &value[end..]),
+            None => value.to_string(),
+        },
+        Some('%') => bounds
+            .iter()
+            .find(|&&start| pattern::matches(pattern, &value[start..]))
+            .map_or_else(
+                || value.to_string(),
+                |&start| format!("{}{}", &value[..start], replacement),
+            ),
+        _ => {
+            let all = anchor == Some('/');
+            let mut result = String::new();
+            let mut position = 0;
+            let mut i = 0;
+            while i < bounds.len() {
+                let start = bounds[i];
+                match longest_match_at(start).filter(|&end| end > start) {
+                    Some(end) => {
+                        result.push_str(&value[position..start]);
+                        result.push_str(replacement);
+                        position = end;
+                        if !all {
+                            break;
+                        }
+                        i = bounds.iter().position(|&b| b == end).unwrap_or(bounds.len());
+                    }
+                    None => i += 1,
+                }
+            }
+            result.push_str(&value[position..]);
+            result
+        }
+    }
+}
+
+/// `${var:offset}` and `${var:offset:length}`, counted in characters. A
+/// negative offset counts from the end, and a negative length stops that
+/// many characters short of it.
+fn substring(value: &str, offset: i64, length: Option<i64>) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let len = chars.len() as i64;
+    let start = if offset < 0 { len + offset } else { offset };
+    if start < 0 || start > len {
+        return String::new();
+    }
+    let end = match length {
+        None => len,
+        Some(length) if length < 0 => (len + length).max(start),
+        Some(length) => (start + length).min(len),
+    };
+    chars[start as usize..end as usize].iter().collect()
+}
+
+/// If the body of a `$(...)` is wrapped in one more pair of parentheses, it
+/// was really a `$((...))` and this returns the expression inside.
+fn arithmetic_body(body: &str) -> Option<&str> {
+    let inner = body.strip_prefix('(')?.strip_suffix(')')?;
+    let mut depth = 0;
+    for c in inner.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return None,
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    Some(inner)
+}
+
+/// `^`/`,` change the first character to upper/lower case, `^^`/`,,` all of
+/// them. A pattern, if given, limits the change to characters matching it.
+fn change_case(value: &str, pattern: &str, upper: bool, all: bool) -> String {
+    let mut result = String::new();
+    for (i, c) in value.chars().enumerate() {
+        let selected = (all || i == 0) && (pattern.is_empty() || pattern::matches(pattern, &c.to_string()));
+        if !selected {
+            result.push(c);
+        } else if upper {
+            result.extend(c.to_uppercase());
+        } else {
+            result.extend(c.to_lowercase());
+        }
+    }
+    result
+}
+
+// Inside backquotes, a backslash only quotes `$`, a backquote or itself.
+fn unescape_backquoted(body: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(&next) = chars.peek() {
+                if "$`\\".contains(next) {
+                    unescaped.push(next);
+                    chars.next();
+                    continue;
+                }
+            }
+        }
+        unescaped.push(c);
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glob::tests::TempDir;
+
+    fn shell_with(positional: &[&str]) -> Shell {
+        let mut shell = Shell::new();
+        shell.positional = positional.iter().map(|arg| arg.to_string()).collect();
+        shell
+    }
+
+    // The arguments the words of the simple command `line` expand to.
+    fn expand(shell: &mut Shell, line: &str) -> Vec<String> {
+        let list = parser::parse(line).unwrap();
+        let parser::Command::Simple(simple) = &list.0[0].first.commands[0] else {
+            panic!("expected a simple command");
+        };
+        shell.expand_words(&simple.words).unwrap()
+    }
+
+    // `shell` with the variables in `vars` set.
+    fn shell_with_vars(vars: &[(&str, &str)]) -> Shell {
+        let mut shell = shell_with(&[]);
+        for (name, value) in vars {
+            shell.env_vars.insert(name.to_string(), value.to_string());
+        }
+        shell
+    }
+
+    // The value of `${body}`.
+    fn parameter(shell: &mut Shell, body: &str) -> String {
+        shell.expand_parameter(body).unwrap()
+    }
+
+    #[test]
+    fn tests_with_a_colon_treat_empty_as_unset() {
+        let mut shell = shell_with_vars(&[("set", "x"), ("empty", "")]);
+        assert_eq!(parameter(&mut shell, "unset:-d"), "d");
+        assert_eq!(parameter(&mut shell, "empty:-d"), "d");
+        assert_eq!(parameter(&mut shell, "set:-d"), "x");
+        assert_eq!(parameter(&mut shell, "empty:+a"), "");
+        assert_eq!(parameter(&mut shell, "set:+a"), "a");
+        assert_eq!(parameter(&mut shell, "unset:+a"), "");
+        assert_eq!(parameter(&mut shell, "set:?gone"), "x");
+        assert_eq!(shell.expand_parameter("empty:?").unwrap_err(), "empty: parameter null or not set");
+        assert_eq!(shell.expand_parameter("unset:?gone").unwrap_err(), "unset: gone");
+        assert_eq!(parameter(&mut shell, "empty:=v"), "v");
+        assert_eq!(shell.env_vars["empty"], "v");
+        assert_eq!(parameter(&mut shell, "new:=$set$set"), "xx");
+        assert_eq!(shell.env_vars["new"], "xx");
+    }
+
+    #[test]
+    fn tests_without_a_colon_only_treat_unset_as_unset() {
+        let mut shell = shell_with_vars(&[("empty", "")]);
+        assert_eq!(parameter(&mut shell, "empty-d"), "");
+        assert_eq!(parameter(&mut shell, "unset-d"), "d");
+        assert_eq!(parameter(&mut shell, "empty+a"), "a");
+        assert_eq!(parameter(&mut shell, "unset+a"), "");
+        assert_eq!(parameter(&mut shell, "empty?gone"), "");
+        assert_eq!(shell.expand_parameter("unset?gone").unwrap_err(), "unset: gone");
+        assert_eq!(parameter(&mut shell, "empty=v"), "");
+        assert_eq!(shell.env_vars["empty"], "");
+        assert_eq!(parameter(&mut shell, "unset=v"), "v");
+        assert_eq!(shell.env_vars["unset"], "v");
+    }
+
+    #[test]
+    fn length_counts_characters() {
+        let mut shell = shell_with(&["a", "b", "c"]);
+        shell.env_vars.insert("v".to_string(), "héllo".to_string());
+        assert_eq!(parameter(&mut shell, "#v"), "5");
+        assert_eq!(parameter(&mut shell, "#unset"), "0");
+        assert_eq!(parameter(&mut shell, "#@"), "3");
+        assert_eq!(parameter(&mut shell, "#1"), "1");
+    }
+
+    #[test]
+    fn doubled_removal_operators_remove_the_longest_match() {
+        let mut shell = shell_with_vars(&[("v", "a/b/c.tar.gz")]);
+        assert_eq!(parameter(&mut shell, "v#*/"), "b/c.tar.gz");
+        assert_eq!(parameter(&mut shell, "v##*/"), "c.tar.gz");
+        assert_eq!(parameter(&mut shell, "v%.*"), "a/b/c.tar");
+        assert_eq!(parameter(&mut shell, "v%%.*"), "a/b/c");
+        assert_eq!(parameter(&mut shell, "v#x*"), "a/b/c.tar.gz");
+        assert_eq!(parameter(&mut shell, "v%\"*.gz\""), "a/b/c.tar.gz");
+        assert_eq!(parameter(&mut shell, "v#"), "a/b/c.tar.gz");
+    }
+
+    #[test]
+    fn replacement_takes_the_first_every_leading_or_trailing_match() {
+        let mut shell = shell_with_vars(&[("v", "banana")]);
+        assert_eq!(parameter(&mut shell, "v/an/AN"), "bANana");
+        assert_eq!(parameter(&mut shell, "v//an/AN"), "bANANa");
+        assert_eq!(parameter(&mut shell, "v//a"), "bnn");
+        assert_eq!(parameter(&mut shell, "v/a*/X"), "bX");
+        assert_eq!(parameter(&mut shell, "v/#ba/X"), "Xnana");
+        assert_eq!(parameter(&mut shell, "v/#na/X"), "banana");
+        assert_eq!(parameter(&mut shell, "v/%na/X"), "banaX");
+        assert_eq!(parameter(&mut shell, "v/%ba/X"), "banana");
+        assert_eq!(parameter(&mut shell, "v/x/y"), "banana");
+    }
+
+    #[test]
+    fn substrings_count_characters_and_clamp_to_the_value() {
+        let mut shell = shell_with_vars(&[("v", "héllo wörld")]);
+        assert_eq!(parameter(&mut shell, "v:1:4"), "éllo");
+        assert_eq!(parameter(&mut shell, "v:6"), "wörld");
+        assert_eq!(parameter(&mut shell, "v: -5"), "wörld");
+        assert_eq!(parameter(&mut shell, "v:(-5):2"), "wö");
+        assert_eq!(parameter(&mut shell, "v:2:-3"), "llo wö");
+        assert_eq!(parameter(&mut shell, "v:8:-5"), "");
+        assert_eq!(parameter(&mut shell, "v:3:100"), "lo wörld");
+        assert_eq!(parameter(&mut shell, "v:11"), "");
+        assert_eq!(parameter(&mut shell, "v:20"), "");
+        assert_eq!(parameter(&mut shell, "v: -20"), "");
+        assert_eq!(parameter(&mut shell, "v:0:0"), "");
+    }
+
+    #[test]
+    fn case_changes_apply_to_the_first_or_every_matching_character() {
+        let mut shell = shell_with_vars(&[("v", "hello wörld"), ("w", "HÉLLO")]);
+        assert_eq!(parameter(&mut shell, "v^"), "Hello wörld");
+        assert_eq!(parameter(&mut shell, "v^^"), "HELLO WÖRLD");
+        assert_eq!(parameter(&mut shell, "v^^[lo]"), "heLLO wörLd");
+        assert_eq!(parameter(&mut shell, "v^[a-g]"), "hello wörld");
+        assert_eq!(parameter(&mut shell, "w,"), "hÉLLO");
+        assert_eq!(parameter(&mut shell, "w,,"), "héllo");
+        assert_eq!(parameter(&mut shell, "w,,L"), "HÉllO");
+    }
+
+    #[test]
+    fn adjacent_quoted_and_unquoted_parts_make_one_word() {
+        let mut shell = shell_with(&[]);
+        shell.env_vars.insert("v".to_string(), "1 2".to_string());
+        assert_eq!(expand(&mut shell, r#"echo a"b c"'d'\ e"#), ["echo", "ab cd e"]);
+        assert_eq!(expand(&mut shell, r#"echo x"$v"y 'x$v'"#), ["echo", "x1 2y", "x$v"]);
+        assert_eq!(expand(&mut shell, "echo x${v}y"), ["echo", "x1", "2y"]);
+    }
+
+    #[test]
+    fn empty_quoted_arguments_survive() {
+        let mut shell = shell_with(&[]);
+        assert_eq!(expand(&mut shell, r#"echo "" '' a """#), ["echo", "", "", "a", ""]);
+        assert_eq!(expand(&mut shell, r#"echo $unset ""$unset "$unset""#), ["echo", "", ""]);
+    }
+
+    #[test]
+    fn ifs_whitespace_runs_separate_and_other_characters_delimit() {
+        let mut shell = shell_with(&[]);
+        shell.env_vars.insert("v".to_string(), "  a \t b\n ".to_string());
+        assert_eq!(expand(&mut shell, "echo $v"), ["echo", "a", "b"]);
+
+        shell.env_vars.insert("IFS".to_string(), ":".to_string());
+        shell.env_vars.insert("v".to_string(), "a::b:".to_string());
+        assert_eq!(expand(&mut shell, "echo $v"), ["echo", "a", "", "b"]);
+        shell.env_vars.insert("v".to_string(), ":a b".to_string());
+        assert_eq!(expand(&mut shell, "echo $v"), ["echo", "", "a b"]);
+
+        shell.env_vars.insert("IFS".to_string(), " :".to_string());
+        shell.env_vars.insert("v".to_string(), " a : b  :: c".to_string());
+        assert_eq!(expand(&mut shell, "echo $v"), ["echo", "a", "b", "", "c"]);
+
+        shell.env_vars.insert("IFS".to_string(), String::new());
+        assert_eq!(expand(&mut shell, "echo $v"), ["echo", " a : b  :: c"]);
+    }
+
+    #[test]
+    fn quoted_at_makes_one_field_per_parameter() {
+        let mut shell = shell_with(&["a b", "c"]);
+        assert_eq!(expand(&mut shell, r#"echo "$@""#), ["echo", "a b", "c"]);
+        assert_eq!(expand(&mut shell, r#"echo "x$@y""#), ["echo", "xa b", "cy"]);
+        assert_eq!(expand(&mut shell, r#"echo "pre $@" "${@}post""#), ["echo", "pre a b", "c", "a b", "cpost"]);
+        assert_eq!(expand(&mut shell, r#"echo "${@:2}" "${@: -2:1}""#), ["echo", "c", "a b"]);
+        assert_eq!(expand(&mut shell, r#"echo "$(echo "$@")""#), ["echo", "a b c"]);
+    }
+
+    #[test]
+    fn quoted_at_without_parameters_makes_no_field() {
+        let mut shell = shell_with(&[]);
+        assert_eq!(expand(&mut shell, r#"echo "$@" "$@$@""#), ["echo"]);
+        assert_eq!(expand(&mut shell, r#"echo "x$@" """#), ["echo", "x", ""]);
+    }
+
+    #[test]
+    fn quoted_star_joins_with_the_first_character_of_ifs() {
+        let mut shell = shell_with(&["a b", "c"]);
+        assert_eq!(expand(&mut shell, r#"echo "$*""#), ["echo", "a b c"]);
+        shell.env_vars.insert("IFS".to_string(), ":-".to_string());
+        assert_eq!(expand(&mut shell, r#"echo "$*" "${*:1}""#), ["echo", "a b:c", "a b:c"]);
+        shell.env_vars.insert("IFS".to_string(), String::new());
+        assert_eq!(expand(&mut shell, r#"echo "$*""#), ["echo", "a bc"]);
+    }
+
+    #[test]
+    fn unmatched_patterns_follow_nullglob_and_failglob() {
+        let dir = TempDir::new("expand-options", &["a.txt"]);
+        let mut shell = shell_with(&[]);
+        let line = format!("echo {0}/*.txt {0}/*.md", dir.0);
+        let unmatched = format!("{}/*.md", dir.0);
+        let matched = format!("{}/a.txt", dir.0);
+        assert_eq!(expand(&mut shell, &line), ["echo", &matched, &unmatched]);
+        shell.glob_options.nullglob = true;
+        assert_eq!(expand(&mut shell, &line), ["echo", &matched]);
+        shell.glob_options.failglob = true;
+        let list = parser::parse(&line).unwrap();
+        let parser::Command::Simple(simple) = &list.0[0].first.commands[0] else {
+            panic!("expected a simple command");
+        };
+        assert_eq!(shell.expand_words(&simple.words).unwrap_err(), format!("no match: {}", unmatched));
+    }
+
+    #[test]
+    fn quoted_wildcards_stay_literal() {
+        let dir = TempDir::new("expand-quoted", &["a.txt", "b.txt"]);
+        let mut shell = shell_with(&[]);
+        let pattern = format!("{}/*.txt", dir.0);
+        shell.env_vars.insert("p".to_string(), pattern.clone());
+        let line = format!("echo '{0}' \"{0}\" {1}/\\*.txt \"$p\"", pattern, dir.0);
+        assert_eq!(expand(&mut shell, &line), ["echo", &pattern, &pattern, &pattern, &pattern]);
+        let all = [format!("{}/a.txt", dir.0), format!("{}/b.txt", dir.0)];
+        assert_eq!(expand(&mut shell, "echo $p")[1..], all);
+        assert_eq!(expand(&mut shell, &format!("echo {}/\"*\".txt", dir.0))[1..], [pattern]);
+    }
+}
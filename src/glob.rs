@@ -0,0 +1,266 @@
+// Pathname expansion: turns an unquoted word containing `*`, `?` or `[...]`
+// into the sorted list of existing paths it matches.
+
+use std::fs;
+use std::path::Path;
+
+use crate::pattern;
+
+/// The `shopt` settings that change how globbing behaves.
+#[derive(Debug, Clone, Default)]
+pub struct GlobOptions {
+    // A pattern with no matches expands to nothing instead of itself.
+    pub nullglob: bool,
+    // A pattern with no matches is an error and the command doesn't run.
+    pub failglob: bool,
+    // Wildcards match names starting with `.` too.
+    pub dotglob: bool,
+    // `**` as a whole path component matches any number of directories.
+    pub globstar: bool,
+}
+
+impl GlobOptions {
+    pub const NAMES: [&'static str; 4] = ["dotglob", "failglob", "globstar", "nullglob"];
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "dotglob" => Some(&mut self.dotglob),
+            "failglob" => Some(&mut self.failglob),
+            "globstar" => Some(&mut self.globstar),
+            "nullglob" => Some(&mut self.nullglob),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the paths matching `pattern`, sorted. An empty result means
+/// nothing matched; what to do then is up to the caller.
+pub fn glob(pattern: &str, options: &GlobOptions) -> Vec<String> {
+    let (prefix, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => ("/", rest),
+        None => ("", pattern),
+    };
+    let components: Vec<&str> = rest.split('/').collect();
+
+    let mut results = Vec::new();
+    expand_from(prefix, &components, options, &mut results);
+    results.sort();
+    results.dedup();
+    results
+}
+
+/// Matches `components` against the filesystem below `prefix`, which is
+/// either empty (the current directory) or ends in `/`.
+fn expand_from(prefix: &str, components: &[&str], options: &GlobOptions, results: &mut Vec<String>) {
+    let Some((&component, rest)) = components.split_first() else {
+        return;
+    };
+    let last = rest.is_empty();
+
+    // An empty component comes from a trailing or doubled slash. Getting
+    // here means `prefix` is a directory, which is all a trailing slash asks.
+    // A relative pattern starts from an empty prefix, which names no path.
+    if component.is_empty() {
+        if !last {
+            expand_from(prefix, rest, options, results);
+        } else if !prefix.is_empty() {
+            results.push(prefix.to_string());
+        }
+        return;
+    }
+
+    // `**` matches the directory it starts from too, as `dir/`, but the
+    // current directory isn't a match of its own.
+    if options.globstar && component == "**" {
+        if last {
+            if !prefix.is_empty() {
+                results.push(prefix.to_string());
+            }
+            walk(prefix, options, results);
+        } else {
+            let mut directories = vec![prefix.to_string()];
+            collect_directories(prefix, options, &mut directories);
+            for directory in directories {
+                expand_from(&directory, rest, options, results);
+            }
+        }
+        return;
+    }
+
+    if !pattern::has_wildcards(component) {
+        let path = format!("{}{}", prefix, pattern::unescape(component));
+        descend(path, rest, options, results);
+        return;
+    }
+
+    for name in matching_entries(prefix, component, options) {
+        descend(format!("{}{}", prefix, name), rest, options, results);
+    }
+}
+
+fn descend(path: String, rest: &[&str], options: &GlobOptions, results: &mut Vec<String>) {
+    if rest.is_empty() {
+        if fs::symlink_metadata(&path).is_ok() {
+            results.push(path);
+        }
+    } else if Path::new(&path).is_dir() {
+        expand_from(&format!("{}/", path), rest, options, results);
+    }
+}
+
+fn directory_of(prefix: &str) -> &str {
+    if prefix.is_empty() {
+        "."
+    } else {
+        prefix
+    }
+}
+
+/// Names in the directory `prefix` that match `component`. Names starting
+/// with `.` only match a pattern that starts with `.` unless dotglob is set.
+fn matching_entries(prefix: &str, component: &str, options: &GlobOptions) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(directory_of(prefix)) else {
+        return Vec::new();
+    };
+    let explicit_dot = component.starts_with('.');
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| explicit_dot || options.dotglob || !name.starts_with('.'))
+        .filter(|name| pattern::matches(component, name))
+        .collect()
+}
+
+/// Visible entries of a directory, as `(path, is_dir)` pairs. Symlinks to
+/// directories are not descended into, so `**` can't loop.
+fn visible_entries(prefix: &str, options: &GlobOptions) -> Vec<(String, bool)> {
+    let Ok(entries) = fs::read_dir(directory_of(prefix)) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') && !options.dotglob {
+                return None;
+            }
+            let is_dir = entry.file_type().is_ok_and(|kind| kind.is_dir());
+            Some((format!("{}{}", prefix, name), is_dir))
+        })
+        .collect()
+}
+
+/// Every file and directory below `prefix`, for a trailing `**`.
+fn walk(prefix: &str, options: &GlobOptions, results: &mut Vec<String>) {
+    for (path, is_dir) in visible_entries(prefix, options) {
+        if is_dir {
+            walk(&format!("{}/", path), options, results);
+        }
+        results.push(path);
+    }
+}
+
+/// Every directory below `prefix`, each with a trailing `/`.
+fn collect_directories(prefix: &str, options: &GlobOptions, directories: &mut Vec<String>) {
+    for (path, is_dir) in visible_entries(prefix, options) {
+        if is_dir {
+            let directory = format!("{}/", path);
+            collect_directories(&directory, options, directories);
+            directories.push(directory);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+    use std::{env, process};
+
+    // Held by tests that depend on the current directory, so one changing
+    // it doesn't pull it out from under another.
+    static CURRENT_DIR: Mutex<()> = Mutex::new(());
+
+    pub(crate) fn lock_current_dir() -> MutexGuard<'static, ()> {
+        CURRENT_DIR.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // A directory holding the given files, with a trailing `/` for a
+    // directory, removed again when dropped.
+    pub(crate) struct TempDir(pub(crate) String);
+
+    impl TempDir {
+        pub(crate) fn new(name: &str, files: &[&str]) -> Self {
+            let root = format!("{}/rush-glob-{}-{}", env::temp_dir().display(), process::id(), name);
+            fs::remove_dir_all(&root).ok();
+            fs::create_dir_all(&root).unwrap();
+            for file in files {
+                let path = format!("{}/{}", root, file);
+                if file.ends_with('/') {
+                    fs::create_dir_all(&path).unwrap();
+                } else {
+                    fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+                    fs::write(&path, "").unwrap();
+                }
+            }
+            TempDir(root)
+        }
+
+        // The matches for the relative `pattern`, from inside the directory.
+        fn glob(&self, pattern: &str, options: &GlobOptions) -> Vec<String> {
+            let _lock = lock_current_dir();
+            let previous = env::current_dir().unwrap();
+            env::set_current_dir(&self.0).unwrap();
+            let matches = glob(pattern, options);
+            env::set_current_dir(previous).unwrap();
+            matches
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    #[test]
+    fn matches_come_back_sorted() {
+        let dir = TempDir::new("sorted", &["b.txt", "a.txt", "c.rs", "a1.txt"]);
+        let options = GlobOptions::default();
+        assert_eq!(dir.glob("*.txt", &options), ["a.txt", "a1.txt", "b.txt"]);
+        assert_eq!(dir.glob("?.*", &options), ["a.txt", "b.txt", "c.rs"]);
+        assert_eq!(dir.glob("[ab]*", &options), ["a.txt", "a1.txt", "b.txt"]);
+        assert!(dir.glob("*.md", &options).is_empty());
+    }
+
+    #[test]
+    fn dot_files_need_dotglob_or_a_leading_dot() {
+        let dir = TempDir::new("dotglob", &[".hidden", "shown"]);
+        let mut options = GlobOptions::default();
+        assert_eq!(dir.glob("*", &options), ["shown"]);
+        assert_eq!(dir.glob(".h*", &options), [".hidden"]);
+        options.dotglob = true;
+        assert_eq!(dir.glob("*", &options), [".hidden", "shown"]);
+    }
+
+    #[test]
+    fn globstar_matches_any_depth() {
+        let dir = TempDir::new("globstar", &["a.rs", "src/b.rs", "src/deep/c.rs", "src/deep/d.txt", ".git/e.rs"]);
+        let mut options = GlobOptions::default();
+        assert_eq!(dir.glob("**/*.rs", &options), ["src/b.rs"]);
+        options.globstar = true;
+        assert_eq!(dir.glob("**/*.rs", &options), ["a.rs", "src/b.rs", "src/deep/c.rs"]);
+        assert_eq!(dir.glob("src/**", &options), ["src/", "src/b.rs", "src/deep", "src/deep/c.rs", "src/deep/d.txt"]);
+        assert_eq!(dir.glob("**/", &options), ["src/", "src/deep/"]);
+        assert_eq!(dir.glob("src/**/", &options), ["src/", "src/deep/"]);
+        assert_eq!(dir.glob("**", &options), ["a.rs", "src", "src/b.rs", "src/deep", "src/deep/c.rs", "src/deep/d.txt"]);
+    }
+
+    #[test]
+    fn escaped_wildcards_match_themselves() {
+        let dir = TempDir::new("escaped", &["a*b", "axb"]);
+        let options = GlobOptions::default();
+        assert_eq!(dir.glob("a\\*b", &options), ["a*b"]);
+        assert_eq!(dir.glob("a*b", &options), ["a*b", "axb"]);
+    }
+}
@@ -0,0 +1,878 @@
+// Command history: the commands run so far and the file that keeps them
+// between sessions, and history expansion: `!` references to earlier
+// commands, and `^old^new^` to run the last one again with a change. Each
+// line is expanded as it's read, before the lexer sees it, so the expanded
+// line is what runs and what the history remembers.
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::sys;
+
+const HISTORY_FILE_NAME: &str = ".rush_history";
+const MAX_HISTORY: usize = 1000;
+// Starts each entry in the history file, telling it from the plain lines of
+// the old format.
+const HISTORY_MARKER: &str = "#\t";
+
+// The history file is only ever appended to, under a lock, so shells running
+// at once keep each other's entries. Each entry is one line of tab-separated
+// fields, with tabs, newlines and backslashes escaped:
+//
+//     #  started  milliseconds  status  session  cwd  command
+//
+// A line not in that form is an entry from the old format, which kept just
+// the command.
+#[derive(Debug)]
+pub struct History {
+    // The newest `MAX_HISTORY` entries: the end of the history file as it
+    // was when the shell started, then the commands run since. Older ones
+    // stay on disk, read back only by searches that get that far.
+    entries: Vec<String>,
+    position: isize,
+    history_file_path: String,
+    // How many entries the file held when it was read, and where among them
+    // `entries` started.
+    loaded: usize,
+    start: usize,
+    // How many entries have been dropped from the front of `entries` since.
+    dropped: usize,
+    // The pid this shell's commands are saved under, to find them among
+    // those other shells have saved since.
+    session: u32,
+}
+
+/// What the history file keeps about a command besides its text.
+pub struct HistoryRecord {
+    started: SystemTime,
+    duration: Duration,
+    status: i32,
+    cwd: String,
+    // The pid of the shell that ran the command.
+    session: u32,
+}
+
+impl HistoryRecord {
+    /// A record for a command starting now, in the current directory.
+    pub fn start(session: u32) -> Self {
+        HistoryRecord {
+            started: SystemTime::now(),
+            duration: Duration::ZERO,
+            status: 0,
+            cwd: env::current_dir().map(|dir| dir.to_string_lossy().into_owned()).unwrap_or_default(),
+            session,
+        }
+    }
+
+    /// Fills in how the command went, now that it's done.
+    pub fn finish(&mut self, status: i32) {
+        self.duration = self.started.elapsed().unwrap_or_default();
+        self.status = status;
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        let home_dir = env::var("HOME").unwrap_or_else(|_| ".".to_string());
+        let history_file_path = format!("{}/{}", home_dir, HISTORY_FILE_NAME);
+        
+        let mut history = History {
+            entries: Vec::new(),
+            position: -1,
+            history_file_path,
+            loaded: 0,
+            start: 0,
+            dropped: 0,
+            session: process::id(),
+        };
+        history.load_from_file();
+        history
+    }
+
+    fn load_from_file(&mut self) {
+        let lines = self.read_file();
+        self.loaded = lines.len();
+        self.start = lines.len().saturating_sub(MAX_HISTORY);
+        self.entries = lines.into_iter().skip(self.start).map(|(_, command)| command).collect();
+        self.position = self.entries.len() as isize;
+    }
+
+    /// Every entry in the history file, including those other shells have
+    /// added since this one started, with the session that saved it if the
+    /// line is in the current format.
+    fn read_file(&self) -> Vec<(Option<u32>, String)> {
+        let Ok(mut file) = File::open(&self.history_file_path) else {
+            return Vec::new();
+        };
+        let mut contents = String::new();
+        if sys::lock_file(file.as_raw_fd(), false).is_err() || file.read_to_string(&mut contents).is_err() {
+            return Vec::new();
+        }
+        contents
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| match parse_history_line(line) {
+                Some((session, command)) => (Some(session), command),
+                None => (None, line.to_string()),
+            })
+            .collect()
+    }
+
+    /// Adds a command to the in-memory history, unless it's blank or the
+    /// same as the last one. Returns whether it was added, and so should be
+    /// saved once it has run.
+    pub fn add(&mut self, command: String) -> bool {
+        if command.trim().is_empty() || self.entries.last() == Some(&command) {
+            return false;
+        }
+        self.entries.push(command);
+        if self.entries.len() > MAX_HISTORY {
+            self.entries.remove(0);
+            self.dropped += 1;
+        }
+        self.position = self.entries.len() as isize;
+        true
+    }
+
+    /// Appends a command that has run to the history file.
+    pub fn save(&self, command: &str, record: &HistoryRecord) {
+        let started = record.started.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let line = format!(
+            "{}{}\t{}\t{}\t{}\t{}\t{}\n",
+            HISTORY_MARKER,
+            started.as_secs(),
+            record.duration.as_millis(),
+            record.status,
+            record.session,
+            escape_history_field(&record.cwd),
+            escape_history_field(command)
+        );
+        let file = OpenOptions::new().append(true).create(true).open(&self.history_file_path);
+        let result = file.and_then(|mut file| {
+            sys::lock_file(file.as_raw_fd(), true)?;
+            file.write_all(line.as_bytes())
+        });
+        if let Err(e) = result {
+            eprintln!("{}: {}", self.history_file_path, e);
+        }
+    }
+
+    pub fn get_previous(&mut self) -> Option<&String> {
+        if self.position > 0 && !self.entries.is_empty() {
+            self.position -= 1;
+            self.entries.get(self.position as usize)
+        } else {
+            self.entries.first()
+        }
+    }
+
+    pub fn get_next(&mut self) -> Option<&String> {
+        if (self.position + 1) < self.entries.len() as isize {
+            self.position += 1;
+            self.entries.get(self.position as usize)
+        } else {
+            self.position = self.entries.len() as isize;
+            None
+        }
+    }
+
+    pub fn reset_position(&mut self) {
+        self.position = self.entries.len() as isize;
+    }
+
+    /// The number of entries, counting those only on disk.
+    pub fn len(&self) -> usize {
+        self.archived() + self.entries.len()
+    }
+
+    /// How many entries in the history file are older than `entries`. The
+    /// file is only appended to, so while the entries dropped from memory
+    /// were all read at startup this is known without reading it again.
+    fn archived(&self) -> usize {
+        if self.dropped <= self.loaded - self.start {
+            self.start + self.dropped
+        } else {
+            self.archive_end(&self.read_file())
+        }
+    }
+
+    /// Where the entries older than `entries` end in the history file's
+    /// `lines`. Once this shell's own commands have been dropped too, that's
+    /// just after the last of them, wherever other shells' entries have put
+    /// it.
+    fn archive_end(&self, lines: &[(Option<u32>, String)]) -> usize {
+        let own_dropped = self.dropped.saturating_sub(self.loaded - self.start);
+        if own_dropped == 0 {
+            return self.start + self.dropped;
+        }
+        lines
+            .iter()
+            .enumerate()
+            .skip(self.loaded)
+            .filter(|(_, (session, _))| *session == Some(self.session))
+            .nth(own_dropped - 1)
+            .map_or(lines.len(), |(i, _)| i + 1)
+    }
+
+    /// The entries older than the in-memory window, from the file.
+    fn read_archive(&self) -> Vec<String> {
+        let mut lines = self.read_file();
+        lines.truncate(self.archive_end(&lines));
+        lines.into_iter().map(|(_, command)| command).collect()
+    }
+
+    /// The entry numbered `index`, counting from 0 for the oldest.
+    fn entry(&self, index: usize) -> Option<String> {
+        match index.checked_sub(self.archived()) {
+            Some(i) => self.entries.get(i).cloned(),
+            None => self.read_archive().into_iter().nth(index),
+        }
+    }
+
+    /// The newest entry starting with `prefix`.
+    fn latest_starting_with(&self, prefix: &str) -> Option<String> {
+        if let Some(entry) = self.entries.iter().rev().find(|entry| entry.starts_with(prefix)) {
+            return Some(entry.clone());
+        }
+        self.read_archive().into_iter().rev().find(|entry| entry.starts_with(prefix))
+    }
+
+    /// Finds the entry nearest to `from` that contains `text`, looking at
+    /// `from` itself and then older entries, or newer ones if not
+    /// `backward`. Entries are numbered from the oldest, on disk or not.
+    pub fn search(&self, text: &str, from: usize, backward: bool) -> Option<(usize, String)> {
+        let archived = self.archived();
+        let in_window = from.saturating_sub(archived).min(self.entries.len());
+        if backward {
+            let end = if from < archived { 0 } else { (in_window + 1).min(self.entries.len()) };
+            if let Some(i) = self.entries[..end].iter().rposition(|entry| entry.contains(text)) {
+                return Some((archived + i, self.entries[i].clone()));
+            }
+            let mut archive = self.read_archive();
+            archive.truncate(from.saturating_add(1));
+            let i = archive.iter().rposition(|entry| entry.contains(text))?;
+            Some((i, archive.swap_remove(i)))
+        } else {
+            if from < archived {
+                let archive = self.read_archive();
+                if let Some(i) = archive.iter().skip(from).position(|entry| entry.contains(text)) {
+                    return Some((from + i, archive[from + i].clone()));
+                }
+            }
+            let i = self.entries[in_window..].iter().position(|entry| entry.contains(text))?;
+            Some((archived + in_window + i, self.entries[in_window + i].clone()))
+        }
+    }
+}
+
+/// The session and command in a line of the history file, if it's in the
+/// current format.
+fn parse_history_line(line: &str) -> Option<(u32, String)> {
+    let fields: Vec<&str> = line.strip_prefix(HISTORY_MARKER)?.splitn(6, '\t').collect();
+    let [started, duration, status, session, _cwd, command] = fields.as_slice() else {
+        return None;
+    };
+    started.parse::<u64>().ok()?;
+    duration.parse::<u128>().ok()?;
+    status.parse::<i32>().ok()?;
+    let session = session.parse::<u32>().ok()?;
+    Some((session, unescape_history_field(command)))
+}
+
+fn escape_history_field(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape_history_field(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+// Characters that end the text of a `!string` reference.
+const STRING_ENDS: &str = " \t\n:;&|()<>'\"";
+
+// Characters making up the operators that count as words of their own.
+const OPERATOR_CHARS: &str = ";&|()<>";
+
+/// A line after history expansion.
+pub struct Expanded {
+    pub line: String,
+    /// Set by the `:p` modifier: the line is to be shown, not run.
+    pub print_only: bool,
+}
+
+/// Expands the history references in `line`, returning `None` if it has
+/// none. A reference that can't be expanded fails the whole line, with the
+/// message to show for it.
+pub fn expand(line: &str, history: &History) -> Result<Option<Expanded>, String> {
+    let mut expander = Expander {
+        chars: line.chars().collect(),
+        position: 0,
+        history,
+        output: String::new(),
+        print_only: false,
+    };
+    let changed = expander.expand()?;
+    Ok(changed.then_some(Expanded {
+        line: expander.output,
+        print_only: expander.print_only,
+    }))
+}
+
+struct Expander<'a> {
+    chars: Vec<char>,
+    position: usize,
+    history: &'a History,
+    output: String,
+    print_only: bool,
+}
+
+impl Expander<'_> {
+    fn expand(&mut self) -> Result<bool, String> {
+        let mut changed = false;
+        if self.peek() == Some('^') {
+            self.position += 1;
+            let (old, new) = self.substitution('^');
+            let event = self.back(1).ok_or_else(|| self.error(0, "event not found"))?;
+            let text = substitute(&event, &old, &new, false).ok_or_else(|| self.error(0, "substitution failed"))?;
+            self.output.push_str(&text);
+            changed = true;
+        }
+
+        let mut single_quoted = false;
+        let mut double_quoted = false;
+        while let Some(c) = self.next() {
+            match c {
+                // A backslash keeps the next character from starting a
+                // reference; both stay for the lexer to deal with.
+                '\\' if !single_quoted => {
+                    self.output.push(c);
+                    if let Some(next) = self.next() {
+                        self.output.push(next);
+                    }
+                    continue;
+                }
+                '\'' if !double_quoted => single_quoted = !single_quoted,
+                '"' if !single_quoted => double_quoted = !double_quoted,
+                '!' if !single_quoted && self.starts_reference(double_quoted) => {
+                    let text = self.reference()?;
+                    self.output.push_str(&text);
+                    changed = true;
+                    continue;
+                }
+                _ => {}
+            }
+            self.output.push(c);
+        }
+        Ok(changed)
+    }
+
+    /// Whether the `!` just read starts a reference. Followed by a blank,
+    /// `=` or `(`, or by the quote closing a double-quoted string, it
+    /// stands for itself, as it does in `$!`, `${!name}` and `[!...]`.
+    fn starts_reference(&self, double_quoted: bool) -> bool {
+        let before = |back: usize| self.position.checked_sub(back + 1).map(|i| self.chars[i]);
+        if matches!(before(1), Some('$' | '[')) || (before(1) == Some('{') && before(2) == Some('$')) {
+            return false;
+        }
+        match self.peek() {
+            None | Some('=') => false,
+            Some('"') if double_quoted => false,
+            Some(c) if "!#?$^*:-".contains(c) || c.is_ascii_digit() => true,
+            Some(c) => !STRING_ENDS.contains(c),
+        }
+    }
+
+    /// Expands the reference after a `!`: an event, which words of it to
+    /// use, and modifiers to apply.
+    fn reference(&mut self) -> Result<String, String> {
+        let start = self.position - 1;
+        let event = match self.peek() {
+            Some('!') => {
+                self.position += 1;
+                self.back(1)
+            }
+            // The line so far.
+            Some('#') => {
+                self.position += 1;
+                Some(self.output.clone())
+            }
+            Some('-') if self.peek_at(1).is_some_and(|c| c.is_ascii_digit()) => {
+                self.position += 1;
+                let count = self.number();
+                self.back(count)
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let number = self.number();
+                number.checked_sub(1).and_then(|index| self.history.entry(index))
+            }
+            Some('?') => {
+                self.position += 1;
+                let text = self.take_while(|c| c != '?');
+                self.position += 1;
+                let newest = self.history.len().checked_sub(1);
+                let found = newest.and_then(|newest| self.history.search(&text, newest, true));
+                found.filter(|_| !text.is_empty()).map(|(_, entry)| entry)
+            }
+            // Words of the last command, like `!$`.
+            Some('$' | '^' | '*' | ':') => self.back(1),
+            _ => {
+                let prefix = self.take_while(|c| !STRING_ENDS.contains(c));
+                self.history.latest_starting_with(&prefix)
+            }
+        };
+        let event = event.ok_or_else(|| self.error(start, "event not found"))?;
+
+        let mut text = self.select_words(event, start)?;
+        while self.peek() == Some(':') && self.peek_at(1).is_some_and(|c| "ehpqrstg".contains(c)) {
+            self.position += 1;
+            text = self.modify(text, start)?;
+        }
+        Ok(text)
+    }
+
+    /// Picks out the words of `event` that a designator asks for, if one
+    /// follows: after a `:`, or straight after the event when it starts
+    /// with `^`, `$` or `*`. Words are counted from 0, the command name.
+    fn select_words(&mut self, event: String, start: usize) -> Result<String, String> {
+        let follows_colon = self.peek() == Some(':')
+            && self.peek_at(1).is_some_and(|c| c.is_ascii_digit() || "^$*-".contains(c));
+        if follows_colon {
+            self.position += 1;
+        } else if !matches!(self.peek(), Some('^' | '$' | '*')) {
+            return Ok(event);
+        }
+
+        let words = split_words(&event);
+        let last = words.len().saturating_sub(1);
+        let first = match self.peek() {
+            // Every argument, which may be none.
+            Some('*') => {
+                self.position += 1;
+                return Ok(words.get(1..).unwrap_or_default().join(" "));
+            }
+            Some('-') => 0,
+            _ => self.word_index(last).ok_or_else(|| self.error(start, "bad word specifier"))?,
+        };
+        let end = match self.peek() {
+            Some('*') => {
+                self.position += 1;
+                if first > last {
+                    return Ok(String::new());
+                }
+                last
+            }
+            Some('-') => {
+                self.position += 1;
+                match self.word_index(last) {
+                    Some(end) => end,
+                    // `x-` stops short of the last word.
+                    None => last.checked_sub(1).ok_or_else(|| self.error(start, "bad word specifier"))?,
+                }
+            }
+            _ => first,
+        };
+        if first > end || end > last || words.is_empty() {
+            return Err(self.error(start, "bad word specifier"));
+        }
+        Ok(words[first..=end].join(" "))
+    }
+
+    /// Reads a word number, or `^` for the first argument or `$` for the
+    /// last word.
+    fn word_index(&mut self, last: usize) -> Option<usize> {
+        match self.peek()? {
+            '^' => {
+                self.position += 1;
+                Some(1)
+            }
+            '$' => {
+                self.position += 1;
+                Some(last)
+            }
+            c if c.is_ascii_digit() => Some(self.number()),
+            _ => None,
+        }
+    }
+
+    /// Applies the modifier after a `:`.
+    fn modify(&mut self, text: String, start: usize) -> Result<String, String> {
+        let modifier = self.next().unwrap_or_default();
+        let modified = match modifier {
+            // The head or tail of a path.
+            'h' => match text.rfind('/') {
+                Some(slash) => text[..slash].to_string(),
+                None => text,
+            },
+            't' => match text.rfind('/') {
+                Some(slash) => text[slash + 1..].to_string(),
+                None => text,
+            },
+            // The path without its suffix, or only the suffix.
+            'r' => match suffix_start(&text) {
+                Some(dot) => text[..dot].to_string(),
+                None => text,
+            },
+            'e' => match suffix_start(&text) {
+                Some(dot) => text[dot..].to_string(),
+                None => String::new(),
+            },
+            'p' => {
+                self.print_only = true;
+                text
+            }
+            'q' => format!("'{}'", text.replace('\'', "'\\''")),
+            's' | 'g' => {
+                let global = modifier == 'g';
+                if global && self.next() != Some('s') {
+                    return Err(self.error(start, "unrecognized history modifier"));
+                }
+                let Some(delimiter) = self.next() else {
+                    return Err(self.error(start, "bad substitution"));
+                };
+                let (old, new) = self.substitution(delimiter);
+                if old.is_empty() {
+                    return Err(self.error(start, "no previous substitution"));
+                }
+                substitute(&text, &old, &new, global).ok_or_else(|| self.error(start, "substitution failed"))?
+            }
+            _ => return Err(self.error(start, "unrecognized history modifier")),
+        };
+        Ok(modified)
+    }
+
+    /// Reads the `old` and `new` parts of a substitution, each ended by
+    /// `delimiter`, though the last one may instead run to the end of the
+    /// line. A backslash makes the delimiter part of the text.
+    fn substitution(&mut self, delimiter: char) -> (String, String) {
+        let mut parts = [String::new(), String::new()];
+        for part in &mut parts {
+            while let Some(c) = self.next() {
+                match c {
+                    '\\' if self.peek() == Some(delimiter) => part.extend(self.next()),
+                    _ if c == delimiter => break,
+                    _ => part.push(c),
+                }
+            }
+        }
+        let [old, new] = parts;
+        (old, new)
+    }
+
+    /// The entry `count` commands back.
+    fn back(&self, count: usize) -> Option<String> {
+        self.history.len().checked_sub(count).and_then(|index| self.history.entry(index))
+    }
+
+    /// An error message naming the reference from `start` on.
+    fn error(&self, start: usize, message: &str) -> String {
+        let reference: String = self.chars[start..self.position.min(self.chars.len())].iter().collect();
+        format!("{}: {}", reference, message)
+    }
+
+    fn number(&mut self) -> usize {
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        digits.parse().unwrap_or(usize::MAX)
+    }
+
+    fn take_while(&mut self, keep: impl Fn(char) -> bool) -> String {
+        let mut text = String::new();
+        while let Some(c) = self.peek().filter(|c| keep(*c)) {
+            text.push(c);
+            self.position += 1;
+        }
+        text
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.position + offset).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += 1;
+        Some(c)
+    }
+}
+
+/// `text` with `old` replaced by `new`, in which `&` stands for `old`. Only
+/// the first `old` is replaced unless `global`. `None` if there is none.
+fn substitute(text: &str, old: &str, new: &str, global: bool) -> Option<String> {
+    if !text.contains(old) {
+        return None;
+    }
+    let mut replacement = String::new();
+    let mut chars = new.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => replacement.extend(chars.next()),
+            '&' => replacement.push_str(old),
+            _ => replacement.push(c),
+        }
+    }
+    Some(if global {
+        text.replace(old, &replacement)
+    } else {
+        text.replacen(old, &replacement, 1)
+    })
+}
+
+/// Where the suffix of the path's last component starts, at its last `.`.
+fn suffix_start(path: &str) -> Option<usize> {
+    let dot = path.rfind('.')?;
+    (!path[dot..].contains('/')).then_some(dot)
+}
+
+/// Splits a command into words the way designators count them: at blanks
+/// outside quotes, with each run of operator characters a word of its own.
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut operator = false;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in line.chars() {
+        if escaped {
+            word.push(c);
+            escaped = false;
+            continue;
+        }
+        match (quote, c) {
+            (Some('"'), '\\') | (None, '\\') => escaped = true,
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, ' ' | '\t' | '\n') => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                continue;
+            }
+            _ => {}
+        }
+        let is_operator = quote.is_none() && OPERATOR_CHARS.contains(c);
+        if !word.is_empty() && is_operator != operator {
+            words.push(std::mem::take(&mut word));
+        }
+        operator = is_operator;
+        word.push(c);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // A history file for a test, removed again when dropped.
+    struct HistoryFile(String);
+
+    impl HistoryFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = format!("{}/rush-history-{}-{}", env::temp_dir().display(), process::id(), name);
+            fs::write(&path, contents).unwrap();
+            HistoryFile(path)
+        }
+
+        // The history a shell saving under `session` would start with.
+        fn load(&self, session: u32) -> History {
+            let mut history = History {
+                entries: Vec::new(),
+                position: -1,
+                history_file_path: self.0.clone(),
+                loaded: 0,
+                start: 0,
+                dropped: 0,
+                session,
+            };
+            history.load_from_file();
+            history
+        }
+    }
+
+    impl Drop for HistoryFile {
+        fn drop(&mut self) {
+            fs::remove_file(&self.0).ok();
+        }
+    }
+
+    fn run(history: &mut History, command: &str, session: u32) {
+        assert!(history.add(command.to_string()));
+        history.save(command, &HistoryRecord::start(session));
+    }
+
+    #[test]
+    fn history_lines_give_their_session_and_command() {
+        assert_eq!(
+            parse_history_line("#\t1700000000\t12\t0\t4242\t/tmp\techo a\\tb"),
+            Some((4242, "echo a\tb".to_string()))
+        );
+        assert_eq!(parse_history_line("#\t1700000000\t12\t1\t7\t/a\\tb\tls\tx"), Some((7, "ls\tx".to_string())));
+        assert_eq!(parse_history_line("#\t1700000000\t12\t0\tsession\t/tmp\tls"), None);
+        assert_eq!(parse_history_line("#\t1700000000\t12\t0\t4242"), None);
+        assert_eq!(parse_history_line("# a comment"), None);
+        assert_eq!(parse_history_line("ls -l"), None);
+    }
+
+    #[test]
+    fn escaped_history_fields_come_back_unchanged() {
+        for text in [
+            "for f in *; do\n\techo \"$f\"\ndone",
+            "printf 'a\\tb\\n'",
+            "echo \\\\n\\",
+            "\t\\t\n\\n",
+            "",
+        ] {
+            let escaped = escape_history_field(text);
+            assert!(!escaped.contains(['\t', '\n']), "{:?}", escaped);
+            assert_eq!(unescape_history_field(&escaped), text);
+        }
+    }
+
+    #[test]
+    fn plain_lines_are_read_as_old_entries() {
+        let file = HistoryFile::new(
+            "old",
+            "ls -l\n\n# not an entry\n#\t1700000000\t5\t0\t9\t/\techo 'a\\nb'\ncd /tmp\n",
+        );
+        let history = file.load(1);
+        assert_eq!(history.entries, ["ls -l", "# not an entry", "echo 'a\nb'", "cd /tmp"]);
+        assert_eq!(
+            history.read_file().into_iter().map(|(session, _)| session).collect::<Vec<_>>(),
+            [None, None, Some(9), None]
+        );
+    }
+
+    #[test]
+    fn archive_ends_where_this_shell_dropped_its_own_entries() {
+        let old: String = (0..MAX_HISTORY).map(|i| format!("old{}\n", i)).collect();
+        let file = HistoryFile::new("archive", &old);
+        let mut history = file.load(1);
+        let mut other = file.load(2);
+        assert_eq!(history.len(), MAX_HISTORY);
+
+        run(&mut history, "mine0", 1);
+        assert_eq!(history.read_archive(), ["old0"]);
+        run(&mut other, "theirs", 2);
+        for i in 1..MAX_HISTORY {
+            run(&mut history, &format!("mine{}", i), 1);
+        }
+        assert_eq!(history.read_archive().len(), MAX_HISTORY);
+        run(&mut history, "mine1000", 1);
+        run(&mut history, "mine1001", 1);
+
+        // The two dropped commands of this shell, and the other shell's
+        // command saved between them.
+        let archive = history.read_archive();
+        assert_eq!(archive.len(), MAX_HISTORY + 3);
+        assert_eq!(archive[MAX_HISTORY..], ["mine0", "theirs", "mine1"]);
+        assert_eq!(history.len(), 2 * MAX_HISTORY + 3);
+        assert_eq!(history.entry(MAX_HISTORY + 2).as_deref(), Some("mine1"));
+        assert_eq!(history.entry(MAX_HISTORY + 3).as_deref(), Some("mine2"));
+        let newest = history.len() - 1;
+        assert_eq!(history.search("mine1", newest, true), Some((newest, "mine1001".to_string())));
+        assert_eq!(history.search("theirs", newest, true), Some((MAX_HISTORY + 1, "theirs".to_string())));
+        assert_eq!(history.search("mine0", 0, false), Some((MAX_HISTORY, "mine0".to_string())));
+    }
+
+    fn history(entries: &[&str]) -> History {
+        History {
+            entries: entries.iter().map(|entry| entry.to_string()).collect(),
+            position: entries.len() as isize,
+            history_file_path: String::new(),
+            loaded: 0,
+            start: 0,
+            dropped: 0,
+            session: 0,
+        }
+    }
+
+    fn expanded(line: &str, entries: &[&str]) -> String {
+        expand(line, &history(entries)).unwrap().expect("the line has a reference").line
+    }
+
+    #[test]
+    fn events() {
+        let entries = ["echo one two", "ls -l /tmp/notes.txt", "cp a b c"];
+        assert_eq!(expanded("!!", &entries), "cp a b c");
+        assert_eq!(expanded("sudo !!", &entries), "sudo cp a b c");
+        assert_eq!(expanded("!-2", &entries), "ls -l /tmp/notes.txt");
+        assert_eq!(expanded("!1", &entries), "echo one two");
+        assert_eq!(expanded("!ec", &entries), "echo one two");
+        assert_eq!(expanded("!?notes?", &entries), "ls -l /tmp/notes.txt");
+    }
+
+    #[test]
+    fn word_designators() {
+        let entries = ["ls -l /tmp/notes.txt", "cp a b c"];
+        assert_eq!(expanded("echo !$", &entries), "echo c");
+        assert_eq!(expanded("echo !^", &entries), "echo a");
+        assert_eq!(expanded("echo !*", &entries), "echo a b c");
+        assert_eq!(expanded("echo !!:1-", &entries), "echo a b");
+        assert_eq!(expanded("echo !!:0", &entries), "echo cp");
+        assert_eq!(expanded("echo !-2:2", &entries), "echo /tmp/notes.txt");
+    }
+
+    #[test]
+    fn modifiers() {
+        let entries = ["ls -l /tmp/notes.txt", "echo aaa bab"];
+        assert_eq!(expanded("!!:gs/a/x/", &entries), "echo xxx bxb");
+        assert_eq!(expanded("!!:s/a/x/", &entries), "echo xaa bab");
+        assert_eq!(expanded("echo !-2:$:h", &entries), "echo /tmp");
+        assert_eq!(expanded("echo !-2:$:t:r", &entries), "echo notes");
+        assert_eq!(expanded("echo !-2:$:e", &entries), "echo .txt");
+        assert!(expand("!!:p", &history(&entries)).unwrap().unwrap().print_only);
+    }
+
+    #[test]
+    fn quick_substitution() {
+        assert_eq!(expanded("^x^y^", &["echo x x"]), "echo y x");
+        assert_eq!(expanded("^x^y", &["echo x x"]), "echo y x");
+        assert_eq!(expanded("^x^[&]^", &["echo x x"]), "echo [x] x");
+    }
+
+    #[test]
+    fn literal_exclamation_marks() {
+        let history = history(&["echo x"]);
+        for line in ["echo '!x'", "echo \\!x", "echo $!", "echo ${!x}", "ls [!a]*", "echo hi!", "a != b", "echo \"hi!\""] {
+            assert!(expand(line, &history).unwrap().is_none(), "{} was expanded", line);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let entries = ["cp a b c"];
+        let error = |line: &str| expand(line, &history(&entries)).err();
+        assert_eq!(error("!nosuch").as_deref(), Some("!nosuch: event not found"));
+        assert_eq!(error("!-5").as_deref(), Some("!-5: event not found"));
+        assert_eq!(error("!!:9").as_deref(), Some("!!:9: bad word specifier"));
+        assert_eq!(error("^x^y^").as_deref(), Some("^x^y^: substitution failed"));
+        assert_eq!(error("!!:s/x/y/").as_deref(), Some("!!:s/x/y/: substitution failed"));
+        assert_eq!(expand("!!", &history(&[])).err().as_deref(), Some("!!: event not found"));
+    }
+}
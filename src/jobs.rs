@@ -0,0 +1,317 @@
+// The job table: background jobs and stopped foreground ones. Every job
+// runs in a process group of its own, and is known to the user by a small
+// number, a `%` job spec and the command text that started it.
+
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+use crate::{exit_code, sys};
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: usize,
+    pub pgid: i32,
+    // Each process in the job, with its status once it has been reaped.
+    pub processes: Vec<(i32, Option<ExitStatus>)>,
+    pub stopped: bool,
+    pub command: String,
+    // Set when the job's state changes, until the change has been reported.
+    changed: bool,
+}
+
+impl Job {
+    pub fn is_done(&self) -> bool {
+        self.processes.iter().all(|(_, status)| status.is_some())
+    }
+
+    /// The job's status, which is its last process's.
+    pub fn status(&self) -> i32 {
+        match self.processes.last() {
+            Some((_, Some(status))) => exit_code(*status),
+            _ => 0,
+        }
+    }
+
+    /// The state as `jobs` shows it: running, stopped, or how it ended.
+    fn state(&self) -> String {
+        if !self.is_done() {
+            let state = if self.stopped { "Stopped" } else { "Running" };
+            return state.to_string();
+        }
+        let Some((_, Some(status))) = self.processes.last() else {
+            return "Done".to_string();
+        };
+        match (status.code(), status.signal()) {
+            (Some(0), _) => "Done".to_string(),
+            (Some(code), _) => format!("Exit {}", code),
+            (_, Some(signal)) => signal_name(signal),
+            _ => "Done".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Jobs {
+    // Ordered by when each job last became the current one, so the last
+    // is `%+` and the one before it `%-`.
+    jobs: Vec<Job>,
+    // Processes of disowned jobs, still to be reaped.
+    disowned: Vec<i32>,
+}
+
+impl Jobs {
+    /// Adds a job and makes it current. Returns its number, one more than
+    /// the highest in use.
+    pub fn add(
+        &mut self,
+        pgid: i32,
+        processes: Vec<(i32, Option<ExitStatus>)>,
+        command: String,
+        stopped: bool,
+    ) -> usize {
+        let id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        self.jobs.push(Job {
+            id,
+            pgid,
+            processes,
+            stopped,
+            command,
+            changed: false,
+        });
+        id
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Job> {
+        let index = self.jobs.iter().position(|job| job.id == id)?;
+        Some(self.jobs.remove(index))
+    }
+
+    /// Forgets a job without waiting for it. Its processes are still reaped
+    /// quietly once they exit.
+    pub fn disown(&mut self, id: usize) {
+        if let Some(job) = self.remove(id) {
+            let running = job.processes.iter().filter(|(_, status)| status.is_none());
+            self.disowned.extend(running.map(|(pid, _)| *pid));
+        }
+    }
+
+    pub fn make_current(&mut self, id: usize) {
+        if let Some(job) = self.remove(id) {
+            self.jobs.push(job);
+        }
+    }
+
+    /// The numbers of all jobs, in order.
+    pub fn ids(&self) -> Vec<usize> {
+        let mut ids: Vec<usize> = self.jobs.iter().map(|job| job.id).collect();
+        ids.sort();
+        ids
+    }
+
+    /// Finds the job a process belongs to.
+    pub fn find_pid(&self, pid: i32) -> Option<usize> {
+        let job = self.jobs.iter().find(|job| job.processes.iter().any(|(other, _)| *other == pid))?;
+        Some(job.id)
+    }
+
+    /// Finds the job a spec refers to: `%n` by number, `%+` (or `%%`, or no
+    /// spec at all) for the current job, `%-` for the previous one,
+    /// `%name` by how its command starts and `%?text` by what it contains.
+    pub fn find(&self, spec: Option<&str>) -> Result<usize, String> {
+        let spec = spec.unwrap_or("%+");
+        let Some(body) = spec.strip_prefix('%') else {
+            return Err(format!("{}: no such job", spec));
+        };
+        let found = match body {
+            "" | "+" | "%" => {
+                return self.jobs.last().map(|job| job.id).ok_or_else(|| "current: no such job".to_string());
+            }
+            "-" => {
+                let previous = self.jobs.len().checked_sub(2).map_or(self.jobs.last(), |i| self.jobs.get(i));
+                return previous.map(|job| job.id).ok_or_else(|| "previous: no such job".to_string());
+            }
+            _ if body.chars().all(|c| c.is_ascii_digit()) => {
+                self.jobs.iter().filter(|job| job.id.to_string() == body).collect::<Vec<_>>()
+            }
+            _ => match body.strip_prefix('?') {
+                Some(text) => self.jobs.iter().filter(|job| job.command.contains(text)).collect(),
+                None => self.jobs.iter().filter(|job| job.command.starts_with(body)).collect(),
+            },
+        };
+        match found.as_slice() {
+            [job] => Ok(job.id),
+            [] => Err(format!("{}: no such job", spec)),
+            _ => Err(format!("{}: ambiguous job spec", spec)),
+        }
+    }
+
+    /// Reaps whatever has exited or stopped since the last look, without
+    /// blocking.
+    pub fn update(&mut self) {
+        for job in &mut self.jobs {
+            let before = job.state();
+            for (pid, status) in &mut job.processes {
+                if status.is_some() {
+                    continue;
+                }
+                match sys::wait_pid(*pid, sys::WNOHANG | sys::WUNTRACED) {
+                    Ok(Some(reaped)) if reaped.stopped_signal().is_some() => job.stopped = true,
+                    Ok(Some(reaped)) => *status = Some(reaped),
+                    // In a forked copy of the shell, like a pipeline stage
+                    // running `jobs`, the job isn't ours to reap; what was
+                    // last known about it stands.
+                    Ok(None) | Err(_) => {}
+                }
+            }
+            if job.state() != before {
+                job.changed = true;
+            }
+        }
+        self.disowned.retain(|pid| matches!(sys::wait_pid(*pid, sys::WNOHANG), Ok(None)));
+    }
+
+    /// Blocks until every process in the job has exited or one of them
+    /// stops. A Ctrl-C while waiting ends it with an `Interrupted` error.
+    pub fn wait(&mut self, id: usize) -> io::Result<()> {
+        let Some(job) = self.get_mut(id) else {
+            return Ok(());
+        };
+        for (pid, status) in &mut job.processes {
+            if status.is_some() {
+                continue;
+            }
+            match sys::wait_pid_interruptibly(*pid, sys::WUNTRACED)? {
+                Some(reaped) if reaped.stopped_signal().is_some() => {
+                    job.stopped = true;
+                    return Ok(());
+                }
+                reaped => *status = reaped,
+            }
+        }
+        Ok(())
+    }
+
+    /// A job's line in `jobs`, with its pid too if `long`.
+    pub fn describe(&self, id: usize, long: bool) -> String {
+        let Some(job) = self.get(id) else {
+            return String::new();
+        };
+        let mark = match self.jobs.iter().rev().position(|other| other.id == id) {
+            Some(0) => '+',
+            Some(1) => '-',
+            _ => ' ',
+        };
+        let state = job.state();
+        let background = if state == "Running" { " &" } else { "" };
+        if long {
+            format!("[{}]{} {} {:<24}{}{}", id, mark, job.pgid, state, job.command, background)
+        } else {
+            format!("[{}]{}  {:<24}{}{}", id, mark, state, job.command, background)
+        }
+    }
+
+    /// Marks a job's state as seen by the user, dropping it from the table
+    /// if it has finished.
+    pub fn reported(&mut self, id: usize) {
+        match self.get_mut(id) {
+            Some(job) if job.is_done() => {
+                self.remove(id);
+            }
+            Some(job) => job.changed = false,
+            None => {}
+        }
+    }
+
+    /// Lines reporting every job that has finished or stopped since it was
+    /// last reported, to show before the next prompt.
+    pub fn notices(&mut self) -> Vec<String> {
+        self.update();
+        let mut notices = Vec::new();
+        for id in self.ids() {
+            if self.get(id).is_some_and(|job| job.changed) {
+                notices.push(self.describe(id, false));
+                self.reported(id);
+            }
+        }
+        notices
+    }
+}
+
+/// How a process killed by `signal` is described.
+pub fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        1 => "Hangup",
+        2 => "Interrupt",
+        3 => "Quit",
+        4 => "Illegal instruction",
+        6 => "Aborted",
+        8 => "Floating point exception",
+        9 => "Killed",
+        11 => "Segmentation fault",
+        13 => "Broken pipe",
+        14 => "Alarm clock",
+        15 => "Terminated",
+        _ => return format!("Signal {}", signal),
+    };
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jobs(commands: &[&str]) -> Jobs {
+        let mut jobs = Jobs::default();
+        for (i, command) in commands.iter().enumerate() {
+            let pid = 100 + i as i32;
+            jobs.add(pid, vec![(pid, None)], command.to_string(), false);
+        }
+        jobs
+    }
+
+    #[test]
+    fn specs_name_jobs_by_number_or_recency() {
+        let mut jobs = jobs(&["sleep 10", "make all", "vim notes.txt"]);
+        assert_eq!(jobs.find(Some("%1")), Ok(1));
+        assert_eq!(jobs.find(Some("%+")), Ok(3));
+        assert_eq!(jobs.find(Some("%%")), Ok(3));
+        assert_eq!(jobs.find(Some("%")), Ok(3));
+        assert_eq!(jobs.find(None), Ok(3));
+        assert_eq!(jobs.find(Some("%-")), Ok(2));
+        jobs.make_current(1);
+        assert_eq!(jobs.find(Some("%+")), Ok(1));
+        assert_eq!(jobs.find(Some("%-")), Ok(3));
+        assert_eq!(jobs.find(Some("%4")), Err("%4: no such job".to_string()));
+        assert_eq!(jobs.find(Some("2")), Err("2: no such job".to_string()));
+    }
+
+    #[test]
+    fn specs_name_jobs_by_command() {
+        let jobs = jobs(&["sleep 10", "make all", "make check"]);
+        assert_eq!(jobs.find(Some("%sl")), Ok(1));
+        assert_eq!(jobs.find(Some("%make a")), Ok(2));
+        assert_eq!(jobs.find(Some("%make")), Err("%make: ambiguous job spec".to_string()));
+        assert_eq!(jobs.find(Some("%?check")), Ok(3));
+        assert_eq!(jobs.find(Some("%?e")), Err("%?e: ambiguous job spec".to_string()));
+        assert_eq!(jobs.find(Some("%?vim")), Err("%?vim: no such job".to_string()));
+        assert_eq!(jobs.find(Some("%all")), Err("%all: no such job".to_string()));
+    }
+
+    #[test]
+    fn current_and_previous_need_jobs() {
+        let mut jobs = jobs(&["sleep 10"]);
+        assert_eq!(jobs.find(Some("%-")), Ok(1));
+        jobs.remove(1);
+        assert_eq!(jobs.find(None), Err("current: no such job".to_string()));
+        assert_eq!(jobs.find(Some("%-")), Err("previous: no such job".to_string()));
+    }
+}
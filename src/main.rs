@@ -1,61 +1,190 @@
+mod arith;
+mod brace;
+mod complete;
+mod editor;
+mod exec;
+mod expand;
+mod glob;
+mod history;
+mod jobs;
+mod parser;
+mod pattern;
+mod sys;
+
 use std::collections::HashMap;
 use std::fs::{self, File};
 use std::fs::OpenOptions;
-use std::io::{self, Write, Read};
-use std::process::{Command, Stdio};
+use std::io::{self, PipeReader, PipeWriter, Write};
+use std::os::fd::{AsRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, Command, ExitStatus};
 use std::{path::Path, process};
 use std::env;
+use std::rc::Rc;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+use exec::{Flow, JobGroup};
+use glob::GlobOptions;
+use history::{History, HistoryRecord};
+use jobs::Jobs;
+use parser::{ParseError, Pending};
 
 #[derive(Debug, PartialEq, Clone)]
 enum TokenType {
-    Word(String),
+    Word(Vec<WordPart>),
     Pipe,
     Redirect(RedirectType),
     And,
     Or,  
     Semicolon,
-    Quote(String, bool),
+    // `;;`, `;&` and `;;&`, which end a `case` item.
+    DoubleSemicolon,
+    SemicolonAnd,
+    DoubleSemicolonAnd,
+    LeftParen,
+    RightParen,
+    Newline,
+    IoNumber(i32),
+    PipeErr,
+    Background,
+    // The raw expression of an `(( ... ))` command.
+    Arithmetic(String),
+}
+
+// The pieces one shell word is written in, such as `--name=`, `"$HOME"` and
+// `'x'` in `--name="$HOME"'x'`. Expansion needs to know which text was
+// quoted: only unquoted results are split into fields and globbed.
+#[derive(Debug, PartialEq, Clone)]
+enum WordPart {
+    // Raw text with its `$` and backquote expansions still in it.
+    Unquoted(String),
+    DoubleQuoted(String),
+    // Literal text: single-quoted, a backslash-escaped character, or a word
+    // that has already been expanded.
+    SingleQuoted(String),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 enum RedirectType {
     Output,
     Append,
-    Error,
-    ErrorAppend,
+    Input,
+    ReadWrite,
+    DupOutput,
+    DupInput,
+    OutputAll,
+    AppendAll,
+    // The body, and whether the delimiter was quoted (which keeps the body
+    // literal, like single-quoted text).
+    HereDoc(String, bool),
+    HereString,
 }
 
+// Each redirection names the file descriptor it applies to first.
 #[derive(Debug, Clone)]
 enum Redirection {
-    None,
-    OutputTo(String),
-    OutputAppend(String),
-    ErrorTo(String),
-    ErrorAppend(String),
-    Pipe,
+    OutputTo(RawFd, String),
+    OutputAppend(RawFd, String),
+    InputFrom(RawFd, String),
+    ReadWrite(RawFd, String),
+    Duplicate(RawFd, RawFd),
+    Close(RawFd),
+    HereDoc(RawFd, String),
+    HereString(RawFd, String),
 }
 
+// A simple command after expansion, ready to run.
 #[derive(Debug)]
 struct PipelineCommand {
     command: String,
     args: Vec<String>,
-    redirection: Redirection,
+    redirections: Vec<Redirection>,
+    // `NAME=value` prefixes, put in the command's environment.
+    assignments: Vec<(String, String)>,
 }
 
+// The lexer only splits input into tokens. Words keep their `$` and
+// backquote expansions as raw text; `Shell::expand_tokens` performs them
+// right before the command runs, so they see the effects of earlier commands.
 struct Lexer {
     input: Vec<char>,
     position: usize,
+    // Where lexing resumes once the current line ends, past any here-doc
+    // bodies that were read ahead for it.
+    heredoc_end: Option<usize>,
+    // Set when a here-doc body, quote or substitution runs off the end of
+    // the input, meaning the caller has to read more lines first. Says what
+    // the first of them is waiting for.
+    incomplete: Option<Pending>,
+    // Where the last token returned started.
+    token_start: usize,
+}
+
+#[derive(Clone)]
+struct Shell {
     env_vars: HashMap<String, String>,
+    // What the special parameters expand to: `$?`, `$$`, `$!`, `$0`, the
+    // positional parameters behind `$1`, `$#`, `$@` and `$*`, and `$-`.
+    last_status: i32,
+    // The status of the last command substitution in the command being
+    // expanded, which is a command without a name's own status.
+    substitution_status: Option<i32>,
+    shell_pid: u32,
+    last_background_pid: Option<u32>,
+    arg0: String,
+    positional: Vec<String>,
+    flags: String,
+    glob_options: GlobOptions,
+    // How many loops are running, and a `break` or `continue` that is
+    // unwinding out of them.
+    loop_depth: usize,
+    flow: Option<Flow>,
+    // Defined functions, and for each call in progress the variables it
+    // has made local, with the values to restore when it returns.
+    functions: HashMap<String, Rc<parser::Command>>,
+    local_frames: Vec<Vec<(String, Option<String>)>>,
+    // Job control is on when the shell reads from a terminal: then each job
+    // gets a process group, and the foreground one gets the terminal.
+    job_control: bool,
+    jobs: Jobs,
+    // The pipeline running in the foreground, named after it if it stops.
+    pipeline_text: String,
+    // How to complete each command's arguments, set by `complete`.
+    completions: HashMap<String, complete::Spec>,
 }
 
-const HISTORY_FILE_NAME: &str = ".rush_history";
-const MAX_HISTORY: usize = 1000;
+// Where the shell parks its own copies of redirected descriptors, out of
+// the way of the low numbers scripts use.
+const SAVED_FD_BASE: RawFd = 10;
 
-#[derive(Debug)]
-struct History {
-    entries: Vec<String>,
-    position: isize,
-    history_file_path: String,
+// The write ends of here-doc pipes whose writer threads are still running.
+// A forked copy of the shell has to close them, or a command reading the
+// here-doc there would never see end of file.
+static HEREDOC_WRITERS: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+
+const BUILTINS: &[&str] = &[
+    "bg", "break", "cd", "compgen", "complete", "continue", "disown", "exec", "exit", "fg", "jobs", "let",
+    "local", "return", "set", "shopt", "wait",
+];
+
+const COMMAND_NOT_FOUND: i32 = 127;
+
+// The option letters `set` takes.
+const SET_OPTIONS: &str = "abefhmnuvxC";
+
+/// The text of a word whose expansions have all been carried out.
+fn word_text(parts: &[WordPart]) -> String {
+    parts
+        .iter()
+        .map(|part| match part {
+            WordPart::Unquoted(text) | WordPart::DoubleQuoted(text) | WordPart::SingleQuoted(text) => {
+                text.as_str()
+            }
+        })
+        .collect()
 }
 
 impl Iterator for Lexer {
@@ -66,14 +195,21 @@ impl Iterator for Lexer {
 }
 
 impl Lexer {
-    fn new(input: &str, env_vars: HashMap<String, String>) -> Self {
+    fn new(input: &str) -> Self {
         Lexer {
             input: input.chars().collect(),
             position: 0,
-            env_vars,
+            heredoc_end: None,
+            incomplete: None,
+            token_start: 0,
         }
     }
 
+    /// Marks the input as incomplete, unless something earlier already has.
+    fn wait_for(&mut self, pending: Pending) {
+        self.incomplete.get_or_insert(pending);
+    }
+
     fn peek(&self) -> Option<char> {
         self.input.get(self.position).copied()
     }
@@ -88,95 +224,432 @@ impl Lexer {
         }
     }
 
-    fn lex_quote(&mut self, quote_char: char) -> Option<TokenType> {
+    /// Reads one shell word starting with `c`. Quoted, escaped and unquoted
+    /// text written side by side all goes into the same word. Returns `None`
+    /// if the word turned out to be nothing but a line continuation.
+    fn lex_word(&mut self, c: char) -> Option<Vec<WordPart>> {
+        let mut parts = Vec::new();
+        let mut unquoted = String::new();
+        let mut next = Some(c);
+        while let Some(c) = next {
+            match c {
+                '\'' | '"' => {
+                    if !unquoted.is_empty() {
+                        parts.push(WordPart::Unquoted(std::mem::take(&mut unquoted)));
+                    }
+                    parts.push(self.lex_quote(c));
+                }
+                '\\' => match self.advance() {
+                    Some('\n') => {}
+                    Some(escaped) => {
+                        if !unquoted.is_empty() {
+                            parts.push(WordPart::Unquoted(std::mem::take(&mut unquoted)));
+                        }
+                        parts.push(WordPart::SingleQuoted(escaped.to_string()));
+                    }
+                    // A backslash ending the input continues it on the next line.
+                    None => self.wait_for(Pending::Line),
+                },
+                _ => self.lex_raw_char(c, &mut unquoted),
+            }
+            next = self.peek().filter(|&c| !(c.is_whitespace() || "()|><;&".contains(c)));
+            if next.is_some() {
+                self.advance();
+            }
+        }
+        if !unquoted.is_empty() {
+            parts.push(WordPart::Unquoted(unquoted));
+        }
+        (!parts.is_empty()).then_some(parts)
+    }
+
+    // Double-quoted text is kept raw (escapes included) for expansion later.
+    fn lex_quote(&mut self, quote_char: char) -> WordPart {
         let mut content = String::new();
         let is_single = quote_char == '\'';
 
+        let mut closed = false;
         while let Some(c) = self.advance() {
             if c == quote_char {
-                return Some(TokenType::Quote(content, is_single));
+                closed = true;
+                break;
+            }
+            if is_single {
+                content.push(c);
+            } else {
+                self.lex_raw_char(c, &mut content);
+            }
+        }
+        // An unclosed quote carries on into the next line.
+        if !closed {
+            self.wait_for(Pending::Text(quote_char.to_string()));
+        }
+
+        if is_single {
+            WordPart::SingleQuoted(content)
+        } else {
+            WordPart::DoubleQuoted(content)
+        }
+    }
+
+    /// Appends `c` to a raw word, pulling in the whole of a `$(...)` or
+    /// backquoted substitution it starts, so the spaces, quotes and
+    /// operators inside don't end the word.
+    fn lex_raw_char(&mut self, c: char, word: &mut String) {
+        if c == '\\' {
+            word.push(c);
+            if let Some(next) = self.advance() {
+                word.push(next);
             }
-            if c == '\\' && !is_single {
-                if let Some(next) = self.advance() {
-                    content.push(next);
+        } else if c == '$' && self.peek() == Some('(') {
+            self.advance();
+            let body = self.lex_substitution();
+            word.push_str("$(");
+            word.push_str(&body);
+            word.push(')');
+        } else if c == '$' && self.peek() == Some('{') {
+            self.advance();
+            let body = self.lex_braced();
+            word.push_str("${");
+            word.push_str(&body);
+            word.push('}');
+        } else if c == '`' {
+            let body = self.lex_backquoted();
+            word.push('`');
+            word.push_str(&body);
+            word.push('`');
+        } else {
+            word.push(c);
+        }
+    }
+
+    /// Reads the body of a `$(...)` up to its matching `)`.
+    fn lex_substitution(&mut self) -> String {
+        let mut body = String::new();
+        let mut depth = 1;
+        while let Some(c) = self.advance() {
+            match c {
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return body;
+                    }
                 }
-            } else if c == '$' && !is_single {
-                if let Some(var) = self.lex_variable() {
-                    content.push_str(&var);
+                '\\' => {
+                    body.push(c);
+                    if let Some(next) = self.advance() {
+                        body.push(next);
+                    }
+                    continue;
                 }
-            } else {
-                content.push(c);
+                '\'' | '"' => {
+                    body.push(c);
+                    while let Some(inner) = self.advance() {
+                        body.push(inner);
+                        if inner == '\\' && c == '"' {
+                            if let Some(next) = self.advance() {
+                                body.push(next);
+                            }
+                        } else if inner == c {
+                            break;
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
             }
+            body.push(c);
         }
-        None
+        self.wait_for(Pending::Text(")".to_string()));
+        body
     }
 
-    fn lex_variable(&mut self) -> Option<String> {
-        let mut var_name = String::new();
+    /// Reads the body of a `${...}` up to its matching `}`.
+    fn lex_braced(&mut self) -> String {
+        let mut body = String::new();
+        let mut depth = 1;
+        while let Some(c) = self.advance() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return body;
+                    }
+                }
+                '\'' => {
+                    body.push(c);
+                    while let Some(inner) = self.advance() {
+                        body.push(inner);
+                        if inner == '\'' {
+                            break;
+                        }
+                    }
+                    continue;
+                }
+                '\\' | '"' | '$' | '`' => {
+                    self.lex_raw_char(c, &mut body);
+                    if c == '"' {
+                        while let Some(inner) = self.advance() {
+                            self.lex_raw_char(inner, &mut body);
+                            if inner == '"' {
+                                break;
+                            }
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            body.push(c);
+        }
+        self.wait_for(Pending::Text("}".to_string()));
+        body
+    }
+
+    fn lex_backquoted(&mut self) -> String {
+        let mut body = String::new();
+        while let Some(c) = self.advance() {
+            match c {
+                '`' => return body,
+                '\\' => {
+                    body.push(c);
+                    if let Some(next) = self.advance() {
+                        body.push(next);
+                    }
+                }
+                _ => body.push(c),
+            }
+        }
+        self.wait_for(Pending::Text("`".to_string()));
+        body
+    }
+
+    fn lex_name(&mut self) -> String {
+        let mut name = String::new();
         while let Some(c) = self.peek() {
             if c.is_alphanumeric() || c == '_' {
-                var_name.push(c);
+                name.push(c);
                 self.advance();
             } else {
                 break;
             }
         }
-        self.env_vars.get(&var_name).cloned()
+        name
     }
 
     fn lex_redirect(&mut self) -> TokenType {
+        let redirect_type = match self.peek() {
+            Some('>') => RedirectType::Append,
+            Some('&') => RedirectType::DupOutput,
+            // `>|` only differs from `>` under noclobber, which we don't have.
+            Some('|') => RedirectType::Output,
+            _ => return TokenType::Redirect(RedirectType::Output),
+        };
+        self.advance();
+        TokenType::Redirect(redirect_type)
+    }
+
+    fn lex_input_redirect(&mut self) -> TokenType {
         match self.peek() {
+            Some('&') => {
+                self.advance();
+                return TokenType::Redirect(RedirectType::DupInput);
+            }
             Some('>') => {
                 self.advance();
-                TokenType::Redirect(RedirectType::Append)
+                return TokenType::Redirect(RedirectType::ReadWrite);
             }
-            Some('2') if self.input.get(self.position + 1) == Some(&'>') => {
+            Some('<') => {}
+            _ => return TokenType::Redirect(RedirectType::Input),
+        }
+        self.advance();
+        match self.peek() {
+            Some('<') => {
                 self.advance();
+                TokenType::Redirect(RedirectType::HereString)
+            }
+            Some('-') => {
                 self.advance();
-                if self.peek() == Some('>') {
-                    self.advance();
-                    TokenType::Redirect(RedirectType::ErrorAppend)
-                } else {
-                    TokenType::Redirect(RedirectType::Error)
+                self.lex_heredoc(true)
+            }
+            _ => self.lex_heredoc(false),
+        }
+    }
+
+    fn lex_heredoc(&mut self, strip_tabs: bool) -> TokenType {
+        while matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.advance();
+        }
+
+        // Any quoting in the delimiter turns off expansion in the body.
+        let mut delimiter = String::new();
+        let mut quoted = false;
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || "|&;<>()".contains(c) {
+                break;
+            }
+            self.advance();
+            match c {
+                '\'' | '"' => {
+                    quoted = true;
+                    while let Some(inner) = self.advance() {
+                        if inner == c {
+                            break;
+                        }
+                        delimiter.push(inner);
+                    }
+                }
+                '\\' => {
+                    quoted = true;
+                    if let Some(next) = self.advance() {
+                        delimiter.push(next);
+                    }
                 }
+                _ => delimiter.push(c),
             }
-            _ => TokenType::Redirect(RedirectType::Output),
         }
+
+        let body = self.read_heredoc_body(&delimiter, strip_tabs);
+        TokenType::Redirect(RedirectType::HereDoc(body, quoted))
+    }
+
+    /// Collects here-doc lines up to `delimiter`, starting on the line after
+    /// the current one (or after the previous here-doc on the same line).
+    fn read_heredoc_body(&mut self, delimiter: &str, strip_tabs: bool) -> String {
+        let mut pos = self.heredoc_end.unwrap_or_else(|| {
+            self.input[self.position..]
+                .iter()
+                .position(|&c| c == '\n')
+                .map_or(self.input.len(), |offset| self.position + offset + 1)
+        });
+
+        let mut body = String::new();
+        loop {
+            if pos >= self.input.len() {
+                self.wait_for(Pending::HereDoc(delimiter.to_string(), strip_tabs));
+                break;
+            }
+            let line_end = self.input[pos..]
+                .iter()
+                .position(|&c| c == '\n')
+                .map_or(self.input.len(), |offset| pos + offset);
+            let mut line: String = self.input[pos..line_end].iter().collect();
+            pos = (line_end + 1).min(self.input.len());
+            if strip_tabs {
+                line = line.trim_start_matches('\t').to_string();
+            }
+            if line == delimiter {
+                break;
+            }
+            body.push_str(&line);
+            body.push('\n');
+        }
+
+        self.heredoc_end = Some(pos);
+        body
     }
 
     fn next_token(&mut self) -> Option<TokenType> {
         while let Some(c) = self.advance() {
+            self.token_start = self.position - 1;
             match c {
-                ' ' | '\t' | '\n' => continue,
+                '\n' => {
+                    if let Some(end) = self.heredoc_end.take() {
+                        self.position = self.position.max(end);
+                    }
+                    return Some(TokenType::Newline);
+                }
+                ' ' | '\t' => continue,
                 '|' => {
-                    if self.peek() == Some('|') {
-                        self.advance();
-                        return Some(TokenType::Or);
+                    match self.peek() {
+                        Some('|') => {
+                            self.advance();
+                            return Some(TokenType::Or);
+                        }
+                        Some('&') => {
+                            self.advance();
+                            return Some(TokenType::PipeErr);
+                        }
+                        _ => return Some(TokenType::Pipe),
                     }
-                    return Some(TokenType::Pipe);
                 }
                 '>' => return Some(self.lex_redirect()),
-                ';' => return Some(TokenType::Semicolon),
-                '\'' | '"' => return self.lex_quote(c),
-                '$' => {
-                    if let Some(var) = self.lex_variable() {
-                        return Some(TokenType::Word(var));
+                '<' => return Some(self.lex_input_redirect()),
+                ';' => {
+                    let token = match self.peek() {
+                        Some(';') => {
+                            self.advance();
+                            if self.peek() == Some('&') {
+                                self.advance();
+                                TokenType::DoubleSemicolonAnd
+                            } else {
+                                TokenType::DoubleSemicolon
+                            }
+                        }
+                        Some('&') => {
+                            self.advance();
+                            TokenType::SemicolonAnd
+                        }
+                        _ => TokenType::Semicolon,
+                    };
+                    return Some(token);
+                }
+                // `((` starts an arithmetic command only if it is closed by
+                // `))` around a valid expression. Otherwise it is two
+                // subshells opening, as in `((cd a; make) )`.
+                '(' if self.peek() == Some('(') => {
+                    let start = self.position;
+                    self.advance();
+                    let expression = self.lex_substitution();
+                    match self.peek() {
+                        Some(')') if arith::is_expression(&expression) => {
+                            self.advance();
+                        }
+                        None => self.wait_for(Pending::Text(")".to_string())),
+                        _ => {
+                            self.position = start;
+                            return Some(TokenType::LeftParen);
+                        }
                     }
+                    return Some(TokenType::Arithmetic(expression));
                 }
+                '(' => return Some(TokenType::LeftParen),
+                ')' => return Some(TokenType::RightParen),
                 '&' if self.peek() == Some('&') => {
                     self.advance();
                     return Some(TokenType::And);
                 }
+                '&' if self.peek() == Some('>') => {
+                    self.advance();
+                    if self.peek() == Some('>') {
+                        self.advance();
+                        return Some(TokenType::Redirect(RedirectType::AppendAll));
+                    }
+                    return Some(TokenType::Redirect(RedirectType::OutputAll));
+                }
+                '&' => return Some(TokenType::Background),
+                // A `#` starting a word comments out the rest of the line.
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.advance();
+                    }
+                }
                 _ => {
-                    let mut word = String::from(c);
-                    while let Some(next) = self.peek() {
-                        if next.is_whitespace() || ")|><;&".contains(next) {
-                            break;
+                    let Some(parts) = self.lex_word(c) else {
+                        continue;
+                    };
+                    // A number written right against `<` or `>` picks the fd
+                    // the redirection applies to, as in `2>` or `3<&-`.
+                    if let [WordPart::Unquoted(word)] = parts.as_slice() {
+                        if matches!(self.peek(), Some('<') | Some('>')) {
+                            if let Ok(fd) = word.parse() {
+                                return Some(TokenType::IoNumber(fd));
+                            }
                         }
-                        word.push(next);
-                        self.advance();
                     }
-                    return Some(TokenType::Word(word));
+                    return Some(TokenType::Word(parts));
                 }
             }
         }
@@ -184,536 +657,992 @@ impl Lexer {
     }
 }
 
-impl History {
-    fn new() -> Self {
-        let home_dir = env::var("HOME").unwrap_or_else(|_| ".".to_string());
-        let history_file_path = format!("{}/{}", home_dir, HISTORY_FILE_NAME);
-        
-        let mut history = History {
-            entries: Vec::new(),
-            position: -1,
-            history_file_path,
-        };
-        history.load_from_file();
-        history
+
+// helper functions
+
+fn not_found(command: &str) {
+    println!("{}: command not found", command);
+}
+
+fn resolve_program(command: &str, env_path: &str) -> Option<String> {
+    if command.starts_with('\'') || command.starts_with('"') {
+        Some(command.to_string())
+    } else {
+        find_in_path(command, env_path)
     }
+}
 
-    fn load_from_file(&mut self) {
-        if let Ok(mut file) = File::open(&self.history_file_path) {
-            let mut contents = String::new();
-            if file.read_to_string(&mut contents).is_ok() {
-                self.entries = contents.lines()
-                    .map(String::from)
-                    .collect::<Vec<_>>();
-                self.position = self.entries.len() as isize;
-            }
+fn open_for(redirection: &Redirection) -> io::Result<Option<File>> {
+    let mut options = OpenOptions::new();
+    let path = match redirection {
+        Redirection::OutputTo(_, path) => {
+            options.write(true).create(true).truncate(true);
+            path
+        }
+        Redirection::OutputAppend(_, path) => {
+            options.create(true).append(true);
+            path
+        }
+        Redirection::InputFrom(_, path) => {
+            options.read(true);
+            path
+        }
+        Redirection::ReadWrite(_, path) => {
+            options.read(true).write(true).create(true);
+            path
+        }
+        _ => return Ok(None),
+    };
+    options
+        .open(path)
+        .map(Some)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+/// Rewires the shell's own file descriptors for the duration of a command.
+/// Children inherit the result when they are spawned, and `restore` puts the
+/// shell back the way it was. Redirections applied with `permanent` set (as
+/// `exec` does) are never undone.
+#[derive(Default)]
+struct Redirector {
+    saved: Vec<(RawFd, Option<RawFd>)>,
+    permanent: bool,
+}
+
+impl Redirector {
+    fn permanent() -> Self {
+        Redirector {
+            saved: Vec::new(),
+            permanent: true,
         }
     }
 
-    fn save_to_file(&self) {
-        if let Ok(mut file) = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&self.history_file_path)
-        {
-            for entry in &self.entries {
-                writeln!(file, "{}", entry).ok();
-            }
+    fn save(&mut self, fd: RawFd) {
+        if self.permanent || self.saved.iter().any(|&(saved, _)| saved == fd) {
+            return;
         }
+        // A descriptor that isn't open has nothing to copy; restoring it
+        // means closing it again.
+        let copy = sys::dup_above(fd, SAVED_FD_BASE).ok();
+        self.saved.push((fd, copy));
     }
 
-    fn add(&mut self, command: String) {
-        if !command.trim().is_empty() && self.entries.last().map_or(true, |last| last != &command) {
-            self.entries.push(command);
-            if self.entries.len() > MAX_HISTORY {
-                self.entries.remove(0);
-            }
-            self.position = self.entries.len() as isize;
-            self.save_to_file();
+    fn redirect(&mut self, fd: RawFd, source: impl Into<OwnedFd>) -> io::Result<()> {
+        let source = source.into();
+        io::stdout().flush().ok();
+        self.save(fd);
+        if source.as_raw_fd() == fd {
+            // Already in place; closing `source` would undo the redirect.
+            let _ = source.into_raw_fd();
+            return Ok(());
         }
+        sys::dup2(source.as_raw_fd(), fd)
     }
 
-    fn get_previous(&mut self) -> Option<&String> {
-        if self.position > 0 && !self.entries.is_empty() {
-            self.position -= 1;
-            self.entries.get(self.position as usize)
-        } else {
-            self.entries.get(0)
+    fn duplicate(&mut self, fd: RawFd, source: RawFd) -> io::Result<()> {
+        io::stdout().flush().ok();
+        if fd == source {
+            return Ok(());
         }
+        self.save(fd);
+        sys::dup2(source, fd)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", source, e)))
     }
 
-    fn get_next(&mut self) -> Option<&String> {
-        if (self.position + 1) < self.entries.len() as isize {
-            self.position += 1;
-            self.entries.get(self.position as usize)
-        } else {
-            self.position = self.entries.len() as isize;
-            None
+    fn close(&mut self, fd: RawFd) {
+        io::stdout().flush().ok();
+        self.save(fd);
+        sys::close(fd).ok();
+    }
+
+    /// Applies redirections left to right, so `> out 2>&1` and `2>&1 > out`
+    /// differ the way POSIX says they should.
+    fn apply(&mut self, redirections: &[Redirection]) -> io::Result<()> {
+        for redirection in redirections {
+            match redirection {
+                Redirection::OutputTo(fd, _)
+                | Redirection::OutputAppend(fd, _)
+                | Redirection::InputFrom(fd, _)
+                | Redirection::ReadWrite(fd, _) => {
+                    if let Some(file) = open_for(redirection)? {
+                        self.redirect(*fd, file)?;
+                    }
+                }
+                Redirection::Duplicate(fd, source) => self.duplicate(*fd, *source)?,
+                Redirection::Close(fd) => self.close(*fd),
+                Redirection::HereDoc(fd, text) | Redirection::HereString(fd, text) => {
+                    let (reader, mut writer) = io::pipe()?;
+                    // Written from a separate thread so a reader that never
+                    // drains the pipe can't block the shell.
+                    let text = text.clone();
+                    let write_end = writer.as_raw_fd();
+                    heredoc_writers().push(write_end);
+                    thread::spawn(move || {
+                        writer.write_all(text.as_bytes()).ok();
+                        // Closed with the list locked, so a fork never sees
+                        // the number after it has been reused.
+                        let mut writers = heredoc_writers();
+                        writers.retain(|&open| open != write_end);
+                        drop(writer);
+                    });
+                    self.redirect(*fd, reader)?;
+                }
+            }
         }
+        Ok(())
     }
 
-    fn reset_position(&mut self) {
-        self.position = self.entries.len() as isize;
+    fn restore(self) {
+        io::stdout().flush().ok();
+        for (fd, copy) in self.saved.into_iter().rev() {
+            match copy {
+                Some(copy) => {
+                    sys::dup2(copy, fd).ok();
+                    sys::close(copy).ok();
+                }
+                None => {
+                    sys::close(fd).ok();
+                }
+            }
+        }
     }
 }
 
+fn heredoc_writers() -> MutexGuard<'static, Vec<RawFd>> {
+    HEREDOC_WRITERS.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
-// helper functions
-
-fn not_found(command: &str) {
-    println!("{}: command not found", command);
+/// Forks the shell. The child closes its copies of the write ends of any
+/// here-doc pipes still being filled; close-on-exec only covers the
+/// programs it goes on to run.
+fn fork_shell() -> io::Result<i32> {
+    // Held across the fork, so no writer thread closes its pipe meanwhile.
+    let writers = heredoc_writers();
+    let pid = sys::fork()?;
+    if pid == 0 {
+        for &fd in writers.iter() {
+            sys::close(fd).ok();
+        }
+    }
+    Ok(pid)
 }
 
-fn execute_command(
-    command: &str,
-    args: &[String],
+/// Spawns one pipeline stage, into `group` if the shell is doing job
+/// control. The pipe ends are put in place first and the command's own
+/// redirections are applied on top of them, so `cmd 2>&1 |` and
+/// `cmd > file |` behave as they do in other shells.
+fn spawn_stage(
+    cmd: &PipelineCommand,
     env_path: &str,
-    redirection: Redirection,
-    stdin: Option<Stdio>,
-) -> io::Result<(Option<Stdio>, bool)> {
-    let program = if command.starts_with('\'') || command.starts_with('"') {
-        command.to_string()
-    } else {
-        match find_in_path(command, env_path) {
-            Some(path) => path,
-            None => {
-                not_found(command);
-                return Ok((None, false));
+    stdin: Option<PipeReader>,
+    stdout: Option<PipeWriter>,
+    group: Option<JobGroup>,
+) -> io::Result<Option<Child>> {
+    let mut redirector = Redirector::default();
+    let result = (|| {
+        if let Some(stdin) = stdin {
+            redirector.redirect(0, stdin)?;
+        }
+        if let Some(stdout) = stdout {
+            redirector.redirect(1, stdout)?;
+        }
+        redirector.apply(&cmd.redirections)?;
+
+        let Some(program) = resolve_program(&cmd.command, env_path) else {
+            not_found(&cmd.command);
+            return Ok(None);
+        };
+        // argv[0] stays as typed, so the child's own error messages name
+        // `ls` rather than the `/usr/bin/ls` we resolved it to.
+        let mut command = Command::new(&program);
+        command.arg0(&cmd.command).args(&cmd.args).envs(cmd.assignments.iter().cloned());
+        if let Some(group) = group {
+            sys::spawn_in_job(&mut command, group.pgid, group.foreground);
+        }
+        command.spawn().map(Some)
+    })();
+    redirector.restore();
+    result
+}
+
+/// The status a command reports through `$?`: its exit code, or 128 plus the
+/// signal number if a signal killed it.
+fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+}
+
+fn parse_redirection(
+    fd: Option<RawFd>,
+    redir_type: &RedirectType,
+    target: &str,
+    redirections: &mut Vec<Redirection>,
+) {
+    let target = target.to_string();
+    match redir_type {
+        RedirectType::Output => redirections.push(Redirection::OutputTo(fd.unwrap_or(1), target)),
+        RedirectType::Append => {
+            redirections.push(Redirection::OutputAppend(fd.unwrap_or(1), target))
+        }
+        RedirectType::Input => redirections.push(Redirection::InputFrom(fd.unwrap_or(0), target)),
+        RedirectType::ReadWrite => {
+            redirections.push(Redirection::ReadWrite(fd.unwrap_or(0), target))
+        }
+        RedirectType::DupOutput | RedirectType::DupInput => {
+            let default_fd = if *redir_type == RedirectType::DupOutput { 1 } else { 0 };
+            let fd = fd.unwrap_or(default_fd);
+            if target == "-" {
+                redirections.push(Redirection::Close(fd));
+            } else if let Ok(source) = target.parse() {
+                redirections.push(Redirection::Duplicate(fd, source));
+            } else if *redir_type == RedirectType::DupOutput && fd == 1 {
+                // `>&file` is the older spelling of `&>file`.
+                parse_redirection(None, &RedirectType::OutputAll, &target, redirections);
+            } else {
+                eprintln!("{}: ambiguous redirect", target);
             }
         }
-    };
+        RedirectType::OutputAll => {
+            redirections.push(Redirection::OutputTo(1, target));
+            redirections.push(Redirection::Duplicate(2, 1));
+        }
+        RedirectType::AppendAll => {
+            redirections.push(Redirection::OutputAppend(1, target));
+            redirections.push(Redirection::Duplicate(2, 1));
+        }
+        RedirectType::HereString => {
+            redirections.push(Redirection::HereString(fd.unwrap_or(0), format!("{}\n", target)))
+        }
+        RedirectType::HereDoc(body, _) => {
+            redirections.push(Redirection::HereDoc(fd.unwrap_or(0), body.clone()))
+        }
+    }
+}
 
-    let mut cmd = Command::new(&program);
-    cmd.args(args);
+fn find_in_path(command: &str, path: &str) -> Option<String> {
+    if command.contains('/') {
+        return is_executable(Path::new(command)).then(|| command.to_string());
+    }
+    path.split(':')
+        .map(|dir| Path::new(dir).join(command))
+        .find(|full_path| is_executable(full_path))
+        .map(|full_path| full_path.to_string_lossy().into_owned())
+}
+
+/// Whether `path` is a file someone may execute.
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
 
-    if let Some(stdin) = stdin {
-        cmd.stdin(stdin);
+fn expand_tilde(path: &str) -> String {
+    if path == "~" {
+        std::env::var("HOME").unwrap_or_else(|_| String::from(path))
+    } else if path.starts_with("~/") {
+        let home = std::env::var("HOME").unwrap_or_else(|_| String::from("~"));
+        path.replacen("~", &home, 1)
+    } else {
+        String::from(path)
     }
+}
 
-    match &redirection {
-        Redirection::Pipe => {
-            cmd.stdout(Stdio::piped());
-            cmd.stderr(Stdio::inherit());
-            let mut child = cmd.spawn()?;
-            let success = child.wait()?.success();
-            Ok((child.stdout.map(Stdio::from), success))
+fn change_directory(path: &str) -> io::Result<()> {
+    let expanded_path = expand_tilde(path);
+    match std::env::set_current_dir(&expanded_path) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("cd: {}: No such file or directory", path);
+            Err(e)
         }
-        Redirection::ErrorTo(path) | Redirection::ErrorAppend(path) => {
-            cmd.stdout(Stdio::inherit());
-            cmd.stderr(Stdio::piped());
-            let output = cmd.output()?;
-            let success = output.status.success();
+    }
+}
 
-            let stderr_str = String::from_utf8_lossy(&output.stderr);
-            let cleaned_stderr = stderr_str.replace(&format!("/usr/bin/{}", command), command);
 
-            let mut file = if matches!(redirection, Redirection::ErrorTo(_)) {
-                OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(path)?
-            } else {
-                OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .append(true)
-                    .open(path)?
-            };
-            file.write_all(cleaned_stderr.as_bytes())?;
-            Ok((None, success))
-        }
-        Redirection::OutputTo(path) | Redirection::OutputAppend(path) => {
-            cmd.stderr(Stdio::inherit());
-            cmd.stdout(Stdio::piped());
-            let output = cmd.output()?;
-            let success = output.status.success();
-
-            let mut file = if matches!(redirection, Redirection::OutputTo(_)) {
-                OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(path)?
+/// Runs a builtin in the shell process itself, with its redirections applied
+/// around it.
+fn run_builtin(shell: &mut Shell, cmd: &PipelineCommand) -> io::Result<i32> {
+    if cmd.command == "exec" {
+        return exec_command(cmd, shell.search_path());
+    }
+
+    let mut redirector = Redirector::default();
+    if let Err(e) = redirector.apply(&cmd.redirections) {
+        redirector.restore();
+        return Err(e);
+    }
+    let status = match cmd.command.as_str() {
+        "exit" => process::exit(
+            cmd.args
+                .first()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(shell.last_status),
+        ),
+        "cd" => {
+            let path = cmd.args.first().map(String::as_str).unwrap_or("");
+            let changed = if path.is_empty() {
+                let home = shell.env_vars.get("HOME").cloned().unwrap_or_default();
+                change_directory(&home)
             } else {
-                OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .append(true)
-                    .open(path)?
+                change_directory(path)
             };
-            file.write_all(&output.stdout)?;
-            Ok((None, success))
+            if changed.is_ok() { 0 } else { 1 }
         }
-        Redirection::None => {
-            cmd.stdout(Stdio::inherit());
-            cmd.stderr(Stdio::inherit());
-            let status = cmd.status()?;
-            Ok((None, status.success()))
-        }
-    }
+        "set" => set_builtin(shell, &cmd.args),
+        "shopt" => shopt_builtin(shell, &cmd.args),
+        "let" => let_builtin(shell, &cmd.args),
+        "break" | "continue" => loop_control_builtin(shell, &cmd.command, &cmd.args),
+        "local" => local_builtin(shell, &cmd.args),
+        "return" => return_builtin(shell, &cmd.args),
+        "jobs" => jobs_builtin(shell, &cmd.args),
+        "fg" => fg_builtin(shell, &cmd.args),
+        "bg" => bg_builtin(shell, &cmd.args),
+        "wait" => wait_builtin(shell, &cmd.args),
+        "disown" => disown_builtin(shell, &cmd.args),
+        "complete" => complete_builtin(shell, &cmd.args),
+        "compgen" => compgen_builtin(shell, &cmd.args),
+        _ => 1,
+    };
+    redirector.restore();
+    Ok(status)
 }
 
-fn parse_command(tokens: &[TokenType]) -> Option<PipelineCommand> {
-    let mut command = None;
-    let mut args = Vec::new();
-    let mut redirection = Redirection::None;
-    let mut i = 0;
+/// `set -- args` (or just `set args`) replaces the positional parameters;
+/// plain `set` lists the shell's variables.
+/// `set` with no arguments prints the variables. Leading `-x`/`+x` words
+/// turn options on and off, as `$-` shows; the shell only records them so
+/// far. The words after the options, or after `--` even if there are none,
+/// replace the positional parameters.
+fn set_builtin(shell: &mut Shell, args: &[String]) -> i32 {
+    if args.is_empty() {
+        let mut names: Vec<&String> = shell.env_vars.keys().collect();
+        names.sort();
+        for name in names {
+            println!("{}={}", name, shell.env_vars[name]);
+        }
+        return 0;
+    }
 
-    while i < tokens.len() {
-        match &tokens[i] {
-            TokenType::Word(word) | TokenType::Quote(word, _) => {
-                if command.is_none() {
-                    command = Some(word.clone());
-                } else {
-                    args.push(word.clone());
-                }
-                i += 1;
-            }
-            TokenType::Redirect(redir_type) => {
-                if i + 1 < tokens.len() {
-                    if let TokenType::Word(path) | TokenType::Quote(path, _) = &tokens[i + 1] {
-                        redirection = match redir_type {
-                            RedirectType::Output => Redirection::OutputTo(path.clone()),
-                            RedirectType::Append => Redirection::OutputAppend(path.clone()),
-                            RedirectType::Error => Redirection::ErrorTo(path.clone()),
-                            RedirectType::ErrorAppend => Redirection::ErrorAppend(path.clone()),
-                        };
-                        i += 2;
-                    } else {
-                        i += 1;
-                    }
-                } else {
-                    i += 1;
-                }
-            }
-            TokenType::Pipe => {
-                redirection = Redirection::Pipe;
-                i += 1;
+    let mut rest = args;
+    let mut replace = false;
+    while let Some((word, after)) = rest.split_first() {
+        if word == "--" || word == "-" {
+            rest = after;
+            replace = true;
+            break;
+        }
+        let (on, letters) = match (word.strip_prefix('-'), word.strip_prefix('+')) {
+            (Some(letters), _) | (_, Some(letters)) if !letters.is_empty() => (word.starts_with('-'), letters),
+            _ => break,
+        };
+        if let Some(unknown) = letters.chars().find(|c| !SET_OPTIONS.contains(*c)) {
+            eprintln!("set: {}{}: invalid option", &word[..1], unknown);
+            eprintln!("set: usage: set [-{}] [--] [arg ...]", SET_OPTIONS);
+            return 2;
+        }
+        for letter in letters.chars() {
+            shell.flags.retain(|c| c != letter);
+            if on {
+                shell.flags.push(letter);
             }
-            _ => i += 1,
         }
+        rest = after;
     }
+    if replace || !rest.is_empty() {
+        shell.positional = rest.to_vec();
+    }
+    0
+}
 
-    command.map(|cmd| PipelineCommand {
-        command: cmd,
-        args,
-        redirection,
-    })
+/// `break [n]` and `continue [n]` act on the nth enclosing loop, or the
+/// outermost one if there are fewer than n.
+fn loop_control_builtin(shell: &mut Shell, name: &str, args: &[String]) -> i32 {
+    let count = match args.first().map(|arg| arg.parse::<usize>()) {
+        None => 1,
+        Some(Ok(count)) if count > 0 => count,
+        Some(Ok(_)) => {
+            eprintln!("{}: {}: loop count out of range", name, args[0]);
+            return 1;
+        }
+        Some(Err(_)) => {
+            eprintln!("{}: {}: numeric argument required", name, args[0]);
+            return 1;
+        }
+    };
+    if shell.loop_depth == 0 {
+        eprintln!("{}: only meaningful in a `for', `while', or `until' loop", name);
+        return 0;
+    }
+    let count = count.min(shell.loop_depth);
+    shell.flow = Some(if name == "break" {
+        Flow::Break(count)
+    } else {
+        Flow::Continue(count)
+    });
+    0
 }
 
-fn parse_pipeline(tokens: Vec<TokenType>) -> Vec<(Vec<PipelineCommand>, Option<TokenType>)> {
-    let mut pipelines = Vec::new();
-    let mut current_pipeline = Vec::new();
-    let mut current_tokens = Vec::new();
+/// `return [n]` leaves the current function with status n, or the status
+/// of the last command.
+fn return_builtin(shell: &mut Shell, args: &[String]) -> i32 {
+    if shell.local_frames.is_empty() {
+        eprintln!("return: can only `return' from a function");
+        return 1;
+    }
+    let status = match args.first().map(|arg| arg.parse::<i32>()) {
+        None => shell.last_status,
+        Some(Ok(status)) => status.rem_euclid(256),
+        Some(Err(_)) => {
+            eprintln!("return: {}: numeric argument required", args[0]);
+            2
+        }
+    };
+    shell.flow = Some(Flow::Return);
+    status
+}
 
-    for token in tokens.iter() {
-        match token {
-            TokenType::Pipe => {
-                if !current_tokens.is_empty() {
-                    if let Some(command) = parse_command(&current_tokens) {
-                        current_pipeline.push(command);
-                    }
-                    current_tokens.clear();
-                }
+/// `local name[=value]...` makes variables local to the current function
+/// call. A name given without a value starts out unset.
+fn local_builtin(shell: &mut Shell, args: &[String]) -> i32 {
+    if shell.local_frames.is_empty() {
+        eprintln!("local: can only be used in a function");
+        return 1;
+    }
+    let mut status = 0;
+    for arg in args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+        if parser::is_name(name) {
+            shell.set_local(name, value);
+        } else {
+            eprintln!("local: `{}': not a valid identifier", arg);
+            status = 1;
+        }
+    }
+    status
+}
+
+/// `jobs [-l|-p] [job...]` lists jobs, with their pids under `-l` or as
+/// only their pids under `-p`. Finished jobs are listed one last time.
+fn jobs_builtin(shell: &mut Shell, args: &[String]) -> i32 {
+    let mut long = false;
+    let mut pids_only = false;
+    let mut specs = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "-l" => long = true,
+            "-p" => pids_only = true,
+            option if option.starts_with('-') => {
+                eprintln!("jobs: {}: invalid option", option);
+                return 2;
             }
-            TokenType::And | TokenType::Or | TokenType::Semicolon => {
-                if !current_tokens.is_empty() {
-                    if let Some(command) = parse_command(&current_tokens) {
-                        current_pipeline.push(command);
-                    }
-                    current_tokens.clear();
-                }
-                if !current_pipeline.is_empty() {
-                    pipelines.push((current_pipeline, Some(token.clone())));
-                    current_pipeline = Vec::new();
+            spec => specs.push(spec),
+        }
+    }
+
+    shell.jobs.update();
+    let mut status = 0;
+    let ids = if specs.is_empty() {
+        shell.jobs.ids()
+    } else {
+        let mut ids = Vec::new();
+        for spec in specs {
+            match shell.jobs.find(Some(spec)) {
+                Ok(id) => ids.push(id),
+                Err(message) => {
+                    eprintln!("jobs: {}", message);
+                    status = 1;
                 }
             }
-            _ => current_tokens.push(token.clone()),
         }
+        ids
+    };
+    for id in ids {
+        match shell.jobs.get(id) {
+            Some(job) if pids_only => println!("{}", job.pgid),
+            Some(_) => println!("{}", shell.jobs.describe(id, long)),
+            None => continue,
+        }
+        shell.jobs.reported(id);
     }
+    status
+}
 
-    if !current_tokens.is_empty() {
-        if let Some(command) = parse_command(&current_tokens) {
-            current_pipeline.push(command);
+/// `fg [job]` continues a job in the foreground and waits for it.
+fn fg_builtin(shell: &mut Shell, args: &[String]) -> i32 {
+    shell.jobs.update();
+    let id = match shell.jobs.find(args.first().map(String::as_str)) {
+        Ok(id) => id,
+        Err(message) => {
+            eprintln!("fg: {}", message);
+            return 1;
         }
+    };
+    let Some(job) = shell.jobs.get_mut(id) else {
+        return 1;
+    };
+    println!("{}", job.command);
+    if shell.job_control {
+        sys::set_terminal_group(0, job.pgid).ok();
+    }
+    let mut waited = Ok(());
+    if job.stopped {
+        job.stopped = false;
+        waited = sys::kill(-job.pgid, sys::SIGCONT);
     }
-    if !current_pipeline.is_empty() {
-        pipelines.push((current_pipeline, None));
+    waited = waited.and_then(|_| shell.jobs.wait(id));
+    if shell.job_control {
+        sys::set_terminal_group(0, sys::process_group()).ok();
     }
 
-    pipelines
+    if let Err(e) = waited {
+        eprintln!("fg: {}", e);
+        return 1;
+    }
+    match shell.jobs.get(id) {
+        Some(job) if job.stopped => {
+            shell.jobs.make_current(id);
+            shell.report_stopped(id);
+            128 + sys::SIGTSTP
+        }
+        Some(job) => {
+            let status = job.status();
+            if status == 128 + sys::SIGINT {
+                sys::set_interrupted(true);
+            }
+            shell.jobs.remove(id);
+            status
+        }
+        None => 0,
+    }
 }
 
-fn find_in_path(command: &str, path: &str) -> Option<String> {
-    let cmd_path = Path::new(command);
-    if cmd_path.is_absolute() || command.contains('/') {
-        if let Ok(metadata) = fs::metadata(cmd_path) {
-            if metadata.is_file() {
-                #[cfg(unix)]
-                {
-                    use std::os::unix::fs::PermissionsExt;
-                    if metadata.permissions().mode() & 0o111 != 0 {
-                        return Some(command.to_string());
-                    }
-                }
-                #[cfg(not(unix))]
-                {
-                    return Some(command.to_string());
-                }
+/// `bg [job]` continues a stopped job in the background.
+fn bg_builtin(shell: &mut Shell, args: &[String]) -> i32 {
+    shell.jobs.update();
+    let id = match shell.jobs.find(args.first().map(String::as_str)) {
+        Ok(id) => id,
+        Err(message) => {
+            eprintln!("bg: {}", message);
+            return 1;
+        }
+    };
+    let Some(job) = shell.jobs.get_mut(id) else {
+        return 1;
+    };
+    if !job.stopped {
+        eprintln!("bg: job {} already in background", id);
+        return 0;
+    }
+    job.stopped = false;
+    if let Err(e) = sys::kill(-job.pgid, sys::SIGCONT) {
+        eprintln!("bg: {}", e);
+        return 1;
+    }
+    shell.jobs.make_current(id);
+    println!("[{}]+ {} &", id, shell.jobs.get(id).map_or("", |job| &job.command));
+    0
+}
+
+/// `wait [job|pid...]` waits for the given jobs, or for every job, and
+/// returns the status of the last one waited for. Stopped jobs are reported
+/// rather than waited for, and Ctrl-C gives up waiting.
+fn wait_builtin(shell: &mut Shell, args: &[String]) -> i32 {
+    let mut ids = Vec::new();
+    let mut status = 0;
+    for arg in args {
+        let found = if arg.starts_with('%') {
+            shell.jobs.find(Some(arg))
+        } else {
+            match arg.parse::<i32>() {
+                Ok(pid) => shell
+                    .jobs
+                    .find_pid(pid)
+                    .ok_or_else(|| format!("pid {} is not a child of this shell", pid)),
+                Err(_) => Err(format!("`{}': not a pid or valid job spec", arg)),
+            }
+        };
+        match found {
+            Ok(id) => ids.push(Some(id)),
+            Err(message) => {
+                eprintln!("wait: {}", message);
+                ids.push(None);
             }
         }
-        return None;
+    }
+    if args.is_empty() {
+        ids = shell.jobs.ids().into_iter().map(Some).collect();
     }
 
-    for dir in path.split(':') {
-        let full_path = Path::new(dir).join(command);
-        if let Ok(metadata) = fs::metadata(&full_path) {
-            if metadata.is_file() {
-                #[cfg(unix)]
-                {
-                    use std::os::unix::fs::PermissionsExt;
-                    if metadata.permissions().mode() & 0o111 != 0 {
-                        return Some(full_path.to_string_lossy().to_string());
-                    }
-                }
-                #[cfg(not(unix))]
-                {
-                    return Some(full_path.to_string_lossy().to_string());
+    for id in ids {
+        let Some(id) = id else {
+            status = COMMAND_NOT_FOUND;
+            continue;
+        };
+        // A stopped job would never finish; it stays in the table instead.
+        if !shell.jobs.get(id).is_some_and(|job| job.stopped) {
+            match shell.jobs.wait(id) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => return 128 + sys::SIGINT,
+                Err(e) => {
+                    eprintln!("wait: {}", e);
+                    return 1;
                 }
             }
         }
+        match shell.jobs.get(id) {
+            Some(job) if job.stopped => {
+                eprintln!("wait: %{}: job is stopped", id);
+                status = 128 + sys::SIGTSTP;
+            }
+            Some(job) => {
+                status = job.status();
+                shell.jobs.remove(id);
+            }
+            None => status = 0,
+        }
     }
-    None
+    if args.is_empty() { 0 } else { status }
 }
 
-fn expand_tilde(path: &str) -> String {
-    if path == "~" {
-        std::env::var("HOME").unwrap_or_else(|_| String::from(path))
-    } else if path.starts_with("~/") {
-        let home = std::env::var("HOME").unwrap_or_else(|_| String::from("~"));
-        path.replacen("~", &home, 1)
+/// `disown [-a] [job...]` removes jobs from the table, so the shell stops
+/// tracking them.
+fn disown_builtin(shell: &mut Shell, args: &[String]) -> i32 {
+    let ids: Vec<Result<usize, String>> = if args.iter().any(|arg| arg == "-a") {
+        shell.jobs.ids().into_iter().map(Ok).collect()
+    } else if args.is_empty() {
+        vec![shell.jobs.find(None)]
     } else {
-        String::from(path)
+        args.iter().map(|arg| shell.jobs.find(Some(arg))).collect()
+    };
+    let mut status = 0;
+    for id in ids {
+        match id {
+            Ok(id) => shell.jobs.disown(id),
+            Err(message) => {
+                eprintln!("disown: {}", message);
+                status = 1;
+            }
+        }
     }
+    status
 }
 
-fn change_directory(path: &str) -> io::Result<()> {
-    let expanded_path = expand_tilde(path);
-    match std::env::set_current_dir(&expanded_path) {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            println!("cd: {}: No such file or directory", path);
-            Err(e)
+/// `let expr...` evaluates each expression in turn, and succeeds if the last
+/// value is non-zero.
+fn let_builtin(shell: &mut Shell, args: &[String]) -> i32 {
+    if args.is_empty() {
+        eprintln!("let: expression expected");
+        return 1;
+    }
+    let mut value = 0;
+    for arg in args {
+        match arith::evaluate(arg, &mut shell.env_vars) {
+            Ok(result) => value = result,
+            Err(message) => {
+                eprintln!("{}", message);
+                return 1;
+            }
         }
     }
+    if value != 0 { 0 } else { 1 }
 }
 
+/// `complete [options] name...` sets how the named commands' arguments are
+/// completed, `complete -r [name...]` removes that, and `complete -p
+/// [name...]` (or plain `complete`) shows it as the commands that set it.
+/// An `-F` function gets the word being completed as `$2`; see
+/// `complete::call_function` for what else it can read.
+fn complete_builtin(shell: &mut Shell, args: &[String]) -> i32 {
+    let (mode, names) = match args.first().map(String::as_str) {
+        Some(flag @ ("-p" | "-r")) => (Some(flag), &args[1..]),
+        None => (Some("-p"), args),
+        _ => (None, args),
+    };
+    let mut status = 0;
+    match mode {
+        Some("-p") if names.is_empty() => {
+            let mut names: Vec<&String> = shell.completions.keys().collect();
+            names.sort();
+            for name in names {
+                println!("{}", shell.completions[name].describe(name));
+            }
+        }
+        Some("-r") if names.is_empty() => shell.completions.clear(),
+        Some(flag) => {
+            for name in names {
+                let found = match flag {
+                    "-p" => shell.completions.get(name).map(|spec| println!("{}", spec.describe(name))),
+                    _ => shell.completions.remove(name).map(|_| ()),
+                };
+                if found.is_none() {
+                    eprintln!("complete: {}: no completion specification", name);
+                    status = 1;
+                }
+            }
+        }
+        None => match complete::Spec::parse(args) {
+            Ok((_, [])) => {
+                eprintln!("complete: usage: complete [-bcdfjuv] [-o option] [-A action] [-G pattern] [-W words] [-F function] [-X filter] [-P prefix] [-S suffix] name...");
+                status = 2;
+            }
+            Ok((spec, names)) => {
+                for name in names {
+                    shell.completions.insert(name.clone(), spec.clone());
+                }
+            }
+            Err(message) => {
+                eprintln!("complete: {}", message);
+                status = 2;
+            }
+        },
+    }
+    status
+}
 
-fn main() {
-     let env_path = std::env::var("PATH").unwrap();
-    let mut env_vars = HashMap::new();
-    env_vars.insert("HOME".to_string(), std::env::var("HOME").unwrap_or_default());
-    env_vars.insert("PATH".to_string(), env_path.clone());
-    
-    let mut history = History::new();
-    
-    loop {
-        print!("$ ");
-        if io::stdout().flush().is_err() {
-            println!("Error flushing stdout");
-            continue;
+/// `compgen [options] [word]` prints the candidates the options give for
+/// completing `word`, one per line. It fails if there are none.
+fn compgen_builtin(shell: &mut Shell, args: &[String]) -> i32 {
+    let (spec, word) = match complete::Spec::parse(args) {
+        Ok((spec, [])) => (spec, String::new()),
+        Ok((spec, [word])) => (spec, word.clone()),
+        Ok(_) => {
+            eprintln!("compgen: too many arguments");
+            return 2;
+        }
+        Err(message) => {
+            eprintln!("compgen: {}", message);
+            return 2;
         }
+    };
+    let candidates = complete::compgen(shell, &spec, word);
+    if candidates.is_empty() {
+        return 1;
+    }
+    for candidate in candidates {
+        println!("{}", candidate);
+    }
+    0
+}
 
-        let mut current_input = String::new();
-        let stdin = io::stdin();
-        let mut stdout = io::stdout();
-        let mut chars = stdin.bytes();
+/// `shopt -s name...` turns options on and `shopt -u name...` turns them off.
+/// Otherwise it prints the named options (or all of them), and with `-q` it
+/// only reports through its status whether they are all on.
+fn shopt_builtin(shell: &mut Shell, args: &[String]) -> i32 {
+    let (mode, names) = match args.first().map(String::as_str) {
+        Some(flag @ ("-s" | "-u" | "-q")) => (Some(flag), &args[1..]),
+        _ => (None, args),
+    };
+    let names: Vec<&str> = if names.is_empty() {
+        GlobOptions::NAMES.to_vec()
+    } else {
+        names.iter().map(String::as_str).collect()
+    };
 
-        'input: loop {
-            match chars.next() {
-                Some(Ok(b'\n')) => {
-                    println!();
-                    break;
-                }
-                Some(Ok(3)) => { // Ctrl-C
-                    println!("^C");
-                    current_input.clear();
-                    break;
-                }
-                Some(Ok(4)) => { // Ctrl-D
-                    if current_input.is_empty() {
-                        println!();
-                        process::exit(0);
-                    }
-                }
-                Some(Ok(27)) => {
-                    if let (Some(Ok(91)), Some(Ok(code))) = (chars.next(), chars.next()) {
-                        match code {
-                            65 => {
-                                if let Some(previous) = history.get_previous() {
-                                    print!("\r$ {}", " ".repeat(current_input.len()));
-                                    print!("\r$ {}", previous);
-                                    stdout.flush().unwrap();
-                                    current_input = previous.clone();
-                                }
-                            }
-                            66 => { 
-                                print!("\r$ {}", " ".repeat(current_input.len()));
-                                if let Some(next) = history.get_next() {
-                                    print!("\r$ {}", next);
-                                    current_input = next.clone();
-                                } else {
-                                    print!("\r$ ");
-                                    current_input.clear();
-                                }
-                                stdout.flush().unwrap();
-                            }
-                            _ => {}
-                        }
-                        continue 'input;
-                    }
-                }
-                Some(Ok(127)) => { 
-                    if !current_input.is_empty() {
-                        current_input.pop();
-                        print!("\r$ {}", " ".repeat(current_input.len() + 1));
-                        print!("\r$ {}", current_input);
-                        stdout.flush().unwrap();
-                    }
+    let mut status = 0;
+    for name in names {
+        let Some(option) = shell.glob_options.get_mut(name) else {
+            eprintln!("shopt: {}: invalid shell option name", name);
+            status = 1;
+            continue;
+        };
+        match mode {
+            Some("-s") => *option = true,
+            Some("-u") => *option = false,
+            Some(_) => {
+                if !*option {
+                    status = 1;
                 }
-                Some(Ok(c)) => {
-                    current_input.push(c as char);
-                    print!("{}", c as char);
-                    stdout.flush().unwrap();
+            }
+            None => {
+                println!("{:<15}\t{}", name, if *option { "on" } else { "off" });
+                if !*option && !args.is_empty() {
+                    status = 1;
                 }
-                _ => {}
             }
         }
+    }
+    status
+}
 
-        if current_input.trim().is_empty() {
-            continue;
+/// `exec` without a command applies its redirections to the shell for good,
+/// e.g. `exec 3> log` or `exec 3<&-`. With a command, it replaces the shell.
+fn exec_command(cmd: &PipelineCommand, env_path: &str) -> io::Result<i32> {
+    Redirector::permanent().apply(&cmd.redirections)?;
+    match cmd.args.split_first() {
+        Some((program, args)) => exec_program(program, args, &[], env_path),
+        None => Ok(0),
+    }
+}
+
+/// Replaces the shell with `program`. Only returns if it can't: with the
+/// not-found status, or the error from exec.
+fn exec_program(program: &str, args: &[String], assignments: &[(String, String)], env_path: &str) -> io::Result<i32> {
+    let Some(path) = resolve_program(program, env_path) else {
+        not_found(program);
+        return Ok(COMMAND_NOT_FOUND);
+    };
+    sys::default_signals();
+    Err(Command::new(path).arg0(program).args(args).envs(assignments.iter().cloned()).exec())
+}
+
+impl Shell {
+    fn new() -> Self {
+        let mut env_vars = HashMap::new();
+        env_vars.insert("HOME".to_string(), std::env::var("HOME").unwrap_or_default());
+        env_vars.insert("PATH".to_string(), std::env::var("PATH").unwrap_or_default());
+
+        let mut args = env::args();
+        let arg0 = args.next().unwrap_or_else(|| "rush".to_string());
+        let flags = if sys::is_terminal(0) { "i" } else { "" };
+        Shell {
+            env_vars,
+            last_status: 0,
+            substitution_status: None,
+            shell_pid: process::id(),
+            last_background_pid: None,
+            arg0,
+            positional: args.collect(),
+            flags: flags.to_string(),
+            glob_options: GlobOptions::default(),
+            loop_depth: 0,
+            flow: None,
+            functions: HashMap::new(),
+            local_frames: Vec::new(),
+            job_control: false,
+            jobs: Jobs::default(),
+            pipeline_text: String::new(),
+            completions: HashMap::new(),
         }
+    }
+
+    /// The directories programs are looked up in, from `PATH` as it is
+    /// now.
+    fn search_path(&self) -> &str {
+        self.env_vars.get("PATH").map_or("", String::as_str)
+    }
 
-        if !current_input.trim().is_empty() {
-            history.add(current_input.clone());
+    /// The prompt in the variable `name`, or `default` if it isn't set.
+    fn prompt(&self, name: &str, default: &str) -> String {
+        self.env_vars.get(name).cloned().unwrap_or_else(|| default.to_string())
+    }
+
+    /// Parses and runs a complete piece of input, such as the body of a
+    /// command substitution.
+    fn run_source(&mut self, source: &str) -> i32 {
+        match parser::parse(source) {
+            Ok(list) => self.execute_list(&list),
+            Err(error) => {
+                eprintln!("{}", error);
+                self.last_status = 2;
+                2
+            }
         }
+    }
+}
 
-        let lexer = Lexer::new(&current_input, env_vars.clone());
-        let tokens: Vec<TokenType> = lexer.into_iter().collect();
+fn main() {
+    let mut shell = Shell::new();
+    if sys::is_terminal(0) {
+        shell.job_control = true;
+        sys::catch_interrupts();
+        sys::ignore_job_signals();
+        // Lead a process group of our own and take the terminal for it.
+        // Failing just means we already lead one, as a session leader.
+        sys::set_process_group(0, 0).ok();
+        sys::set_terminal_group(0, sys::process_group()).ok();
+    }
+    let mut history = History::new();
 
-        if tokens.is_empty() {
-            continue;
+    'read: loop {
+        for notice in shell.jobs.notices() {
+            if shell.flags.contains('i') {
+                eprintln!("{}", notice);
+            }
         }
-        let pipelines = parse_pipeline(tokens);
-        if pipelines.is_empty() {
+        sys::set_interrupted(false);
+        let Some(line) = editor::read_line(&shell.prompt("PS1", "$ "), &mut history, &mut shell) else {
+            process::exit(shell.last_status);
+        };
+
+        history.reset_position();
+        if line.trim().is_empty() {
             continue;
         }
+        let Some(line) = expand_history(&shell, &mut history, line) else {
+            continue;
+        };
 
-        let mut last_success = true;
-        'pipeline_loop: for (pipeline, operator) in pipelines {
-            if pipeline.len() == 1 {
-                let cmd = &pipeline[0];
-                match cmd.command.as_str() {
-                    "exit" => {
-                        process::exit(cmd.args.first().and_then(|s| s.parse().ok()).unwrap_or(0))
-                    }
-                    "cd" => {
-                        let path = cmd.args.first().map(String::as_str).unwrap_or("");
-                        last_success = if path.is_empty() {
-                            let home = env_vars.get("HOME").cloned().unwrap_or_default();
-                            change_directory(&home).is_ok()
-                        } else {
-                            change_directory(path).is_ok()
+        // Keep reading lines while a command, here-doc body or quote is
+        // still open.
+        let mut input = parser::Input::new(line);
+        let parsed = loop {
+            match input.parse() {
+                Err(ParseError::Incomplete) => match editor::read_line(&shell.prompt("PS2", "> "), &mut history, &mut shell) {
+                    // Ctrl-C abandons the whole command, not just this line.
+                    Some(_) if sys::interrupted() => continue 'read,
+                    Some(line) => {
+                        let Some(line) = expand_history(&shell, &mut history, line) else {
+                            continue 'read;
                         };
-                        continue;
+                        input.push_line(&line);
                     }
-                    _ => {}
-                }
+                    None => break Err(ParseError::Incomplete),
+                },
+                parsed => break parsed,
             }
+        };
+        let current_input = input.into_source();
 
-            let mut previous_output = None;
-            for (i, cmd) in pipeline.iter().enumerate() {
-                let is_last = i == pipeline.len() - 1;
-                let redirection = if is_last {
-                    cmd.redirection.clone()
-                } else {
-                    Redirection::Pipe
-                };
-
-                match execute_command(
-                    &cmd.command,
-                    &cmd.args,
-                    &env_path,
-                    redirection,
-                    previous_output,
-                ) {
-                    Ok((output, success)) => {
-                        previous_output = output;
-                        last_success = success;
-                    }
-                    Err(e) => {
-                        eprintln!("Error executing command: {}", e);
-                        last_success = false;
-                        break;
-                    }
+        let added = history.add(current_input.clone());
+        let mut record = HistoryRecord::start(shell.shell_pid);
+        match parsed {
+            Ok(list) => {
+                shell.execute_list(&list);
+                // Move past the `^C` the terminal echoed.
+                if sys::interrupted() {
+                    eprintln!();
                 }
             }
-
-            match operator {
-                Some(TokenType::And) if !last_success => break 'pipeline_loop,
-                Some(TokenType::Or) if last_success => break 'pipeline_loop,
-                _ => {}
+            Err(error) => {
+                eprintln!("{}", error);
+                shell.last_status = 2;
             }
         }
+        if added {
+            record.finish(shell.last_status);
+            history.save(&current_input, &record);
+        }
     }
 }
 
-// earlier mode of redirection --saved for reference
-// fn setup_redirection(
-//     redirection: &Redirection,
-//     stdout_pipe: Option<Stdio>,
-// ) -> io::Result<(Option<Stdio>, Option<Stdio>)> {
-//     let stdout = match redirection {
-//         Redirection::OutputTo(path) => Some(Stdio::from(
-//             OpenOptions::new()
-//                 .write(true)
-//                 .create(true)
-//                 .truncate(true)
-//                 .open(path)?,
-//         )),
-//         Redirection::OutputAppend(path) => Some(Stdio::from(
-//             OpenOptions::new()
-//                 .write(true)
-//                 .create(true)
-//                 .append(true)
-//                 .open(path)?,
-//         )),
-//         Redirection::Pipe => stdout_pipe,
-//         _ => None,
-//     };
-
-//     let stderr = match redirection {
-//         Redirection::ErrorTo(path) => Some(Stdio::from(
-//             OpenOptions::new()
-//                 .write(true)
-//                 .create(true)
-//                 .truncate(true)
-//                 .open(path)?,
-//         )),
-//         Redirection::ErrorAppend(path) => Some(Stdio::from(
-//             OpenOptions::new()
-//                 .write(true)
-//                 .create(true)
-//                 .append(true)
-//                 .open(path)?,
-//         )),
-//         _ => None,
-//     };
-
-//     Ok((stdout, stderr))
-// }
+/// Applies history expansion to a line read interactively, showing the
+/// line if it changed. `None` means there's nothing to run: a reference
+/// couldn't be expanded, or `:p` asked only to see the result, which goes
+/// into the history instead.
+fn expand_history(shell: &Shell, history: &mut History, line: String) -> Option<String> {
+    if !shell.flags.contains('i') {
+        return Some(line);
+    }
+    match history::expand(&line, history) {
+        Ok(None) => Some(line),
+        Ok(Some(expanded)) => {
+            println!("{}", expanded.line);
+            if expanded.print_only {
+                if history.add(expanded.line.clone()) {
+                    history.save(&expanded.line, &HistoryRecord::start(shell.shell_pid));
+                }
+                return None;
+            }
+            Some(expanded.line)
+        }
+        Err(message) => {
+            eprintln!("{}", message);
+            None
+        }
+    }
+}
 
 // Basic version of lexer (if you want to implement using this)
 // fn tokenize(input: &str) -> Vec<String> {
@@ -773,3 +1702,57 @@ fn main() {
 
 //     tokens.into_iter().filter(|s| !s.is_empty()).collect()
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glob::tests::TempDir;
+
+    #[test]
+    fn redirections_apply_left_to_right() {
+        let dir = TempDir::new("redirect-order", &[]);
+        let file = format!("{}/out", dir.0);
+        let mut shell = Shell::new();
+        shell.env_vars.insert("f".to_string(), file.clone());
+        let both = "{ echo out; echo err >&2; }";
+
+        shell.run_source(&format!("seen=$( {} 2>&1 >\"$f\" )", both));
+        assert_eq!(shell.env_vars["seen"], "err");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "out\n");
+
+        shell.run_source(&format!("seen=$( {} >\"$f\" 2>&1 )", both));
+        assert_eq!(shell.env_vars["seen"], "");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "out\nerr\n");
+
+        shell.run_source(&format!("seen=$( {} 3>&1 >\"$f\" 2>&3 3>&- )", both));
+        assert_eq!(shell.env_vars["seen"], "err");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "out\n");
+
+        shell.run_source("echo one >\"$f\" >>\"$f\"; echo two >>\"$f\"");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "one\ntwo\n");
+    }
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn set_options_go_into_flags_and_the_rest_into_positional_parameters() {
+        let mut shell = Shell::new();
+        shell.flags.clear();
+        shell.positional = words(&["a", "b", "c"]);
+        assert_eq!(set_builtin(&mut shell, &words(&["-x"])), 0);
+        assert_eq!(shell.positional, ["a", "b", "c"]);
+        assert_eq!(shell.flags, "x");
+        assert_eq!(set_builtin(&mut shell, &words(&["-eu", "+x", "d", "-v"])), 0);
+        assert_eq!(shell.flags, "eu");
+        assert_eq!(shell.positional, ["d", "-v"]);
+        assert_eq!(set_builtin(&mut shell, &words(&["+e", "--", "-x"])), 0);
+        assert_eq!(shell.flags, "u");
+        assert_eq!(shell.positional, ["-x"]);
+        assert_eq!(set_builtin(&mut shell, &words(&["--"])), 0);
+        assert!(shell.positional.is_empty());
+        assert_eq!(set_builtin(&mut shell, &words(&["-uq", "e"])), 2);
+        assert_eq!(shell.flags, "u");
+    }
+}
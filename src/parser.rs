@@ -0,0 +1,959 @@
+// The parser: a recursive-descent pass that turns the lexer's tokens into a
+// syntax tree, which `exec.rs` walks to run it. Words stay unexpanded in the
+// tree; they are expanded each time the command holding them runs.
+
+use std::fmt;
+use std::os::fd::RawFd;
+use std::rc::Rc;
+
+use crate::{word_text, Lexer, RedirectType, TokenType, WordPart};
+
+pub type Word = Vec<WordPart>;
+
+// Reserved words that end a list rather than start a command.
+const CLOSING_KEYWORDS: &[&str] = &["then", "elif", "else", "fi", "do", "done", "esac", "}"];
+
+/// And-or lists separated by `;`, `&` or newlines, run one after another.
+#[derive(Debug, Clone, Default)]
+pub struct List(pub Vec<AndOr>);
+
+/// Pipelines joined by `&&` and `||`, which short-circuit left to right
+/// with equal precedence.
+#[derive(Debug, Clone)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+    // Set by a trailing `&`, which runs the list as a background job.
+    pub background: bool,
+    // The source text, which is how a job shows up in `jobs`.
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connector {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    // Set by a leading `!`, which inverts the pipeline's status.
+    pub negated: bool,
+    pub commands: Vec<Command>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    Simple(SimpleCommand),
+    Compound(Compound, Vec<Redirect>),
+    // A function definition: the name and the body it is called with.
+    Function(String, Rc<Command>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SimpleCommand {
+    // `NAME=value` words in front of the command name.
+    pub assignments: Vec<Assignment>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Assignment {
+    pub name: String,
+    pub value: Word,
+    // The words of `NAME=( words )`. Variables only hold strings, so they
+    // are stored one to a line, the way `COMPREPLY` is read.
+    pub elements: Option<Vec<Word>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    pub fd: Option<RawFd>,
+    pub kind: RedirectType,
+    // Empty for here-documents, whose body is part of `kind`.
+    pub target: Word,
+}
+
+#[derive(Debug, Clone)]
+pub enum Compound {
+    // `{ list; }`
+    BraceGroup(List),
+    // `( list )`, run in a forked copy of the shell.
+    Subshell(List),
+    // `(( expr ))`, with the expression still raw.
+    Arithmetic(String),
+    // `if`/`elif` conditions with their bodies, then the `else` body.
+    If(Vec<(List, List)>, Option<List>),
+    // `while` or, with `until` set, `until`.
+    Loop {
+        until: bool,
+        condition: List,
+        body: List,
+    },
+    // `for name in words`; without `in`, the words are `"$@"`.
+    For {
+        name: String,
+        words: Option<Vec<Word>>,
+        body: List,
+    },
+    Case(Word, Vec<CaseItem>),
+}
+
+/// One `pattern | pattern) list ;;` item of a `case`.
+#[derive(Debug, Clone)]
+pub struct CaseItem {
+    pub patterns: Vec<Word>,
+    pub body: List,
+    pub terminator: CaseTerminator,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaseTerminator {
+    // `;;`: stop after this item.
+    Break,
+    // `;&`: run the next item's body too, without testing its patterns.
+    FallThrough,
+    // `;;&`: go on testing the following items' patterns.
+    Continue,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    // The input stopped in the middle of a command, so more lines are
+    // needed before it can run.
+    Incomplete,
+    // A token that can't appear where it did, as it would be written.
+    Unexpected(String),
+}
+
+/// What incomplete input is still waiting for. Until a line comes that
+/// could supply it, parsing the input again can't turn out any different.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pending {
+    // The rest of a here-doc body, ended by a line holding just the
+    // delimiter; with the flag set, after any leading tabs.
+    HereDoc(String, bool),
+    // Text that has to appear before the input can end, such as a closing
+    // quote or bracket, or the reserved word ending a compound command.
+    Text(String),
+    // Any line could finish it, as after a trailing backslash.
+    Line,
+}
+
+impl Pending {
+    fn met_by(&self, line: &str) -> bool {
+        match self {
+            Pending::HereDoc(delimiter, strip_tabs) => {
+                let line = if *strip_tabs { line.trim_start_matches('\t') } else { line };
+                line == delimiter
+            }
+            Pending::Text(text) => line.contains(text.as_str()),
+            Pending::Line => true,
+        }
+    }
+}
+
+/// Input read a line at a time until it makes a complete command. A line
+/// that can't finish what is still open is only added, so a long here-doc
+/// or loop body isn't parsed again for every line of it.
+pub struct Input {
+    source: String,
+    // What the input was waiting for when it was last parsed, until a line
+    // that could supply it is added.
+    pending: Option<Pending>,
+}
+
+impl Input {
+    pub fn new(source: String) -> Self {
+        Input { source, pending: None }
+    }
+
+    pub fn push_line(&mut self, line: &str) {
+        self.source.push('\n');
+        self.source.push_str(line);
+        if self.pending.as_ref().is_some_and(|pending| pending.met_by(line)) {
+            self.pending = None;
+        }
+    }
+
+    pub fn parse(&mut self) -> Result<List, ParseError> {
+        if self.pending.is_some() {
+            return Err(ParseError::Incomplete);
+        }
+        parse_source(&self.source).map_err(|(error, pending)| {
+            self.pending = pending;
+            error
+        })
+    }
+
+    pub fn into_source(self) -> String {
+        self.source
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Incomplete => write!(f, "syntax error: unexpected end of file"),
+            ParseError::Unexpected(token) => {
+                write!(f, "syntax error near unexpected token `{}'", token)
+            }
+        }
+    }
+}
+
+impl Command {
+    /// The command's redirections. A function definition has none of its
+    /// own to add to.
+    fn redirects_mut(&mut self) -> Option<&mut Vec<Redirect>> {
+        match self {
+            Command::Simple(simple) => Some(&mut simple.redirects),
+            Command::Compound(_, redirects) => Some(redirects),
+            Command::Function(..) => None,
+        }
+    }
+}
+
+/// Lexes and parses a complete piece of input.
+pub fn parse(source: &str) -> Result<List, ParseError> {
+    parse_source(source).map_err(|(error, _)| error)
+}
+
+/// Parses like `parse`, also saying what incomplete input is waiting for.
+fn parse_source(source: &str) -> Result<List, (ParseError, Option<Pending>)> {
+    let mut lexer = Lexer::new(source);
+    let mut tokens = Vec::new();
+    let mut spans = Vec::new();
+    while let Some(token) = lexer.next() {
+        tokens.push(token);
+        spans.push((lexer.token_start, lexer.position));
+    }
+    if let Some(pending) = lexer.incomplete {
+        return Err((ParseError::Incomplete, Some(pending)));
+    }
+
+    let mut parser = Parser {
+        tokens,
+        spans,
+        source: lexer.input,
+        position: 0,
+        closing: None,
+    };
+    let list = parser.parse_list();
+    let result = list.and_then(|list| match parser.peek() {
+        None => Ok(list),
+        Some(_) => Err(parser.unexpected()),
+    });
+    result.map_err(|error| {
+        let pending = (error == ParseError::Incomplete).then(|| parser.closing.take().unwrap_or(Pending::Line));
+        (error, pending)
+    })
+}
+
+struct Parser {
+    tokens: Vec<TokenType>,
+    // Where each token lies in the source.
+    spans: Vec<(usize, usize)>,
+    source: Vec<char>,
+    position: usize,
+    // What closes the compound command the input ran out in, if it was
+    // waiting for its closing word or bracket.
+    closing: Option<Pending>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&TokenType> {
+        self.tokens.get(self.position)
+    }
+
+    /// The source text from the token at `start` to the last one consumed.
+    fn text_from(&self, start: usize) -> String {
+        let from = self.spans[start].0;
+        let to = self.spans[self.position - 1].1;
+        self.source[from..to].iter().collect()
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek() == Some(&TokenType::Newline) {
+            self.position += 1;
+        }
+    }
+
+    /// The error for the current token: running out of input just means the
+    /// command isn't finished yet.
+    fn unexpected(&self) -> ParseError {
+        match self.peek() {
+            Some(token) => ParseError::Unexpected(describe(token)),
+            None => ParseError::Incomplete,
+        }
+    }
+
+    /// The reserved word at the current position, if any.
+    fn peek_keyword(&self) -> Option<&str> {
+        match self.peek() {
+            Some(TokenType::Word(word)) => match word.as_slice() {
+                [WordPart::Unquoted(text)] => Some(text),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, token: TokenType) -> Result<(), ParseError> {
+        if self.peek() == Some(&token) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.missing(&describe(&token)))
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        if self.peek_keyword() == Some(keyword) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.missing(keyword))
+        }
+    }
+
+    /// The error for a closing word or bracket that isn't at the current
+    /// position. At the end of the input, only a line holding it can finish
+    /// the command.
+    fn missing(&mut self, text: &str) -> ParseError {
+        if self.peek().is_none() {
+            self.closing = Some(Pending::Text(text.to_string()));
+        }
+        self.unexpected()
+    }
+
+    fn at_command_start(&self) -> bool {
+        if self.peek_keyword().is_some_and(|word| CLOSING_KEYWORDS.contains(&word)) {
+            return false;
+        }
+        matches!(
+            self.peek(),
+            Some(
+                TokenType::Word(_)
+                    | TokenType::IoNumber(_)
+                    | TokenType::Redirect(_)
+                    | TokenType::Arithmetic(_)
+                    | TokenType::LeftParen
+            )
+        )
+    }
+
+    /// Parses and-or lists for as long as commands keep coming. Whatever
+    /// stops it is left for the caller to check.
+    fn parse_list(&mut self) -> Result<List, ParseError> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_newlines();
+            if !self.at_command_start() {
+                break;
+            }
+            let mut and_or = self.parse_and_or()?;
+            let separated = match self.peek() {
+                Some(TokenType::Semicolon | TokenType::Newline) => true,
+                Some(TokenType::Background) => {
+                    and_or.background = true;
+                    true
+                }
+                _ => false,
+            };
+            items.push(and_or);
+            if !separated {
+                break;
+            }
+            self.position += 1;
+        }
+        Ok(List(items))
+    }
+
+    /// A list inside a compound command, which can't be empty.
+    fn parse_compound_list(&mut self) -> Result<List, ParseError> {
+        let list = self.parse_list()?;
+        if list.0.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(list)
+    }
+
+    fn parse_and_or(&mut self) -> Result<AndOr, ParseError> {
+        let start = self.position;
+        let first = self.parse_pipeline()?;
+        let mut rest = Vec::new();
+        loop {
+            let connector = match self.peek() {
+                Some(TokenType::And) => Connector::And,
+                Some(TokenType::Or) => Connector::Or,
+                _ => break,
+            };
+            self.position += 1;
+            self.skip_newlines();
+            rest.push((connector, self.parse_pipeline()?));
+        }
+        Ok(AndOr {
+            first,
+            rest,
+            background: false,
+            text: self.text_from(start),
+        })
+    }
+
+    fn parse_pipeline(&mut self) -> Result<Pipeline, ParseError> {
+        let start = self.position;
+        let negated = self.peek_keyword() == Some("!");
+        if negated {
+            self.position += 1;
+        }
+
+        let mut commands = vec![self.parse_command()?];
+        loop {
+            let pipe_stderr = match self.peek() {
+                Some(TokenType::Pipe) => false,
+                Some(TokenType::PipeErr) => true,
+                _ => break,
+            };
+            // `|&` is shorthand for `2>&1 |`, applied after the command's
+            // own redirections.
+            if pipe_stderr {
+                let redirects = commands.last_mut().and_then(Command::redirects_mut);
+                let Some(redirects) = redirects else {
+                    return Err(self.unexpected());
+                };
+                redirects.push(Redirect {
+                    fd: Some(2),
+                    kind: RedirectType::DupOutput,
+                    target: vec![WordPart::Unquoted("1".to_string())],
+                });
+            }
+            self.position += 1;
+            self.skip_newlines();
+            commands.push(self.parse_command()?);
+        }
+        Ok(Pipeline {
+            negated,
+            commands,
+            text: self.text_from(start),
+        })
+    }
+
+    fn parse_command(&mut self) -> Result<Command, ParseError> {
+        if self.peek_keyword() == Some("function") {
+            self.position += 1;
+            let name = match self.peek_keyword() {
+                Some(name) => name.to_string(),
+                None => return Err(self.unexpected()),
+            };
+            self.position += 1;
+            if self.peek() == Some(&TokenType::LeftParen) {
+                self.position += 1;
+                self.expect(TokenType::RightParen)?;
+            }
+            return self.parse_function_body(name);
+        }
+        // `NAME=(` starts an assignment, not a function definition.
+        let name = self.peek_keyword().filter(|name| !name.contains('='));
+        if let (Some(name), Some(TokenType::LeftParen)) = (name, self.tokens.get(self.position + 1)) {
+            let name = name.to_string();
+            self.position += 2;
+            self.expect(TokenType::RightParen)?;
+            return self.parse_function_body(name);
+        }
+
+        let compound = match (self.peek(), self.peek_keyword()) {
+            (Some(TokenType::Arithmetic(expression)), _) => {
+                let compound = Compound::Arithmetic(expression.clone());
+                self.position += 1;
+                compound
+            }
+            (Some(TokenType::LeftParen), _) => {
+                self.position += 1;
+                let list = self.parse_compound_list()?;
+                self.expect(TokenType::RightParen)?;
+                Compound::Subshell(list)
+            }
+            (_, Some("{")) => self.parse_brace_group()?,
+            (_, Some("if")) => self.parse_if()?,
+            (_, Some(keyword @ ("while" | "until"))) => {
+                let until = keyword == "until";
+                self.position += 1;
+                let condition = self.parse_compound_list()?;
+                let body = self.parse_do_group()?;
+                Compound::Loop {
+                    until,
+                    condition,
+                    body,
+                }
+            }
+            (_, Some("for")) => self.parse_for()?,
+            (_, Some("case")) => self.parse_case()?,
+            _ => return self.parse_simple_command().map(Command::Simple),
+        };
+        Ok(Command::Compound(compound, self.parse_redirects()?))
+    }
+
+    /// The body of a function is any compound command, usually a brace
+    /// group, with redirections that apply on every call.
+    fn parse_function_body(&mut self, name: String) -> Result<Command, ParseError> {
+        self.skip_newlines();
+        let start = self.position;
+        match self.parse_command()? {
+            body @ Command::Compound(..) => Ok(Command::Function(name, Rc::new(body))),
+            _ => {
+                self.position = start;
+                Err(self.unexpected())
+            }
+        }
+    }
+
+    fn parse_brace_group(&mut self) -> Result<Compound, ParseError> {
+        self.expect_keyword("{")?;
+        let list = self.parse_compound_list()?;
+        self.expect_keyword("}")?;
+        Ok(Compound::BraceGroup(list))
+    }
+
+    fn parse_if(&mut self) -> Result<Compound, ParseError> {
+        let mut branches = Vec::new();
+        let mut otherwise = None;
+        self.expect_keyword("if")?;
+        loop {
+            let condition = self.parse_compound_list()?;
+            self.expect_keyword("then")?;
+            branches.push((condition, self.parse_compound_list()?));
+            match self.peek_keyword() {
+                Some("elif") => self.position += 1,
+                Some("else") => {
+                    self.position += 1;
+                    otherwise = Some(self.parse_compound_list()?);
+                    self.expect_keyword("fi")?;
+                    break;
+                }
+                _ => {
+                    self.expect_keyword("fi")?;
+                    break;
+                }
+            }
+        }
+        Ok(Compound::If(branches, otherwise))
+    }
+
+    fn parse_for(&mut self) -> Result<Compound, ParseError> {
+        self.expect_keyword("for")?;
+        let name = match self.peek_keyword() {
+            Some(name) if is_name(name) => name.to_string(),
+            _ => return Err(self.unexpected()),
+        };
+        self.position += 1;
+        self.skip_newlines();
+
+        let mut words = None;
+        if self.peek_keyword() == Some("in") {
+            self.position += 1;
+            let mut list = Vec::new();
+            while let Some(TokenType::Word(word)) = self.peek() {
+                list.push(word.clone());
+                self.position += 1;
+            }
+            words = Some(list);
+            match self.peek() {
+                Some(TokenType::Semicolon | TokenType::Newline) => self.position += 1,
+                _ => return Err(self.unexpected()),
+            }
+        } else if self.peek() == Some(&TokenType::Semicolon) {
+            self.position += 1;
+        }
+        self.skip_newlines();
+
+        let body = self.parse_do_group()?;
+        Ok(Compound::For { name, words, body })
+    }
+
+    fn parse_case(&mut self) -> Result<Compound, ParseError> {
+        self.expect_keyword("case")?;
+        let Some(TokenType::Word(word)) = self.peek().cloned() else {
+            return Err(self.unexpected());
+        };
+        self.position += 1;
+        self.skip_newlines();
+        self.expect_keyword("in")?;
+        self.skip_newlines();
+
+        let mut items = Vec::new();
+        while self.peek_keyword() != Some("esac") {
+            if self.peek().is_none() {
+                return Err(self.missing("esac"));
+            }
+            if self.peek() == Some(&TokenType::LeftParen) {
+                self.position += 1;
+            }
+            let mut patterns = Vec::new();
+            loop {
+                let Some(TokenType::Word(pattern)) = self.peek().cloned() else {
+                    return Err(self.unexpected());
+                };
+                patterns.push(pattern);
+                self.position += 1;
+                match self.peek() {
+                    Some(TokenType::Pipe) => self.position += 1,
+                    Some(TokenType::RightParen) => {
+                        self.position += 1;
+                        break;
+                    }
+                    _ => return Err(self.unexpected()),
+                }
+            }
+
+            let body = self.parse_list()?;
+            let terminator = match self.peek() {
+                Some(TokenType::DoubleSemicolon) => CaseTerminator::Break,
+                Some(TokenType::SemicolonAnd) => CaseTerminator::FallThrough,
+                Some(TokenType::DoubleSemicolonAnd) => CaseTerminator::Continue,
+                // The last item doesn't need a terminator.
+                _ if self.peek_keyword() == Some("esac") => CaseTerminator::Break,
+                _ => return Err(self.unexpected()),
+            };
+            if self.peek_keyword() != Some("esac") {
+                self.position += 1;
+            }
+            items.push(CaseItem {
+                patterns,
+                body,
+                terminator,
+            });
+            self.skip_newlines();
+        }
+        self.position += 1;
+        Ok(Compound::Case(word, items))
+    }
+
+    /// `do list done`, the body of every loop.
+    fn parse_do_group(&mut self) -> Result<List, ParseError> {
+        self.expect_keyword("do")?;
+        let body = self.parse_compound_list()?;
+        self.expect_keyword("done")?;
+        Ok(body)
+    }
+
+    fn parse_simple_command(&mut self) -> Result<SimpleCommand, ParseError> {
+        let mut command = SimpleCommand::default();
+        loop {
+            match self.peek() {
+                Some(TokenType::Word(word)) => {
+                    let word = word.clone();
+                    self.position += 1;
+                    match assignment(&word).filter(|_| command.words.is_empty()) {
+                        Some(mut assignment) => {
+                            if assignment.value.is_empty() && self.peek() == Some(&TokenType::LeftParen) {
+                                self.position += 1;
+                                assignment.elements = Some(self.parse_elements()?);
+                            }
+                            command.assignments.push(assignment);
+                        }
+                        None => command.words.push(word),
+                    }
+                }
+                Some(TokenType::IoNumber(_) | TokenType::Redirect(_)) => {
+                    command.redirects.push(self.parse_redirect()?);
+                }
+                _ => break,
+            }
+        }
+
+        if command.assignments.is_empty() && command.words.is_empty() && command.redirects.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(command)
+    }
+
+    /// The words of a `NAME=( ... )` assignment, up to the `)`.
+    fn parse_elements(&mut self) -> Result<Vec<Word>, ParseError> {
+        let mut words = Vec::new();
+        loop {
+            self.skip_newlines();
+            match self.peek() {
+                Some(TokenType::Word(word)) => {
+                    words.push(word.clone());
+                    self.position += 1;
+                }
+                Some(TokenType::RightParen) => {
+                    self.position += 1;
+                    return Ok(words);
+                }
+                _ => return Err(self.unexpected()),
+            }
+        }
+    }
+
+    fn parse_redirects(&mut self) -> Result<Vec<Redirect>, ParseError> {
+        let mut redirects = Vec::new();
+        while matches!(self.peek(), Some(TokenType::IoNumber(_) | TokenType::Redirect(_))) {
+            redirects.push(self.parse_redirect()?);
+        }
+        Ok(redirects)
+    }
+
+    fn parse_redirect(&mut self) -> Result<Redirect, ParseError> {
+        let mut fd = None;
+        if let Some(TokenType::IoNumber(number)) = self.peek() {
+            fd = Some(*number);
+            self.position += 1;
+        }
+        let Some(TokenType::Redirect(kind)) = self.peek().cloned() else {
+            return Err(self.unexpected());
+        };
+        self.position += 1;
+
+        if let RedirectType::HereDoc(..) = kind {
+            return Ok(Redirect {
+                fd,
+                kind,
+                target: Vec::new(),
+            });
+        }
+        match self.peek() {
+            Some(TokenType::Word(target)) => {
+                let target = target.clone();
+                self.position += 1;
+                Ok(Redirect { fd, kind, target })
+            }
+            // A missing target is an error even at the end of the input;
+            // another line wouldn't be read as part of this command.
+            None => Err(ParseError::Unexpected("newline".to_string())),
+            Some(_) => Err(self.unexpected()),
+        }
+    }
+}
+
+/// Whether `text` can name a variable.
+pub fn is_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Splits a `NAME=value` word into an assignment.
+pub fn assignment(word: &[WordPart]) -> Option<Assignment> {
+    let Some(WordPart::Unquoted(first)) = word.first() else {
+        return None;
+    };
+    let (name, rest) = first.split_once('=')?;
+    if !is_name(name) {
+        return None;
+    }
+
+    let mut value = Vec::new();
+    if !rest.is_empty() {
+        value.push(WordPart::Unquoted(rest.to_string()));
+    }
+    value.extend(word[1..].iter().cloned());
+    Some(Assignment {
+        name: name.to_string(),
+        value,
+        elements: None,
+    })
+}
+
+/// How a token is written, for syntax errors.
+fn describe(token: &TokenType) -> String {
+    let text = match token {
+        TokenType::Word(word) => return word_text(word),
+        TokenType::IoNumber(fd) => return fd.to_string(),
+        TokenType::Pipe => "|",
+        TokenType::PipeErr => "|&",
+        TokenType::Background => "&",
+        TokenType::And => "&&",
+        TokenType::Or => "||",
+        TokenType::Semicolon => ";",
+        TokenType::DoubleSemicolon => ";;",
+        TokenType::SemicolonAnd => ";&",
+        TokenType::DoubleSemicolonAnd => ";;&",
+        TokenType::LeftParen => "(",
+        TokenType::RightParen => ")",
+        TokenType::Newline => "newline",
+        TokenType::Arithmetic(_) => "((",
+        TokenType::Redirect(kind) => match kind {
+            RedirectType::Output => ">",
+            RedirectType::Append => ">>",
+            RedirectType::Input => "<",
+            RedirectType::ReadWrite => "<>",
+            RedirectType::DupOutput => ">&",
+            RedirectType::DupInput => "<&",
+            RedirectType::OutputAll => "&>",
+            RedirectType::AppendAll => "&>>",
+            RedirectType::HereDoc(..) => "<<",
+            RedirectType::HereString => "<<<",
+        },
+    };
+    text.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stderr_pipe_after_function_definition_is_a_syntax_error() {
+        let error = parse("f() { :; } |& cat").unwrap_err();
+        assert_eq!(error.to_string(), "syntax error near unexpected token `|&'");
+    }
+
+    #[test]
+    fn stderr_pipe_adds_a_redirect_to_the_stage_before_it() {
+        let list = parse("ls |& cat").unwrap();
+        let Command::Simple(simple) = &list.0[0].first.commands[0] else {
+            panic!("expected a simple command");
+        };
+        assert_eq!(simple.redirects.len(), 1);
+    }
+
+    #[test]
+    fn parenthesized_words_after_an_assignment_are_its_elements() {
+        let list = parse("COMPREPLY=( $(compgen -W \"a b\" -- x) \"c d\"\n e )").unwrap();
+        let Command::Simple(simple) = &list.0[0].first.commands[0] else {
+            panic!("expected a simple command");
+        };
+        assert!(simple.words.is_empty());
+        assert_eq!(simple.assignments[0].name, "COMPREPLY");
+        assert_eq!(simple.assignments[0].elements.as_ref().map(Vec::len), Some(3));
+    }
+
+    #[test]
+    fn unclosed_assignment_elements_are_incomplete() {
+        assert_eq!(parse("a=( x y").unwrap_err(), ParseError::Incomplete);
+        assert_eq!(parse("a=( x ; )").unwrap_err().to_string(), "syntax error near unexpected token `;'");
+    }
+
+    fn here_doc_body(list: &List, index: usize) -> &str {
+        let Command::Simple(simple) = &list.0[0].first.commands[0] else {
+            panic!("expected a simple command");
+        };
+        match &simple.redirects[index].kind {
+            RedirectType::HereDoc(body, _) => body,
+            kind => panic!("expected a here-doc, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn long_here_doc_is_parsed_again_only_at_its_delimiter() {
+        let mut input = Input::new("cat <<EOF".to_string());
+        for _ in 0..100_000 {
+            assert_eq!(input.parse().unwrap_err(), ParseError::Incomplete);
+            input.push_line("line");
+        }
+        input.push_line("EOF");
+        let list = input.parse().unwrap();
+        assert_eq!(here_doc_body(&list, 0).lines().count(), 100_000);
+    }
+
+    #[test]
+    fn here_docs_on_one_line_are_read_in_turn() {
+        let mut input = Input::new("cat <<A <<-B".to_string());
+        for line in ["one", "B", "A", "two"] {
+            assert_eq!(input.parse().unwrap_err(), ParseError::Incomplete);
+            input.push_line(line);
+        }
+        assert_eq!(input.parse().unwrap_err(), ParseError::Incomplete);
+        input.push_line("\tB");
+        let list = input.parse().unwrap();
+        assert_eq!(here_doc_body(&list, 0), "one\nB\n");
+        assert_eq!(here_doc_body(&list, 1), "two\n");
+    }
+
+    #[test]
+    fn unclosed_quote_waits_for_its_closing_quote() {
+        let mut input = Input::new("echo 'a".to_string());
+        input.parse().unwrap_err();
+        input.push_line("b \" c");
+        assert_eq!(input.parse().unwrap_err(), ParseError::Incomplete);
+        input.push_line("d'");
+        assert!(input.parse().is_ok());
+        assert_eq!(input.into_source(), "echo 'a\nb \" c\nd'");
+    }
+
+    #[test]
+    fn long_loop_body_is_parsed_again_only_at_a_closing_word() {
+        let mut input = Input::new("while true; do".to_string());
+        for _ in 0..100_000 {
+            assert_eq!(input.parse().unwrap_err(), ParseError::Incomplete);
+            input.push_line("echo line");
+        }
+        input.push_line("done");
+        let list = input.parse().unwrap();
+        let Command::Compound(Compound::Loop { body, .. }, _) = &list.0[0].first.commands[0] else {
+            panic!("expected a loop");
+        };
+        assert_eq!(body.0.len(), 100_000);
+    }
+
+    #[test]
+    fn nested_compound_commands_wait_for_each_closing_word() {
+        let mut input = Input::new("if true; then".to_string());
+        for line in ["case x in", "x) echo fi ;;", "esac", "{ echo a", "}", "echo b &&", "echo c"] {
+            assert_eq!(input.parse().unwrap_err(), ParseError::Incomplete);
+            input.push_line(line);
+        }
+        assert_eq!(input.parse().unwrap_err(), ParseError::Incomplete);
+        input.push_line("fi");
+        assert!(input.parse().is_ok());
+    }
+
+    #[test]
+    fn double_paren_is_arithmetic_only_around_an_expression() {
+        let first = |source: &str| parse(source).unwrap().0[0].first.commands[0].clone();
+        assert!(matches!(first("((x += 1))"), Command::Compound(Compound::Arithmetic(e), _) if e == "x += 1"));
+        assert!(matches!(first("(( $# > 1 ))"), Command::Compound(Compound::Arithmetic(_), _)));
+        let Command::Compound(Compound::Subshell(outer), _) = first("((cd a; make) )") else {
+            panic!("expected a subshell");
+        };
+        assert!(matches!(outer.0[0].first.commands[0], Command::Compound(Compound::Subshell(_), _)));
+        assert!(matches!(first("((echo a) | cat)"), Command::Compound(Compound::Subshell(_), _)));
+        assert_eq!(parse("((x += 1)").unwrap_err(), ParseError::Incomplete);
+    }
+
+    #[test]
+    fn case_items_keep_their_patterns_and_terminators() {
+        let list = parse("case $x in a|'b c') one ;; d) two ;& *) three ;;& e) ;; esac").unwrap();
+        let Command::Compound(Compound::Case(_, items), _) = &list.0[0].first.commands[0] else {
+            panic!("expected a case");
+        };
+        let patterns: Vec<usize> = items.iter().map(|item| item.patterns.len()).collect();
+        assert_eq!(patterns, [2, 1, 1, 1]);
+        let terminators: Vec<CaseTerminator> = items.iter().map(|item| item.terminator).collect();
+        assert_eq!(
+            terminators,
+            [CaseTerminator::Break, CaseTerminator::FallThrough, CaseTerminator::Continue, CaseTerminator::Break]
+        );
+        assert!(items[3].body.0.is_empty());
+    }
+
+    #[test]
+    fn last_case_item_needs_no_terminator() {
+        let list = parse("case x in (a) one;; b) two\nesac").unwrap();
+        let Command::Compound(Compound::Case(_, items), _) = &list.0[0].first.commands[0] else {
+            panic!("expected a case");
+        };
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].terminator, CaseTerminator::Break);
+        assert_eq!(parse("case x in a) one;; b) two").unwrap_err(), ParseError::Incomplete);
+        assert!(parse("case x in a) one;; ;; esac").is_err());
+    }
+
+    #[test]
+    fn hash_starting_a_word_comments_out_the_rest_of_the_line() {
+        let list = parse("echo hi # a comment; echo no\necho a#b '#' \\# #").unwrap();
+        assert_eq!(list.0.len(), 2);
+        let words = |index: usize| match &list.0[index].first.commands[0] {
+            Command::Simple(simple) => simple.words.len(),
+            _ => panic!("expected a simple command"),
+        };
+        assert_eq!(words(0), 2);
+        assert_eq!(words(1), 4);
+        assert!(parse("# only a comment").unwrap().0.is_empty());
+        assert!(parse("if true # then\nthen :; fi").is_ok());
+    }
+}
@@ -0,0 +1,224 @@
+// Shell pattern matching: `*`, `?` and bracket expressions, with a backslash
+// making the next character literal. Used wherever the shell compares text
+// against a pattern rather than the filesystem.
+
+/// Reports whether the whole of `text` matches `pattern`.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches_chars(&pattern, &text)
+}
+
+fn matches_chars(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the most recent `*` if the rest fails to match.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match match_bracket(&pattern[p..], text[t]) {
+                Some((true, len)) => Some(len),
+                Some((false, _)) => None,
+                None => (text[t] == '[').then_some(1),
+            },
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(2),
+            Some(&c) => (c == text[t]).then_some(1),
+            None => None,
+        };
+
+        match step {
+            Some(len) => {
+                p += len;
+                t += 1;
+            }
+            None => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `c` against the bracket expression at the start of `pattern`.
+/// Returns whether it matched and how many pattern characters the
+/// expression spans, or `None` if the `[` isn't closed and so is literal.
+fn match_bracket(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let current = *pattern.get(i)?;
+        if current == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+
+        if current == '[' && pattern.get(i + 1) == Some(&':') {
+            let rest: String = pattern[i + 2..].iter().collect();
+            if let Some(end) = rest.find(":]") {
+                matched |= class_matches(&rest[..end], c);
+                i += 2 + rest[..end].chars().count() + 2;
+                continue;
+            }
+        }
+
+        let (low, next) = match current {
+            '\\' => (*pattern.get(i + 1)?, i + 2),
+            _ => (current, i + 1),
+        };
+        if pattern.get(next) == Some(&'-') && pattern.get(next + 1).is_some_and(|&h| h != ']') {
+            let (high, after) = match pattern[next + 1] {
+                '\\' => (*pattern.get(next + 2)?, next + 3),
+                high => (high, next + 2),
+            };
+            matched |= low <= c && c <= high;
+            i = after;
+        } else {
+            matched |= low == c;
+            i = next;
+        }
+    }
+}
+
+fn class_matches(class: &str, c: char) -> bool {
+    match class {
+        "alnum" => c.is_alphanumeric(),
+        "alpha" => c.is_alphabetic(),
+        "blank" => c == ' ' || c == '\t',
+        "cntrl" => c.is_control(),
+        "digit" => c.is_ascii_digit(),
+        "graph" => c.is_ascii_graphic(),
+        "lower" => c.is_lowercase(),
+        "print" => c.is_ascii_graphic() || c == ' ',
+        "punct" => c.is_ascii_punctuation(),
+        "space" => c.is_whitespace(),
+        "upper" => c.is_uppercase(),
+        "xdigit" => c.is_ascii_hexdigit(),
+        _ => false,
+    }
+}
+
+/// Reports whether `pattern` contains any unescaped special characters, i.e.
+/// whether it can match anything other than itself.
+pub fn has_wildcards(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Removes the backslashes from a pattern that has no wildcards, giving the
+/// one string it matches.
+pub fn unescape(pattern: &str) -> String {
+    let mut unescaped = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Backslash-escapes the special characters in `text`, so it matches only
+/// itself when used as a pattern.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "*?[]\\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("a*c", "abbc"));
+        assert!(matches("a*b*c", "axxbyyc"));
+        assert!(!matches("a*b", "abc"));
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("?", ""));
+        assert!(matches("*.tar.gz", "x.tar.gz"));
+        assert!(!matches("*.tar.gz", "x.tar.gz.bak"));
+    }
+
+    #[test]
+    fn bracket_expressions() {
+        assert!(matches("[abc]", "b"));
+        assert!(!matches("[abc]", "d"));
+        assert!(matches("[a-c]x", "bx"));
+        assert!(matches("[]a]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("[[:digit:]][[:upper:]]", "7Q"));
+        assert!(!matches("[[:space:]]", "x"));
+    }
+
+    #[test]
+    fn negated_bracket_expressions() {
+        for pattern in ["[!abc]", "[^abc]"] {
+            assert!(matches(pattern, "d"), "{}", pattern);
+            assert!(!matches(pattern, "a"), "{}", pattern);
+        }
+        assert!(matches("[!a-c]*", "x.rs"));
+        assert!(!matches("[^a-c]*", "b.rs"));
+        assert!(matches("[!]]", "a"));
+        assert!(!matches("[!]]", "]"));
+        assert!(matches("[^[:digit:]]", "x"));
+        assert!(!matches("[![:digit:]]", "4"));
+        assert!(matches("[!!]", "a"));
+        assert!(!matches("[!!]", "!"));
+    }
+
+    #[test]
+    fn unclosed_bracket_is_literal() {
+        assert!(matches("[ab", "[ab"));
+        assert!(matches("x[", "x["));
+        assert!(!matches("[ab", "a"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+        assert!(matches("[\\]]", "]"));
+        assert!(!has_wildcards("a\\*b"));
+        assert!(has_wildcards("a[b]"));
+        assert_eq!(unescape("a\\*b"), "a*b");
+        for text in ["a*b?", "[x]", "back\\slash"] {
+            assert!(matches(&escape(text), text));
+            assert_eq!(unescape(&escape(text)), text);
+        }
+    }
+}
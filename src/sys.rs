@@ -0,0 +1,338 @@
+// Thin wrappers over the few libc calls the standard library doesn't expose.
+// Everything unsafe in the shell lives in this file.
+
+use std::ffi::c_ulong;
+use std::io;
+use std::os::fd::RawFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(target_os = "linux")]
+const F_DUPFD_CLOEXEC: i32 = 1030;
+#[cfg(not(target_os = "linux"))]
+const F_DUPFD_CLOEXEC: i32 = 67;
+
+// Options for `wait_pid`.
+pub const WNOHANG: i32 = 1;
+pub const WUNTRACED: i32 = 2;
+
+#[cfg(target_os = "linux")]
+pub const SIGCONT: i32 = 18;
+#[cfg(not(target_os = "linux"))]
+pub const SIGCONT: i32 = 19;
+#[cfg(target_os = "linux")]
+pub const SIGTSTP: i32 = 20;
+#[cfg(not(target_os = "linux"))]
+pub const SIGTSTP: i32 = 18;
+pub const SIGINT: i32 = 2;
+const SIGQUIT: i32 = 3;
+pub const SIGPIPE: i32 = 13;
+const SIGTTIN: i32 = 21;
+const SIGTTOU: i32 = 22;
+
+// The signals a shell doing job control ignores itself: the terminal's stop
+// key, and reads and mode changes on the terminal from the background.
+const JOB_SIGNALS: [i32; 3] = [SIGTSTP, SIGTTIN, SIGTTOU];
+const SIG_DFL: usize = 0;
+const SIG_IGN: usize = 1;
+
+// `tcsetattr` action: apply once pending output has been written.
+const TCSADRAIN: i32 = 1;
+
+#[cfg(target_os = "linux")]
+const TIOCGWINSZ: c_ulong = 0x5413;
+#[cfg(not(target_os = "linux"))]
+const TIOCGWINSZ: c_ulong = 0x40087468;
+
+const POLLIN: i16 = 1;
+
+const LOCK_SH: i32 = 1;
+const LOCK_EX: i32 = 2;
+#[cfg(target_os = "linux")]
+type PollCount = c_ulong;
+#[cfg(not(target_os = "linux"))]
+type PollCount = u32;
+
+/// A terminal's settings, as `tcgetattr` fills them in. Its layout differs
+/// between platforms, so it's kept opaque and only changed by libc.
+#[derive(Clone, Copy)]
+#[repr(C, align(8))]
+pub struct Termios([u8; 256]);
+
+#[repr(C)]
+#[derive(Default)]
+struct WindowSize {
+    rows: u16,
+    columns: u16,
+    x_pixels: u16,
+    y_pixels: u16,
+}
+
+#[repr(C)]
+struct PollFd {
+    fd: i32,
+    events: i16,
+    returned_events: i16,
+}
+
+// Set by Ctrl-C, until the shell has abandoned what it was doing.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    #[link_name = "dup2"]
+    fn libc_dup2(src: i32, dst: i32) -> i32;
+    #[link_name = "close"]
+    fn libc_close(fd: i32) -> i32;
+    #[link_name = "read"]
+    fn libc_read(fd: i32, buf: *mut u8, count: usize) -> isize;
+    #[link_name = "fcntl"]
+    fn libc_fcntl(fd: i32, cmd: i32, ...) -> i32;
+    #[link_name = "fork"]
+    fn libc_fork() -> i32;
+    #[link_name = "waitpid"]
+    fn libc_waitpid(pid: i32, status: *mut i32, options: i32) -> i32;
+    #[link_name = "_exit"]
+    fn libc_exit(code: i32) -> !;
+    #[link_name = "isatty"]
+    fn libc_isatty(fd: i32) -> i32;
+    #[link_name = "setpgid"]
+    fn libc_setpgid(pid: i32, pgid: i32) -> i32;
+    #[link_name = "kill"]
+    fn libc_kill(pid: i32, signal: i32) -> i32;
+    #[link_name = "getpgrp"]
+    fn libc_getpgrp() -> i32;
+    #[link_name = "tcsetpgrp"]
+    fn libc_tcsetpgrp(fd: i32, pgrp: i32) -> i32;
+    #[link_name = "signal"]
+    fn libc_signal(signal: i32, handler: usize) -> usize;
+    #[link_name = "siginterrupt"]
+    fn libc_siginterrupt(signal: i32, flag: i32) -> i32;
+    #[link_name = "tcgetattr"]
+    fn libc_tcgetattr(fd: i32, termios: *mut Termios) -> i32;
+    #[link_name = "tcsetattr"]
+    fn libc_tcsetattr(fd: i32, action: i32, termios: *const Termios) -> i32;
+    #[link_name = "cfmakeraw"]
+    fn libc_cfmakeraw(termios: *mut Termios);
+    #[link_name = "ioctl"]
+    fn libc_ioctl(fd: i32, request: c_ulong, ...) -> i32;
+    #[link_name = "flock"]
+    fn libc_flock(fd: i32, operation: i32) -> i32;
+    #[link_name = "poll"]
+    fn libc_poll(fds: *mut PollFd, count: PollCount, timeout: i32) -> i32;
+}
+
+fn cvt(ret: i32) -> io::Result<i32> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+pub fn dup2(src: RawFd, dst: RawFd) -> io::Result<()> {
+    cvt(unsafe { libc_dup2(src, dst) }).map(|_| ())
+}
+
+pub fn close(fd: RawFd) -> io::Result<()> {
+    cvt(unsafe { libc_close(fd) }).map(|_| ())
+}
+
+/// Reads a single byte, with no buffering that could swallow input meant
+/// for the commands the shell runs. `None` at end of input.
+pub fn read_byte(fd: RawFd) -> io::Result<Option<u8>> {
+    let mut byte = 0;
+    match unsafe { libc_read(fd, &mut byte, 1) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(None),
+        _ => Ok(Some(byte)),
+    }
+}
+
+/// Whether `fd` has input to read within `timeout` milliseconds.
+pub fn input_ready(fd: RawFd, timeout: i32) -> bool {
+    let mut poll = PollFd { fd, events: POLLIN, returned_events: 0 };
+    matches!(cvt(unsafe { libc_poll(&mut poll, 1, timeout) }), Ok(1..))
+}
+
+/// Waits for an advisory lock on a whole file, shared between readers
+/// unless `exclusive`. Closing the file releases it.
+pub fn lock_file(fd: RawFd, exclusive: bool) -> io::Result<()> {
+    let operation = if exclusive { LOCK_EX } else { LOCK_SH };
+    loop {
+        match cvt(unsafe { libc_flock(fd, operation) }) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result.map(|_| ()),
+        }
+    }
+}
+
+/// Duplicates `fd` onto the lowest free descriptor >= `min`, with
+/// close-on-exec set so children never see the copy.
+pub fn dup_above(fd: RawFd, min: RawFd) -> io::Result<RawFd> {
+    cvt(unsafe { libc_fcntl(fd, F_DUPFD_CLOEXEC, min) })
+}
+
+pub fn is_terminal(fd: RawFd) -> bool {
+    unsafe { libc_isatty(fd) == 1 }
+}
+
+/// Forks the shell. Returns 0 in the child and the child's pid in the parent.
+pub fn fork() -> io::Result<i32> {
+    cvt(unsafe { libc_fork() })
+}
+
+/// Waits for `pid` to exit and returns its status.
+pub fn wait_for(pid: i32) -> io::Result<ExitStatus> {
+    wait_pid(pid, 0).map(|status| status.expect("a blocking wait always returns a status"))
+}
+
+/// Waits for `pid` to change state, with `WNOHANG` and `WUNTRACED` as
+/// `options`. Returns `None` if `WNOHANG` found nothing to report; with
+/// `WUNTRACED`, a stopped child reports a status with `stopped_signal()`.
+pub fn wait_pid(pid: i32, options: i32) -> io::Result<Option<ExitStatus>> {
+    loop {
+        match wait_pid_once(pid, options) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Like `wait_pid`, but gives up with an `Interrupted` error once Ctrl-C
+/// has set the interrupted flag, so the user can get back to the prompt.
+pub fn wait_pid_interruptibly(pid: i32, options: i32) -> io::Result<Option<ExitStatus>> {
+    loop {
+        match wait_pid_once(pid, options) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted && !interrupted() => continue,
+            result => return result,
+        }
+    }
+}
+
+fn wait_pid_once(pid: i32, options: i32) -> io::Result<Option<ExitStatus>> {
+    let mut status = 0;
+    match cvt(unsafe { libc_waitpid(pid, &mut status, options) })? {
+        0 => Ok(None),
+        _ => Ok(Some(ExitStatus::from_raw(status))),
+    }
+}
+
+/// Moves process `pid` into process group `pgid`. Zero for either means
+/// the calling process.
+pub fn set_process_group(pid: i32, pgid: i32) -> io::Result<()> {
+    cvt(unsafe { libc_setpgid(pid, pgid) }).map(|_| ())
+}
+
+pub fn process_group() -> i32 {
+    unsafe { libc_getpgrp() }
+}
+
+pub fn terminal_mode(fd: RawFd) -> io::Result<Termios> {
+    let mut termios = Termios([0; 256]);
+    cvt(unsafe { libc_tcgetattr(fd, &mut termios) })?;
+    Ok(termios)
+}
+
+pub fn set_terminal_mode(fd: RawFd, termios: &Termios) -> io::Result<()> {
+    cvt(unsafe { libc_tcsetattr(fd, TCSADRAIN, termios) }).map(|_| ())
+}
+
+/// The settings for raw mode: every key comes through as typed, nothing is
+/// echoed, and no output processing turns `\n` into `\r\n`.
+pub fn raw_mode(termios: &Termios) -> Termios {
+    let mut raw = *termios;
+    unsafe { libc_cfmakeraw(&mut raw) };
+    raw
+}
+
+/// How many columns wide the terminal is, if it says.
+pub fn terminal_width(fd: RawFd) -> Option<usize> {
+    let mut size = WindowSize::default();
+    cvt(unsafe { libc_ioctl(fd, TIOCGWINSZ, &mut size as *mut WindowSize) }).ok()?;
+    (size.columns > 0).then_some(size.columns as usize)
+}
+
+/// Makes `pgid` the terminal's foreground process group, the one that
+/// reads its input and gets the signals its keys send.
+pub fn set_terminal_group(fd: RawFd, pgid: i32) -> io::Result<()> {
+    cvt(unsafe { libc_tcsetpgrp(fd, pgid) }).map(|_| ())
+}
+
+/// Ignores the job control stop signals, as the shell itself must so
+/// Ctrl-Z stops only the foreground job.
+pub fn ignore_job_signals() {
+    for signal in JOB_SIGNALS {
+        unsafe { libc_signal(signal, SIG_IGN) };
+    }
+}
+
+extern "C" fn on_interrupt(_signal: i32) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Makes Ctrl-C set the interrupted flag instead of killing the shell, and
+/// interrupt a read in progress so the prompt can start over. Ctrl-\ is
+/// ignored.
+pub fn catch_interrupts() {
+    unsafe {
+        libc_signal(SIGINT, on_interrupt as extern "C" fn(i32) as usize);
+        libc_siginterrupt(SIGINT, 1);
+        libc_signal(SIGQUIT, SIG_IGN);
+    }
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+pub fn set_interrupted(interrupted: bool) {
+    INTERRUPTED.store(interrupted, Ordering::SeqCst);
+}
+
+/// Puts every signal the shell catches or ignores back to its default, for
+/// a program the shell starts or a forked copy of the shell. That includes
+/// SIGPIPE, ignored by the Rust runtime, so a copy writing into a pipe
+/// nobody reads any more is stopped rather than looping on the error.
+pub fn default_signals() {
+    for signal in [SIGINT, SIGQUIT, SIGPIPE].into_iter().chain(JOB_SIGNALS) {
+        unsafe { libc_signal(signal, SIG_DFL) };
+    }
+}
+
+/// Puts a new job process into process group `pgid`, or a new group of its
+/// own if it's 0, and gives it the terminal if `foreground`. Called in the
+/// child; the signals the shell handles go back to their defaults.
+pub fn enter_job(pgid: i32, foreground: bool) {
+    unsafe {
+        libc_setpgid(0, pgid);
+        // While SIGTTOU is still ignored, so taking the terminal from the
+        // background doesn't stop us.
+        if foreground {
+            libc_tcsetpgrp(0, libc_getpgrp());
+        }
+    }
+    default_signals();
+}
+
+/// Has `command` call `enter_job` between fork and exec.
+pub fn spawn_in_job(command: &mut Command, pgid: i32, foreground: bool) {
+    // Only async-signal-safe calls are made in the child.
+    unsafe {
+        command.pre_exec(move || {
+            enter_job(pgid, foreground);
+            Ok(())
+        })
+    };
+}
+
+/// Sends `signal` to `pid`, or to the whole process group if `pid` is
+/// negative.
+pub fn kill(pid: i32, signal: i32) -> io::Result<()> {
+    cvt(unsafe { libc_kill(pid, signal) }).map(|_| ())
+}
+
+/// Ends a forked child without running the parent's exit handlers.
+pub fn exit_child(code: i32) -> ! {
+    unsafe { libc_exit(code) }
+}